@@ -0,0 +1,200 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// An error from updating a file, which can be sent back from the thread it
+/// was updated on.
+pub type UpdateError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Write a file atomically: the contents are written to a temporary file of
+/// this writer's own beside it, synced to disk and moved into place, so a
+/// crash or full disk leaves either the old file or the new one, never a
+/// truncated one, even with another run writing it at the same time. The
+/// temporary file is removed if any step fails. The file's directory is
+/// created if need be.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = temp_path(path);
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(contents.as_ref())?;
+        file.sync_all()
+    });
+    if let Err(err) = written.and_then(|()| std::fs::rename(&temp, path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(err);
+    }
+    // Sync the directory too, so the rename itself survives a crash. Not
+    // every filesystem supports it, and the file's in place either way.
+    if let Ok(dir) = File::open(dir.unwrap_or(Path::new("."))) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Update a file, reading it and writing it with `write`, on a thread where
+/// blocking is expected rather than one of the runtime's, as syncing it to
+/// disk can take a while. The lock is held while it's updated, so that
+/// concurrent updates in this run don't lose each other's changes.
+pub async fn update<T: Send + 'static>(
+    lock: &Arc<Mutex<()>>,
+    update: impl FnOnce() -> Result<T, UpdateError> + Send + 'static,
+) -> Result<T, UpdateError> {
+    let lock = lock.clone();
+    tokio::task::spawn_blocking(move || {
+        let _lock = lock.lock().unwrap_or_else(|err| err.into_inner());
+        update()
+    })
+    .await?
+}
+
+/// Remove the temporary files left beside the file at the path by writes
+/// that were interrupted, such as by a crash, which are older than the given
+/// age; younger ones may belong to a write still under way in another run.
+/// Returns how many were removed.
+pub fn remove_stale_temp(path: &Path, max_age: Duration) -> std::io::Result<usize> {
+    let Some(name) = path.file_name() else {
+        return Ok(0);
+    };
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let entries = match std::fs::read_dir(dir.unwrap_or(Path::new("."))) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !is_temp_of(&entry.file_name(), name) {
+            continue;
+        }
+        let age = SystemTime::now()
+            .duration_since(entry.metadata()?.modified()?)
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => removed += 1,
+            // Removed by another run in the meantime.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(removed)
+}
+
+// A temporary file to write the file at the path to first, named for this
+// process and write as `<path>.<pid>.<uuid>.tmp`, so that writers never
+// share one.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        uuid::Uuid::new_v4()
+    ));
+    PathBuf::from(temp)
+}
+
+// Whether the file name is one `temp_path` gives for the named file.
+fn is_temp_of(file_name: &OsStr, name: &OsStr) -> bool {
+    let (Some(file_name), Some(name)) = (file_name.to_str(), name.to_str()) else {
+        return false;
+    };
+    file_name
+        .strip_prefix(name)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|rest| rest.strip_suffix(".tmp"))
+        .and_then(|rest| rest.split_once('.'))
+        .is_some_and(|(pid, uuid)| {
+            !pid.is_empty()
+                && pid.bytes().all(|b| b.is_ascii_digit())
+                && uuid::Uuid::parse_str(uuid).is_ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The names of the files in the directory.
+    fn names(dir: &Path) -> std::io::Result<Vec<String>> {
+        let mut names = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_write() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("state").join("etags.json");
+        write(&path, "old")?;
+        write(&path, "new")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert_eq!(names(&dir.path().join("state"))?, ["etags.json"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_failure() -> Result<(), Box<dyn std::error::Error>> {
+        // A directory in the way of the file can't be replaced by it, so the
+        // rename fails after the temporary file's been written.
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("etags.json");
+        std::fs::create_dir(&path)?;
+        std::fs::write(path.join("keep"), "")?;
+        assert!(write(&path, "new").is_err());
+        assert_eq!(names(dir.path())?, ["etags.json"]);
+        assert!(path.join("keep").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_stale_temp() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("etags.json");
+        assert_eq!(remove_stale_temp(&path, Duration::ZERO)?, 0);
+
+        std::fs::write(temp_path(&path), "partial")?;
+        std::fs::write(temp_path(&path), "partial")?;
+        std::fs::write(dir.path().join("etags.json.old.tmp"), "unrelated")?;
+        assert_eq!(remove_stale_temp(&path, Duration::from_secs(3600))?, 0);
+        assert_eq!(remove_stale_temp(&path, Duration::ZERO)?, 2);
+        assert_eq!(names(dir.path())?, ["etags.json.old.tmp"]);
+        assert_eq!(
+            remove_stale_temp(
+                &dir.path().join("missing").join("etags.json"),
+                Duration::ZERO
+            )?,
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_path() {
+        let path = Path::new("/var/lib/blob/etags.json");
+        let temp = temp_path(path);
+        assert_ne!(temp, temp_path(path));
+        assert_eq!(temp.parent(), path.parent());
+        assert!(is_temp_of(
+            temp.file_name().unwrap(),
+            OsStr::new("etags.json")
+        ));
+        assert!(!is_temp_of(temp.file_name().unwrap(), OsStr::new("etags")));
+        assert!(!is_temp_of(
+            OsStr::new("etags.json.tmp"),
+            OsStr::new("etags.json")
+        ));
+    }
+}
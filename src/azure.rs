@@ -1,96 +1,1987 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::io::{SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use azure_identity::{DefaultAzureCredential, DefaultAzureCredentialBuilder};
-use azure_storage::StorageCredentials;
+use azure_core::auth::TokenCredential;
+use azure_core::{
+    error::ErrorKind,
+    headers::{self, Headers},
+    request_options::{IfMatchCondition, Timeout},
+    ClientOptions, Context, CustomHeaders, HttpClient, Policy, RetryOptions, StatusCode,
+    TimeoutPolicy, TransportOptions,
+};
+use azure_storage::{CloudLocation, StorageCredentials};
 use azure_storage_blobs::{
-    blob::operations::GetPropertiesResponse,
-    prelude::{BlobClient, ClientBuilder},
+    blob::operations::{GetBlobBuilder, GetPropertiesBuilder, GetPropertiesResponse},
+    prelude::{
+        AccessTier, BlobClient, BlobServiceClient, BlobVersioning, ClientBuilder, Snapshot,
+        VersionId,
+    },
 };
-use log::debug;
+use flate2::write::GzDecoder;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use time::OffsetDateTime;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use url::Url;
 
+use crate::bandwidth;
+use crate::cloud::Cloud;
+use crate::config::{Config, Credential, CustomCloud, TokenSource, UnsafeDestination};
+use crate::correlation::ClientRequestIdPolicy;
+use crate::credentials::{redact_sas, split_sas, AccountKeys, SasTokens};
+use crate::hashes::{md5_to_hex, Hasher, Hashes};
+use crate::identity::{self, CachedCredential, ScopedCredential, STORAGE_SCOPE};
+use crate::naming;
+use crate::progress::Progress;
+use crate::redirect::{self, RedirectPolicy};
+use crate::retry::{RetryAfterPolicy, RetryPolicy};
+
+/// The properties of a blob that are reported to apt.
+#[derive(Debug)]
+pub struct BlobInfo {
+    pub size: u64,
+    pub last_modified: OffsetDateTime,
+    /// When the blob was created, as recorded by the storage service.
+    pub created: OffsetDateTime,
+    pub etag: String,
+    /// Hex-encoded MD5 of the content, if the uploader set one.
+    pub content_md5: Option<String>,
+    /// The encoding the content is stored with, if it's stored compressed.
+    pub content_encoding: Option<String>,
+    /// Whether the blob is in the Archive tier, and so can't be downloaded
+    /// until it's rehydrated.
+    pub archived: bool,
+}
+
+impl From<GetPropertiesResponse> for BlobInfo {
+    fn from(response: GetPropertiesResponse) -> Self {
+        let properties = response.blob.properties;
+        BlobInfo {
+            size: properties.content_length,
+            last_modified: properties.last_modified,
+            created: properties.creation_time,
+            etag: properties.etag.to_string(),
+            content_md5: properties.content_md5.map(|md5| md5_to_hex(md5.as_slice())),
+            content_encoding: properties.content_encoding,
+            archived: properties.access_tier == Some(AccessTier::Archive),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AzureBlob {
     blob_client: BlobClient,
+    // The token credential the blob client authenticates with, if it uses
+    // one.
+    credential: Option<Arc<dyn TokenCredential>>,
+    // The storage account the blob is in.
+    account: String,
+    // A specific version of the blob to operate on, rather than the current one.
+    versioning: Option<BlobVersioning>,
+    // An unauthenticated client to fall back to if the blob's credentials
+    // are rejected, when anonymous access is allowed.
+    anonymous_client: Option<BlobClient>,
+    // Clients for the containers to look for the blob in next if it's
+    // missing from this one.
+    fallbacks: VecDeque<ContainerClients>,
+    // How to wait for role assignments to propagate, if requests refused for
+    // lacking permissions are to be retried.
+    role_propagation: Option<RolePropagation>,
+    // How requests which fail transiently are retried.
+    retry: RetryPolicy,
+    // Whether to ask for the blob to be compressed in transit.
+    compress: bool,
+    // The ETag the blob's content must still have when it's fetched, so a
+    // blob replaced after its properties were got isn't downloaded instead.
+    if_match: Option<String>,
+}
+
+// The clients for accessing a blob in one of the containers it may be in.
+#[derive(Debug)]
+struct ContainerClients {
+    blob_client: BlobClient,
+    credential: Option<Arc<dyn TokenCredential>>,
+    anonymous_client: Option<BlobClient>,
+}
+
+// Retries left for a blob while waiting for a role assignment to propagate.
+#[derive(Debug)]
+struct RolePropagation {
+    retries: u32,
+    delay: Duration,
 }
 
 impl AzureBlob {
+    /// Get the blob the URL refers to. If an account is given it's used in
+    /// place of the one in the URL's host. A SAS token in the URL's query
+    /// is used to access the blob.
     pub fn new_from_url(
         azure_registry: &AzureRegistry,
         url: &Url,
+        account: Option<&str>,
+        config: &Config,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        check_url(url, config)?;
+        let (pin, url) = split_pin(url)?;
+        let url = &url;
         let host = url.host_str().ok_or("No host")?;
-        let mut path_segments = url.path_segments().ok_or("No path segments")?;
-        let container_name = path_segments.next().ok_or("No container")?;
-        let blob_name = path_segments.collect::<Vec<_>>().join("/");
-        let account = host.trim_end_matches(".blob.core.windows.net");
+        let (sas_token, path) = match split_sas(url) {
+            Some((sas_token, path)) => (Some(sas_token), path),
+            None => (None, url.path().to_string()),
+        };
+        let mut path_segments = path.trim_start_matches('/').split('/').peekable();
+
+        // The emulator takes path-style URLs, where the account is the first
+        // part of the path rather than part of the hostname.
+        let (host_account, cloud) = if config.emulator {
+            let address = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            let host_account = path_segments.next().filter(|name| !name.is_empty());
+            (host_account.ok_or("No account")?, Cloud::Emulator(address))
+        } else {
+            Cloud::from_host(host, &config.endpoint_suffixes())
+        };
+        let account = account.unwrap_or(host_account);
+        naming::check_account(account)?;
+
+        // Accounts with a default container may leave it out of the path. A
+        // path starting with its name is taken to give it, though.
+        let container_name = match config.default_container(account) {
+            Some(default) => {
+                if path_segments.peek() == Some(&default) {
+                    path_segments.next();
+                } else {
+                    debug!("Taking {} to be in container {}", path, default);
+                }
+                default
+            }
+            None => {
+                let container_name = path_segments.next().filter(|name| !name.is_empty());
+                container_name.ok_or_else(|| {
+                    format!(
+                        "No container in {}; configure Acquire::blob::Default-Container \
+                         for account {} to leave it out",
+                        redact_sas(url.as_str()),
+                        account
+                    )
+                })?
+            }
+        };
+        naming::check_container(container_name)?;
+        // Data Lake Storage paths name files in a hierarchical namespace,
+        // which the blob service serves as blobs of the same names, but in
+        // which empty directory names are ignored.
+        let blob_name = if !config.emulator && Cloud::is_dfs_host(host, &config.endpoint_suffixes())
+        {
+            dfs_blob_name(path_segments)?
+        } else {
+            path_segments.collect::<Vec<_>>().join("/")
+        };
+
+        // The container may be routed to others depending on the path, in
+        // which case they're tried in order.
+        let containers = match config.route(container_name, &blob_name) {
+            Some(containers) => {
+                debug!(
+                    "Routing {}/{} to {}",
+                    container_name,
+                    blob_name,
+                    containers.join(", ")
+                );
+                containers.iter().map(String::as_str).collect()
+            }
+            None => vec![container_name],
+        };
+        let mut clients = containers
+            .into_iter()
+            .map(|container_name| {
+                naming::check_container(container_name)?;
+                let (blob_client, credential) = azure_registry.get_blob_client(
+                    account,
+                    cloud.clone(),
+                    container_name,
+                    &blob_name,
+                    sas_token,
+                    config,
+                )?;
+                let anonymous_client = config.allow_anonymous.then(|| {
+                    azure_registry
+                        .service_client(
+                            account,
+                            &cloud,
+                            ClientCredentials::Anonymous,
+                            StorageCredentials::anonymous(),
+                            config,
+                        )
+                        .container_client(container_name)
+                        .blob_client(&blob_name)
+                });
+                Ok(ContainerClients {
+                    blob_client,
+                    credential,
+                    anonymous_client,
+                })
+            })
+            .collect::<Result<VecDeque<_>, Box<dyn std::error::Error>>>()?;
+        let ContainerClients {
+            blob_client,
+            credential,
+            anonymous_client,
+        } = clients.pop_front().ok_or("No container")?;
+        let role_propagation = (config.role_propagation_retries > 0).then_some(RolePropagation {
+            retries: config.role_propagation_retries,
+            delay: config.role_propagation_delay,
+        });
+
+        let mut blob = AzureBlob {
+            blob_client,
+            credential,
+            account: account.to_string(),
+            versioning: None,
+            anonymous_client,
+            fallbacks: clients,
+            role_propagation,
+            retry: RetryPolicy::from_config(config),
+            compress: false,
+            if_match: None,
+        };
+        match pin {
+            Some(UrlPin::Snapshot(snapshot)) => blob.pin_snapshot(&snapshot),
+            Some(UrlPin::Version(version_id)) => blob.pin_version(&version_id),
+            None => (),
+        }
+        Ok(blob)
+    }
+
+    /// Whether the blob is pinned to a snapshot or version.
+    pub fn is_pinned(&self) -> bool {
+        self.versioning.is_some()
+    }
+
+    /// Get a token for the blob's account now, if it's accessed with token
+    /// credentials, so that requests for it find one cached.
+    pub async fn prefetch_token(&self) -> azure_core::Result<()> {
+        if let Some(credential) = &self.credential {
+            credential.get_token(&[STORAGE_SCOPE]).await?;
+        }
+        Ok(())
+    }
+
+    /// The kind and SKU of the account the blob is in, which any access to
+    /// it can get, to check the credentials used for it are accepted.
+    pub async fn account_information(
+        &self,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let service_client = self.blob_client.container_client().service_client();
+        match service_client.get_account_information().await {
+            Ok(info) => Ok((info.account_kind, info.sku_name)),
+            Err(err) => Err(self.with_identity(err).await),
+        }
+    }
+
+    /// Whether the blob's container exists.
+    pub async fn container_exists(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.blob_client.container_client().exists().await {
+            Ok(exists) => Ok(exists),
+            Err(err) => Err(self.with_identity(err).await),
+        }
+    }
+
+    /// The storage account the blob is in.
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// The blob's path as `account/container/blob`.
+    pub fn path(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.account,
+            self.blob_client.container_client().container_name(),
+            self.blob_client.blob_name()
+        )
+    }
+
+    fn get_properties(&self) -> GetPropertiesBuilder {
+        let builder = self.blob_client.get_properties();
+        match &self.versioning {
+            Some(versioning) => builder.blob_versioning(versioning.clone()),
+            None => builder,
+        }
+    }
+
+    fn get(&self) -> GetBlobBuilder {
+        let builder = self.blob_client.get();
+        let builder = match &self.versioning {
+            Some(versioning) => builder.blob_versioning(versioning.clone()),
+            None => builder,
+        };
+        match &self.if_match {
+            Some(etag) => builder.if_match(IfMatchCondition::Match(etag.clone())),
+            None => builder,
+        }
+    }
+
+    // Switch to accessing the blob anonymously if the error is the blob's
+    // credentials being rejected and that's allowed. Returns whether the
+    // request should be retried.
+    fn fall_back_to_anonymous(&mut self, err: &azure_core::Error) -> bool {
+        if !is_auth_error(err) {
+            return false;
+        }
+        match self.anonymous_client.take() {
+            Some(client) => {
+                warn!(
+                    "Credentials for {} were rejected, retrying anonymously: {}",
+                    self.account, err
+                );
+                self.blob_client = client;
+                self.credential = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Wait and get a fresh token if the error is the storage service refusing
+    // the request for lacking permissions and there are retries left, as
+    // newly assigned roles take minutes to propagate. Returns whether the
+    // request should be retried.
+    async fn wait_for_role_propagation(&mut self, err: &azure_core::Error) -> bool {
+        if !is_permission_mismatch(err) {
+            return false;
+        }
+        let path = self.path();
+        let Some(credential) = &self.credential else {
+            return false;
+        };
+        let Some(propagation) = self.role_propagation.as_mut().filter(|p| p.retries > 0) else {
+            return false;
+        };
+        propagation.retries -= 1;
+        warn!(
+            "{} was refused for lacking permissions, which may be a role assignment that \
+             hasn't propagated yet; retrying in {}s with a fresh token ({} retries left)",
+            path,
+            propagation.delay.as_secs(),
+            propagation.retries
+        );
+        tokio::time::sleep(propagation.delay).await;
+        if let Err(err) = credential.clear_cache().await {
+            warn!("Failed to clear cached tokens: {}", err);
+        }
+        true
+    }
+
+    // Add the identity the blob was requested as to an error refusing the
+    // request, so that roles can be assigned to the right principal.
+    async fn with_identity(&self, err: azure_core::Error) -> Box<dyn std::error::Error> {
+        let refused = matches!(
+            err.kind(),
+            ErrorKind::HttpResponse {
+                status: StatusCode::Unauthorized | StatusCode::Forbidden,
+                ..
+            }
+        );
+        let Some(credential) = self.credential.as_ref().filter(|_| refused) else {
+            return err.into();
+        };
+        // The token the request was made with is cached, so this doesn't
+        // make another request.
+        let identity = match credential.get_token(&[STORAGE_SCOPE]).await {
+            Ok(token) => identity::describe_token(&token),
+            Err(_) => None,
+        };
+        match identity {
+            Some(identity) => {
+                warn!("Access to {} was refused for {}", self.path(), identity);
+                let message = format!("{} (authenticated as {})", err, identity);
+                azure_core::Error::full(err.kind().clone(), err, message).into()
+            }
+            None => err.into(),
+        }
+    }
+
+    // Move on to the next container the blob may be in, if there is one.
+    fn fall_back_to_next_container(&mut self) -> bool {
+        match self.fallbacks.pop_front() {
+            Some(clients) => {
+                debug!(
+                    "{} not found, trying container {}",
+                    self.path(),
+                    clients.blob_client.container_client().container_name()
+                );
+                self.blob_client = clients.blob_client;
+                self.credential = clients.credential;
+                self.anonymous_client = clients.anonymous_client;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The properties of the blob that are reported to apt, or None if it
+    /// doesn't exist in any of the containers it may be in. A single request
+    /// answers both, so each blob takes one round trip before its content's
+    /// fetched.
+    pub async fn info(&mut self) -> Result<Option<BlobInfo>, Box<dyn std::error::Error>> {
+        loop {
+            let what = format!("Getting properties of {}", self.path());
+            let properties = self
+                .retry
+                .run(&what, || self.get_properties().into_future())
+                .await;
+            match properties {
+                Ok(properties) => return Ok(Some(BlobInfo::from(properties))),
+                Err(err)
+                    if err
+                        .as_http_error()
+                        .is_some_and(|e| e.status() == StatusCode::NotFound) =>
+                {
+                    if !self.fall_back_to_next_container() {
+                        return Ok(None);
+                    }
+                }
+                Err(err) if self.wait_for_role_propagation(&err).await => continue,
+                Err(err) if self.fall_back_to_anonymous(&err) => continue,
+                Err(err) => return Err(self.with_identity(err).await),
+            }
+        }
+    }
+
+    /// Operate on the given snapshot of the blob.
+    pub fn pin_snapshot(&mut self, snapshot: &str) {
+        debug!(
+            "Pinned {} to snapshot {}",
+            self.blob_client.blob_name(),
+            snapshot
+        );
+        self.versioning = Some(Snapshot::new(snapshot.to_string()).into());
+    }
+
+    /// Operate on the given version of the blob.
+    pub fn pin_version(&mut self, version_id: &str) {
+        debug!(
+            "Pinned {} to version {}",
+            self.blob_client.blob_name(),
+            version_id
+        );
+        self.versioning = Some(VersionId::new(version_id.to_string()).into());
+    }
+
+    /// Ask for the blob to be compressed in transit when it's downloaded, if
+    /// the service supports it.
+    pub fn compress_in_transit(&mut self) {
+        self.compress = true;
+    }
+
+    /// Pin this blob to the version that was current at the given time, using
+    /// the container's blob versioning. Returns false if no version of the
+    /// blob existed at that time.
+    pub async fn pin_as_of(
+        &mut self,
+        as_of: OffsetDateTime,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let pinned = loop {
+            match self.version_at(as_of).await {
+                Ok(None) if self.fall_back_to_next_container() => continue,
+                Ok(pinned) => break pinned,
+                Err(err) if self.wait_for_role_propagation(&err).await => continue,
+                Err(err) if self.fall_back_to_anonymous(&err) => continue,
+                Err(err) => return Err(self.with_identity(err).await),
+            }
+        };
+        match pinned {
+            Some(version_id) => {
+                debug!(
+                    "Pinned {} to version {}",
+                    self.blob_client.blob_name(),
+                    version_id
+                );
+                self.versioning = Some(VersionId::new(version_id).into());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // The ID of the version of the blob that was current at the given time,
+    // if there was one.
+    async fn version_at(&self, as_of: OffsetDateTime) -> azure_core::Result<Option<String>> {
+        let blob_name = self.blob_client.blob_name().to_string();
+        let mut pages = self
+            .blob_client
+            .container_client()
+            .list_blobs()
+            .prefix(blob_name.clone())
+            .include_versions(true)
+            .into_stream();
+
+        // Version IDs are the time the version was created, so the version
+        // current at `as_of` is the newest one created at or before it.
+        let mut pinned: Option<(OffsetDateTime, String)> = None;
+        while let Some(page) = pages.next().await {
+            for blob in page?.blobs.blobs().filter(|blob| blob.name == blob_name) {
+                let Some(version_id) = &blob.version_id else {
+                    continue;
+                };
+                let created = azure_core::date::parse_rfc3339(version_id)?;
+                if created <= as_of && pinned.as_ref().is_none_or(|(newest, _)| created > *newest) {
+                    pinned = Some((created, version_id.clone()));
+                }
+            }
+        }
+
+        Ok(pinned.map(|(_, version_id)| version_id))
+    }
+
+    /// Ask for the blob to be rehydrated from the Archive tier to the Hot
+    /// tier, which may take hours. Returns false if it's already being
+    /// rehydrated.
+    pub async fn rehydrate(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let what = format!("Rehydrating {}", self.path());
+        let rehydrated = self
+            .retry
+            .run(&what, || {
+                let builder = self.blob_client.set_blob_tier(AccessTier::Hot);
+                match &self.versioning {
+                    Some(versioning) => builder.blob_versioning(versioning.clone()),
+                    None => builder,
+                }
+                .into_future()
+            })
+            .await;
+        match rehydrated {
+            Ok(_) => Ok(true),
+            Err(err) if is_being_rehydrated(&err) => Ok(false),
+            Err(err) => Err(self.with_identity(err).await),
+        }
+    }
+
+    /// Only download the blob's content while it has the given ETag, failing
+    /// if it's been replaced since its properties were got.
+    pub fn pin_etag(&mut self, etag: &str) {
+        self.if_match = Some(etag.to_string());
+    }
+
+    /// Download the blob of the given size into the given file, returning the
+    /// size and hashes of the whole file. The first `resume_from` bytes are
+    /// taken to be in the file already from an earlier, interrupted attempt,
+    /// and only the rest of the blob is fetched and appended. What remains
+    /// is fetched with concurrent ranged requests if it's larger than a
+    /// single chunk, and streamed otherwise, unless it's to be compressed in
+    /// transit.
+    pub(crate) async fn download_to_file(
+        &self,
+        filename: &str,
+        size: u64,
+        resume_from: u64,
+        config: &Config,
+        progress: &mut Progress,
+    ) -> Result<Hashes, Box<dyn std::error::Error>> {
+        let (file, hasher) =
+            open_for_download(filename, resume_from, config.unsafe_destination).await?;
+        let range = resume_from..size;
+        let downloaded = if self.compress && range.start == 0 && range.end > 0 {
+            self.download_compressed(file, hasher, size, progress).await
+        } else if config.chunk_parallelism > 1 && range.end - range.start > config.chunk_size {
+            self.download_ranged(file, hasher, range, config, progress)
+                .await
+        } else {
+            self.download_streamed(file, hasher, range, progress).await
+        };
+        // Only errors from the storage service may be refusals to describe.
+        let downloaded = match downloaded.map_err(|err| err.downcast::<azure_core::Error>()) {
+            Ok(hashes) => Ok(hashes),
+            Err(Ok(err)) => Err(*err),
+            Err(Err(err)) => return Err(err),
+        };
+        let err = match downloaded {
+            Ok(hashes) => {
+                // apt takes the file as it is once it's told it's done, so
+                // make sure it's all on disk first.
+                tokio::fs::File::open(filename).await?.sync_all().await?;
+                return Ok(hashes);
+            }
+            Err(err) => err,
+        };
+        // Whatever was written came from the blob's old content, so don't
+        // leave it to be resumed from.
+        if is_blob_changed(&err) {
+            if let Err(err) = tokio::fs::remove_file(filename).await {
+                warn!("Failed to remove {}: {}", filename, err);
+            }
+            let message = format!("{} changed while it was being downloaded", self.path());
+            return Err(azure_core::Error::full(err.kind().clone(), err, message).into());
+        }
+        Err(self.with_identity(err).await)
+    }
+
+    // Download a range of the blob into the given file, writing each chunk as
+    // it arrives rather than buffering the whole blob in memory. If the
+    // download fails transiently part way through, it's retried from where
+    // it got to.
+    async fn download_streamed(
+        &self,
+        mut file: tokio::fs::File,
+        mut hasher: Hasher,
+        range: Range<u64>,
+        progress: &mut Progress,
+    ) -> Result<Hashes, Box<dyn std::error::Error>> {
+        let mut position = range.start;
+        let mut failures = 0;
+        loop {
+            let err = match self
+                .stream_range(
+                    &mut file,
+                    &mut hasher,
+                    position..range.end,
+                    &mut position,
+                    progress,
+                )
+                .await
+            {
+                Ok(()) => break,
+                Err(StreamError::Write(err)) => return Err(err.into()),
+                Err(StreamError::Azure(err)) => err,
+            };
+            failures += 1;
+            let Some(delay) = self.retry.backoff(failures, &err) else {
+                return Err(err.into());
+            };
+            let what = format!("Downloading {} from byte {}", self.path(), position);
+            self.retry.log_retry(&what, failures, delay, &err);
+            tokio::time::sleep(delay).await;
+        }
+
+        file.flush().await?;
+        Ok(hasher.finish())
+    }
+
+    // Download the whole blob in a single request, asking for it to be gzip
+    // compressed in transit and decompressing it as it arrives. The service
+    // may send it as is instead. If the download fails part way through, the
+    // rest of the blob is fetched uncompressed, from where it got to.
+    async fn download_compressed(
+        &self,
+        mut file: tokio::fs::File,
+        mut hasher: Hasher,
+        size: u64,
+        progress: &mut Progress,
+    ) -> Result<Hashes, Box<dyn std::error::Error>> {
+        let mut position = 0;
+        match self
+            .stream_compressed(&mut file, &mut hasher, size, &mut position, progress)
+            .await
+        {
+            Ok(()) if position == size => {
+                file.flush().await?;
+                return Ok(hasher.finish());
+            }
+            Ok(()) => warn!(
+                "Compressed download of {} ended at byte {} of {}, continuing uncompressed",
+                self.path(),
+                position,
+                size
+            ),
+            Err(StreamError::Write(err)) => return Err(err.into()),
+            Err(StreamError::Azure(err)) => warn!(
+                "Compressed download of {} failed at byte {}, continuing uncompressed: {}",
+                self.path(),
+                position,
+                err
+            ),
+        }
+        self.download_streamed(file, hasher, position..size, progress)
+            .await
+    }
+
+    // Stream the blob into the file, decompressing it if it arrives gzip
+    // compressed, and advancing the position past each chunk written. What's
+    // written is always the start of the blob's content, so the download can
+    // carry on from the position without compression if this fails.
+    async fn stream_compressed(
+        &self,
+        file: &mut tokio::fs::File,
+        hasher: &mut Hasher,
+        size: u64,
+        position: &mut u64,
+        progress: &mut Progress,
+    ) -> Result<(), StreamError> {
+        let mut accept_encoding = Headers::new();
+        accept_encoding.insert(headers::ACCEPT_ENCODING, "gzip");
+        let mut context = Context::new();
+        context.insert(CustomHeaders::from(accept_encoding));
+
+        let mut responses = self.get().chunk_size(size).context(context).into_stream();
+        while let Some(response) = responses.next().await {
+            let response = response?;
+            let mut decoder = match response.blob.properties.content_encoding.as_deref() {
+                Some("gzip") => Some(GzDecoder::new(Vec::new())),
+                _ => None,
+            };
+            let mut body = response.data;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                bandwidth::consume(chunk.len()).await;
+                let content = match &mut decoder {
+                    Some(decoder) => {
+                        decoder
+                            .write_all(&chunk)
+                            .and_then(|()| decoder.flush())
+                            .map_err(decompression_error)?;
+                        std::mem::take(decoder.get_mut())
+                    }
+                    None => chunk.to_vec(),
+                };
+                write_content(file, hasher, &content, size, position).await?;
+                progress.update(*position);
+            }
+            if let Some(mut decoder) = decoder {
+                decoder.try_finish().map_err(decompression_error)?;
+                let content = std::mem::take(decoder.get_mut());
+                write_content(file, hasher, &content, size, position).await?;
+                progress.update(*position);
+            }
+        }
+        Ok(())
+    }
+
+    // Stream a range of the blob into the file, advancing the position past
+    // each chunk written.
+    async fn stream_range(
+        &self,
+        file: &mut tokio::fs::File,
+        hasher: &mut Hasher,
+        range: Range<u64>,
+        position: &mut u64,
+        progress: &mut Progress,
+    ) -> Result<(), StreamError> {
+        // The blob is fetched as a series of ranged responses, each of which
+        // is itself a stream of body chunks. Only a resumed download needs to
+        // ask for a range itself.
+        let mut responses = match range.start {
+            0 => self.get().into_stream(),
+            start if start < range.end => self.get().range(range).into_stream(),
+            _ => return Ok(()),
+        };
+        while let Some(response) = responses.next().await {
+            let mut body = response?.data;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                bandwidth::consume(chunk.len()).await;
+                hasher.update(&chunk);
+                file.write_all(&chunk).await.map_err(StreamError::Write)?;
+                *position += chunk.len() as u64;
+                progress.update(*position);
+            }
+        }
+        Ok(())
+    }
+
+    // Download a range of the blob with several concurrent range requests.
+    // Chunks are written to the file (and hashed) in order, so at most
+    // `chunk_parallelism` chunks are held in memory at once.
+    async fn download_ranged(
+        &self,
+        mut file: tokio::fs::File,
+        mut hasher: Hasher,
+        range: Range<u64>,
+        config: &Config,
+        progress: &mut Progress,
+    ) -> Result<Hashes, Box<dyn std::error::Error>> {
+        let ranges = chunk_ranges(range.clone(), config.chunk_size);
+        info!(
+            "Downloading {} bytes in {} chunks, {} at a time",
+            range.end - range.start,
+            ranges.len(),
+            config.chunk_parallelism
+        );
+
+        let mut chunks = futures::stream::iter(ranges)
+            .map(|range| self.download_range(range))
+            .buffered(config.chunk_parallelism);
+
+        let mut position = range.start;
+        while let Some(chunk) = chunks.next().await {
+            let data = chunk?;
+            hasher.update(&data);
+            file.write_all(&data).await?;
+            position += data.len() as u64;
+            progress.update(position);
+        }
+
+        file.flush().await?;
+        Ok(hasher.finish())
+    }
+
+    // Fetch a single range of the blob into memory, retrying it if it fails
+    // transiently.
+    async fn download_range(&self, range: Range<u64>) -> azure_core::Result<Vec<u8>> {
+        let what = format!(
+            "Downloading bytes {}-{} of {}",
+            range.start,
+            range.end,
+            self.path()
+        );
+        self.retry
+            .run(&what, || self.fetch_range(range.clone()))
+            .await
+    }
+
+    async fn fetch_range(&self, range: Range<u64>) -> azure_core::Result<Vec<u8>> {
+        let length = range.end - range.start;
+        let mut data = Vec::with_capacity(length as usize);
+
+        let mut responses = self.get().range(range).chunk_size(length).into_stream();
+        while let Some(response) = responses.next().await {
+            let mut body = response?.data;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                bandwidth::consume(chunk.len()).await;
+                data.extend_from_slice(&chunk);
+            }
+        }
+        Ok(data)
+    }
+}
+
+// The name of the blob for a file in a hierarchical namespace, given its
+// path within the container. Paths ending in `/` are for directories, which
+// can't be fetched.
+fn dfs_blob_name<'a>(path_segments: impl Iterator<Item = &'a str>) -> Result<String, String> {
+    let path_segments = path_segments.collect::<Vec<_>>();
+    if path_segments.last().is_some_and(|name| name.is_empty()) {
+        return Err(format!(
+            "{}/ is a directory, not a file",
+            path_segments.join("/")
+        ));
+    }
+    let blob_name = path_segments
+        .into_iter()
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    match blob_name.is_empty() {
+        true => Err("No file".to_string()),
+        false => Ok(blob_name),
+    }
+}
+
+// A snapshot or version of a blob named in its URL.
+#[derive(Debug, PartialEq)]
+enum UrlPin {
+    Snapshot(String),
+    Version(String),
+}
+
+// Take the snapshot or version a blob URL pins it to, given by a `snapshot`
+// or `versionid` parameter, out of its query, returning it and the rest of
+// the URL. Like a SAS token, the parameter may be followed by a path apt
+// appended, which is put back on the URL's path if nothing else is left in
+// the query.
+fn split_pin(url: &Url) -> Result<(Option<UrlPin>, Url), String> {
+    let Some(query) = url.query() else {
+        return Ok((None, url.clone()));
+    };
+    let (params, rest) = match query.split_once('/') {
+        Some((params, rest)) => (params, Some(rest)),
+        None => (query, None),
+    };
+    let mut pin = None;
+    let mut kept = Vec::new();
+    for param in params.split('&') {
+        let pinned = match url::form_urlencoded::parse(param.as_bytes()).next() {
+            Some((name, value)) if name.eq_ignore_ascii_case("snapshot") => {
+                UrlPin::Snapshot(value.into_owned())
+            }
+            Some((name, value)) if name.eq_ignore_ascii_case("versionid") => {
+                UrlPin::Version(value.into_owned())
+            }
+            _ => {
+                kept.push(param);
+                continue;
+            }
+        };
+        if pin.replace(pinned).is_some() {
+            return Err("Only one snapshot or versionid may be given".to_string());
+        }
+    }
+    if pin.is_none() {
+        return Ok((None, url.clone()));
+    }
+
+    let mut unpinned = url.clone();
+    match (kept.is_empty(), rest) {
+        (true, Some(rest)) => {
+            let path = format!("{}/{}", url.path().trim_end_matches('/'), rest);
+            unpinned.set_query(None);
+            unpinned.set_path(&path);
+        }
+        (true, None) => unpinned.set_query(None),
+        (false, _) => {
+            let query = kept.join("&");
+            let query = match rest {
+                Some(rest) => format!("{}/{}", query, rest),
+                None => query,
+            };
+            unpinned.set_query(Some(&query));
+        }
+    }
+    Ok((pin, unpinned))
+}
+
+// Whether the error is from asking for a blob to be rehydrated while it
+// already is being.
+fn is_being_rehydrated(err: &azure_core::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::HttpResponse {
+            status: StatusCode::Conflict,
+            error_code: Some(code),
+        } if code == "BlobBeingRehydrated"
+    )
+}
+
+/// Whether the error is from the blob's content being asked for with an ETag
+/// it no longer has, because it's been replaced.
+pub fn is_blob_changed(err: &azure_core::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::HttpResponse {
+            status: StatusCode::PreconditionFailed,
+            ..
+        }
+    )
+}
+
+// Check that the URL can be for a blob. The scheme only chooses the method
+// apt uses, so the same binary can be installed as several: `blob`,
+// `blob+https`, or `https` for URLs copied from the storage service, which
+// must then be its own.
+fn check_url(url: &Url, config: &Config) -> Result<(), String> {
+    match url.scheme() {
+        "blob" | "blob+https" => Ok(()),
+        "https" => match url.host_str() {
+            Some(host)
+                if config.emulator || Cloud::is_blob_host(host, &config.endpoint_suffixes()) =>
+            {
+                Ok(())
+            }
+            host => Err(format!(
+                "{} is not a blob service URL; use a blob:// URL for other hosts",
+                host.unwrap_or_default()
+            )),
+        },
+        scheme => Err(format!(
+            "Unsupported URL scheme {:?}; expected blob, blob+https or https",
+            scheme
+        )),
+    }
+}
+
+// Options for the storage client's request pipeline.
+// Requests are retried by `RetryPolicy` rather than by the SDK, so that the
+// configured retries are the only ones made.
+fn client_options(config: &Config, http_client: Arc<dyn HttpClient>) -> ClientOptions {
+    ClientOptions::new(TransportOptions::new(http_client))
+        .retry(RetryOptions::none())
+        .per_call_policies(vec![Arc::new(ClientRequestIdPolicy) as Arc<dyn Policy>])
+        .per_retry_policies(vec![
+            Arc::new(RetryAfterPolicy) as Arc<dyn Policy>,
+            Arc::new(RedirectPolicy::new(&config.endpoint_suffixes())),
+        ])
+        .timeout(TimeoutPolicy::new(config.timeout.map(Timeout::new)))
+}
 
-        let blob_client = azure_registry.get_blob_client(account, container_name, &blob_name);
+// Why streaming a blob into a file failed: the storage service, which may be
+// worth retrying, or writing the file, which isn't.
+#[derive(Debug)]
+enum StreamError {
+    Azure(azure_core::Error),
+    Write(std::io::Error),
+}
 
-        Ok(AzureBlob { blob_client })
+impl From<azure_core::Error> for StreamError {
+    fn from(err: azure_core::Error) -> Self {
+        StreamError::Azure(err)
     }
+}
 
-    pub async fn exists(&self) -> Result<bool, Box<dyn std::error::Error>> {
-        Ok(self.blob_client.exists().await?)
+// Write content of the blob to the file, advancing the position past it.
+// Content beyond the blob's size isn't written.
+async fn write_content(
+    file: &mut tokio::fs::File,
+    hasher: &mut Hasher,
+    content: &[u8],
+    size: u64,
+    position: &mut u64,
+) -> Result<(), StreamError> {
+    if *position + content.len() as u64 > size {
+        return Err(StreamError::Azure(azure_core::Error::message(
+            ErrorKind::DataConversion,
+            format!(
+                "Decompressed content is larger than the blob's {} bytes",
+                size
+            ),
+        )));
     }
+    hasher.update(content);
+    file.write_all(content).await.map_err(StreamError::Write)?;
+    *position += content.len() as u64;
+    Ok(())
+}
 
-    pub async fn properties(&self) -> Result<GetPropertiesResponse, Box<dyn std::error::Error>> {
-        Ok(self.blob_client.get_properties().await?)
+fn decompression_error(err: std::io::Error) -> StreamError {
+    StreamError::Azure(azure_core::Error::new(ErrorKind::DataConversion, err))
+}
+
+// Open the file to download into. When resuming, the existing content is
+// kept and hashed so the hashes cover the whole file, and writes are appended
+// after it; otherwise the file is replaced. Symlinks are never written
+// through, in case they lead somewhere the download mustn't go.
+async fn open_for_download(
+    filename: &str,
+    resume_from: u64,
+    unsafe_destination: UnsafeDestination,
+) -> Result<(tokio::fs::File, Hasher), Box<dyn std::error::Error>> {
+    let existing = check_destination(filename, unsafe_destination).await?;
+    let mut hasher = Hasher::new();
+    if resume_from == 0 {
+        // Creating a new file, rather than truncating the existing one, fails
+        // rather than following a symlink put in its place since.
+        if existing.is_some() {
+            tokio::fs::remove_file(filename).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(filename)
+            .await?;
+        return Ok((file, hasher));
     }
 
-    pub async fn uri_start_fields(&self) -> Result<(u64, String), Box<dyn std::error::Error>> {
-        // Return the size and the last modified time
-        let properties = self.properties().await?;
-        Ok((
-            properties.blob.properties.content_length,
-            properties.blob.properties.last_modified.to_string(),
-        ))
+    let existing = existing.ok_or_else(|| format!("{} is missing", filename))?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)
+        .await?;
+    // Make sure the file opened is the one checked, and not something put in
+    // its place since.
+    let opened = file.metadata().await?;
+    if (opened.dev(), opened.ino()) != (existing.dev(), existing.ino()) {
+        return Err(format!("{} changed while resuming", filename).into());
+    }
+    let mut existing = (&mut file).take(resume_from);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = existing.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    if hasher.size() != resume_from {
+        return Err(format!("{} changed while resuming", filename).into());
     }
 
-    pub(crate) async fn download(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        Ok(self.blob_client.get_content().await?)
+    // Drop anything past the resume point, then append after it.
+    file.set_len(resume_from).await?;
+    file.seek(SeekFrom::Start(resume_from)).await?;
+    Ok((file, hasher))
+}
+
+// Check what's at the path to download into, returning its metadata if it's
+// a regular file. Anything else, such as a symlink, is refused or removed
+// without following it, as configured; directories are always refused.
+async fn check_destination(
+    filename: &str,
+    unsafe_destination: UnsafeDestination,
+) -> Result<Option<std::fs::Metadata>, Box<dyn std::error::Error>> {
+    let metadata = match tokio::fs::symlink_metadata(filename).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_file() {
+        return Ok(Some(metadata));
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "directory"
+    } else {
+        "special file"
+    };
+    if file_type.is_dir() || unsafe_destination == UnsafeDestination::Refuse {
+        return Err(format!(
+            "Refusing to download into {}, which is a {}",
+            filename, kind
+        )
+        .into());
     }
+    warn!("Replacing {}, which is a {}", filename, kind);
+    tokio::fs::remove_file(filename).await?;
+    Ok(None)
+}
+
+// Split a range of a blob into consecutive ranges of at most `chunk_size`
+// bytes.
+fn chunk_ranges(range: Range<u64>, chunk_size: u64) -> Vec<Range<u64>> {
+    let end = range.end;
+    range
+        .step_by(chunk_size as usize)
+        .map(|start| start..std::cmp::min(start + chunk_size, end))
+        .collect()
+}
+
+// A token credential shared by the clients which authenticate with it.
+type SharedCredential = Arc<dyn TokenCredential>;
+
+// What a token credential is created for: the authority host, the token
+// sources and any managed identity client ID.
+type CredentialKey = (String, Vec<TokenSource>, Option<String>);
+
+// Which credentials a blob service client accesses an account with.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum ClientCredentials {
+    Sas(String),
+    Emulator,
+    Configured,
+    Anonymous,
 }
 
+// What a blob service client is created for: the account, its cloud and the
+// credentials used.
+type ServiceKey = (String, Cloud, ClientCredentials);
+
 pub(crate) struct AzureRegistry {
-    credential: Arc<DefaultAzureCredential>,
+    // Token credentials for Azure, created as they're first needed, which
+    // keep their tokens for the rest of the session.
+    credentials: Mutex<HashMap<CredentialKey, Arc<dyn TokenCredential>>>,
+    // Blob service clients, created as they're first needed, from which the
+    // clients for each blob are derived.
+    service_clients: Mutex<HashMap<ServiceKey, BlobServiceClient>>,
+    // The HTTP client all requests are made with, so that connections are
+    // reused across blobs and accounts.
+    http_client: Arc<dyn HttpClient>,
 }
 
 impl AzureRegistry {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        // Get a credential for Azure
-        let credential = DefaultAzureCredentialBuilder::new().build()?;
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
         Ok(AzureRegistry {
-            credential: Arc::new(credential),
+            credentials: Mutex::new(HashMap::new()),
+            service_clients: Mutex::new(HashMap::new()),
+            http_client: redirect::new_http_client(config)?,
         })
     }
 
-    pub fn get_blob(&self, url: &Url) -> Result<AzureBlob, Box<dyn std::error::Error>> {
-        AzureBlob::new_from_url(self, url)
+    // Get a credential for Azure which authenticates with the given
+    // authority host, using the configured token sources.
+    pub(crate) fn credential(
+        &self,
+        authority_host: &str,
+        config: &Config,
+    ) -> Arc<dyn TokenCredential> {
+        let client_id = config.managed_identity_client_id.as_deref();
+        let key = (
+            authority_host.to_string(),
+            config.token_sources.clone(),
+            client_id.map(str::to_string),
+        );
+        let mut credentials = self.credentials.lock().unwrap();
+        credentials
+            .entry(key)
+            .or_insert_with(|| {
+                debug!("Creating credential for authority {}", authority_host);
+                Arc::new(CachedCredential::new(identity::token_credential(
+                    &config.token_sources,
+                    client_id,
+                    authority_host,
+                    self.http_client.clone(),
+                )))
+            })
+            .clone()
     }
 
+    pub fn get_blob(
+        &self,
+        url: &Url,
+        account: Option<&str>,
+        config: &Config,
+    ) -> Result<AzureBlob, Box<dyn std::error::Error>> {
+        AzureBlob::new_from_url(self, url, account, config)
+    }
+
+    /// Get a client for a blob, accessed with the given SAS token if there is
+    /// one, and the token credential it authenticates with if it uses one.
     pub fn get_blob_client(
         &self,
         account: &str,
+        cloud: Cloud,
         container_name: &str,
         blob_name: &str,
-    ) -> BlobClient {
-        // Check to see if an AZURE_STORAGE_BEARER_TOKEN is set. This is a token with the
-        // storage.azure.com scope. It's prioritised over user credentials.
-        let storage_credentials = match std::env::var("AZURE_STORAGE_BEARER_TOKEN") {
-            Ok(token) => {
-                debug!("Using storage bearer token for accessing {}", account);
-                StorageCredentials::bearer_token(token)
+        sas_token: Option<&str>,
+        config: &Config,
+    ) -> Result<(BlobClient, Option<SharedCredential>), Box<dyn std::error::Error>> {
+        // A SAS token given with the blob's URL is used first. Then check the
+        // SAS token file, as it's specific to the account or container.
+        let sas_token = match sas_token {
+            Some(token) => Some(token.to_string()),
+            None => Self::sas_token_from_file(account, container_name, config),
+        };
+        // The emulator's development account has a well-known key, which
+        // takes the place of other credentials.
+        let mut token_credential = None;
+        let (client_credentials, storage_credentials) = match sas_token {
+            Some(token) => {
+                debug!(
+                    "Using SAS token for accessing {}/{}",
+                    account, container_name
+                );
+                let credentials = StorageCredentials::sas_token(&token)?;
+                (ClientCredentials::Sas(token), credentials)
             }
-            Err(_) => {
-                debug!("Using token credentials for accessing {}", account);
-                StorageCredentials::token_credential(self.credential.clone())
+            None if matches!(cloud, Cloud::Emulator(_)) => {
+                debug!(
+                    "Using the emulator's development account key for {}",
+                    account
+                );
+                (ClientCredentials::Emulator, StorageCredentials::emulator())
+            }
+            None => match self.configured_credentials(account, &cloud, config) {
+                Ok((credentials, credential)) => {
+                    token_credential = credential;
+                    (ClientCredentials::Configured, credentials)
+                }
+                Err(err) if config.allow_anonymous => {
+                    warn!(
+                        "No credentials for {}, accessing it anonymously: {}",
+                        account, err
+                    );
+                    (
+                        ClientCredentials::Anonymous,
+                        StorageCredentials::anonymous(),
+                    )
+                }
+                Err(err) => return Err(err),
+            },
+        };
+
+        let blob_client = self
+            .service_client(
+                account,
+                &cloud,
+                client_credentials,
+                storage_credentials,
+                config,
+            )
+            .container_client(container_name)
+            .blob_client(blob_name);
+        Ok((blob_client, token_credential))
+    }
+
+    // Get the blob service client for an account accessed with the given
+    // credentials, creating it if this is the first time it's needed. Blob
+    // clients derived from it share its pipeline and connections.
+    fn service_client(
+        &self,
+        account: &str,
+        cloud: &Cloud,
+        client_credentials: ClientCredentials,
+        storage_credentials: StorageCredentials,
+        config: &Config,
+    ) -> BlobServiceClient {
+        let key = (account.to_string(), cloud.clone(), client_credentials);
+        let mut service_clients = self.service_clients.lock().unwrap();
+        service_clients
+            .entry(key)
+            .or_insert_with(|| {
+                debug!("Creating blob service client for {}", account);
+                blob_client_builder(
+                    account,
+                    cloud,
+                    storage_credentials,
+                    config,
+                    self.http_client.clone(),
+                )
+                .blob_service_client()
+            })
+            .clone()
+    }
+
+    // The first available credentials in the configured order, and the token
+    // credential they're from if they are. Token credentials are always
+    // available, as they're only checked once used.
+    fn configured_credentials(
+        &self,
+        account: &str,
+        cloud: &Cloud,
+        config: &Config,
+    ) -> Result<(StorageCredentials, Option<SharedCredential>), Box<dyn std::error::Error>> {
+        for credential in &config.credential_order {
+            match credential {
+                Credential::Key => {
+                    if let Some(key) = Self::account_key(account, config) {
+                        debug!("Using account key for accessing {}", account);
+                        return Ok((StorageCredentials::access_key(account, key), None));
+                    }
+                }
+                Credential::Bearer => {
+                    // This is a token with the storage.azure.com scope.
+                    if let Ok(token) = std::env::var("AZURE_STORAGE_BEARER_TOKEN") {
+                        debug!("Using storage bearer token for accessing {}", account);
+                        return Ok((StorageCredentials::bearer_token(token), None));
+                    }
+                }
+                Credential::Token => {
+                    let authority_host = authority_host(cloud, config);
+                    debug!(
+                        "Using token credentials from {} for accessing {}",
+                        authority_host, account
+                    );
+                    let mut credential = self.credential(&authority_host, config);
+                    let scope = storage_scope(cloud, config);
+                    if scope != STORAGE_SCOPE {
+                        debug!("Getting tokens with scope {} for {}", scope, account);
+                        credential = Arc::new(ScopedCredential::new(credential, scope));
+                    }
+                    return Ok((
+                        StorageCredentials::token_credential(credential.clone()),
+                        Some(credential),
+                    ));
+                }
+            }
+        }
+        Err(format!("No credentials available for {}", account).into())
+    }
+
+    /// The key for an account from the key file or the environment, if
+    /// either has one.
+    pub(crate) fn account_key(account: &str, config: &Config) -> Option<String> {
+        let mut keys = AccountKeys::load(&config.key_file).unwrap_or_else(|err| {
+            warn!("Failed to read {}: {}", config.key_file, err);
+            AccountKeys::default()
+        });
+        keys.add_from_env(
+            std::env::var("AZURE_STORAGE_CONNECTION_STRING")
+                .ok()
+                .as_deref(),
+            std::env::var("AZURE_STORAGE_ACCOUNT").ok().as_deref(),
+            std::env::var("AZURE_STORAGE_KEY").ok().as_deref(),
+        );
+        keys.lookup(account).map(str::to_string)
+    }
+
+    /// The SAS token for a container from the SAS token file, if it has one.
+    pub(crate) fn sas_token_from_file(
+        account: &str,
+        container_name: &str,
+        config: &Config,
+    ) -> Option<String> {
+        match SasTokens::load(&config.sas_file) {
+            Ok(tokens) => tokens.lookup(account, container_name).and_then(|token| {
+                match StorageCredentials::sas_token(token) {
+                    Ok(_) => Some(token.to_string()),
+                    Err(err) => {
+                        warn!("Ignoring invalid SAS token for {}: {}", account, err);
+                        None
+                    }
+                }
+            }),
+            Err(err) => {
+                warn!("Failed to read {}: {}", config.sas_file, err);
+                None
+            }
+        }
+    }
+}
+
+// Get a client builder for an account, pointing it at the configured
+// endpoint if there is one.
+fn blob_client_builder(
+    account: &str,
+    cloud: &Cloud,
+    storage_credentials: StorageCredentials,
+    config: &Config,
+    http_client: Arc<dyn HttpClient>,
+) -> ClientBuilder {
+    let builder = match &config.endpoint {
+        Some(endpoint) => {
+            let uri = endpoint.replace("{account}", account);
+            debug!("Using endpoint {} for {}", uri, account);
+            ClientBuilder::with_location(
+                CloudLocation::Custom {
+                    account: account.to_string(),
+                    uri,
+                },
+                storage_credentials,
+            )
+        }
+        None => ClientBuilder::with_location(cloud.location(account), storage_credentials),
+    };
+    builder.client_options(client_options(config, http_client))
+}
+
+// The configuration given for the cloud, if it's one of the configured
+// clouds.
+fn custom_cloud<'a>(cloud: &Cloud, config: &'a Config) -> Option<&'a CustomCloud> {
+    match cloud {
+        Cloud::Custom(endpoint_suffix) => config.cloud(endpoint_suffix),
+        _ => None,
+    }
+}
+
+/// The authority to get tokens for accounts in the cloud from. An authority
+/// configured for the cloud takes precedence over one configured for all,
+/// which takes precedence over the one for the cloud.
+pub(crate) fn authority_host(cloud: &Cloud, config: &Config) -> String {
+    if let Some(authority_host) =
+        custom_cloud(cloud, config).and_then(|cloud| cloud.authority_host.as_ref())
+    {
+        return authority_host.clone();
+    }
+    match (
+        &config.authority_host,
+        std::env::var("AZURE_AUTHORITY_HOST"),
+    ) {
+        (Some(authority_host), _) => authority_host.clone(),
+        (None, Ok(authority_host)) => authority_host,
+        (None, Err(_)) => cloud.authority_host().to_string(),
+    }
+}
+
+// The scope of tokens for the storage service of the cloud.
+fn storage_scope<'a>(cloud: &Cloud, config: &'a Config) -> &'a str {
+    custom_cloud(cloud, config)
+        .and_then(|cloud| cloud.scope.as_deref())
+        .unwrap_or(STORAGE_SCOPE)
+}
+
+// Whether the error is the storage service refusing a request because the
+// credentials' identity lacks the role to make it.
+fn is_permission_mismatch(err: &azure_core::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::HttpResponse {
+            error_code: Some(code),
+            ..
+        } if code == "AuthorizationPermissionMismatch"
+    )
+}
+
+/// The `FailReason` to give apt for an error from the storage service, named
+/// as apt's http method names its failures, if there's one for it.
+pub fn fail_reason(err: &azure_core::Error) -> Option<String> {
+    match err.kind() {
+        ErrorKind::HttpResponse {
+            error_code: Some(code),
+            ..
+        } if code == "OperationTimedOut" => Some("Timeout".to_string()),
+        ErrorKind::HttpResponse { status, .. } => Some(format!("HttpError{}", *status as u16)),
+        ErrorKind::Io => connection_fail_reason(err).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// What to do about an error from the storage service, if there's something
+/// more to say than the error does.
+pub fn hint(err: &azure_core::Error) -> Option<&'static str> {
+    if is_permission_mismatch(err) {
+        return Some(
+            "the credentials are accepted, but their identity needs the Storage Blob Data \
+             Reader role on the account or container",
+        );
+    }
+    match err.kind() {
+        ErrorKind::Credential => Some(
+            "no token could be got; check the token sources, and that the managed identity \
+             is assigned to this machine",
+        ),
+        ErrorKind::HttpResponse {
+            status: StatusCode::Forbidden,
+            error_code: Some(code),
+        } if code == "AuthenticationFailed" => Some(
+            "the credentials were rejected; check the SAS token hasn't expired and the \
+             account key is current",
+        ),
+        ErrorKind::HttpResponse {
+            status: StatusCode::Forbidden,
+            error_code: Some(code),
+        } if code == "AuthorizationFailure" => Some(
+            "the request was refused; check the account's firewall allows this machine, \
+             and that the SAS token permits reading",
+        ),
+        ErrorKind::HttpResponse {
+            status: StatusCode::NotFound,
+            error_code: Some(code),
+        } if code == "ContainerNotFound" => {
+            Some("the container doesn't exist; check its name in the source list")
+        }
+        _ if is_auth_error(err) => Some(
+            "the credentials were refused; check they're for this account and grant read \
+             access",
+        ),
+        ErrorKind::Io if connection_fail_reason(err) == Some("ConnectionRefused") => {
+            Some("the connection was refused; check the proxy, if one is used, and firewalls")
+        }
+        ErrorKind::Io => Some(
+            "the storage service couldn't be reached; check DNS, proxies and firewalls, or \
+             the private endpoint's DNS zone",
+        ),
+        _ => None,
+    }
+}
+
+// Why a connection to the storage service couldn't be made or used, from the
+// errors underlying the request failing.
+fn connection_fail_reason(err: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            match err.kind() {
+                std::io::ErrorKind::TimedOut => return Some("Timeout"),
+                std::io::ErrorKind::ConnectionRefused => return Some("ConnectionRefused"),
+                _ => {}
             }
+        }
+        // The HTTP client gives up on requests which outlive its timeouts
+        // with an error of its own.
+        if err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+        {
+            return Some("Timeout");
+        }
+        // The HTTP client's error for a hostname which couldn't be looked up
+        // has no type of its own to check for.
+        if err.to_string().starts_with("dns error") {
+            return Some("ResolveFailure");
+        }
+        source = err.source();
+    }
+    None
+}
+
+// Whether the error is the storage service rejecting the credentials, or
+// there being no way to get a token.
+fn is_auth_error(err: &azure_core::Error) -> bool {
+    match err.kind() {
+        ErrorKind::Credential => true,
+        ErrorKind::HttpResponse { status, .. } => {
+            matches!(status, StatusCode::Unauthorized | StatusCode::Forbidden)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageType};
+
+    #[test]
+    fn test_hint() {
+        let http_error = |status, code: &str| {
+            azure_core::Error::message(
+                ErrorKind::HttpResponse {
+                    status,
+                    error_code: Some(code.to_string()),
+                },
+                "error",
+            )
+        };
+        let mismatch = http_error(StatusCode::Forbidden, "AuthorizationPermissionMismatch");
+        assert!(hint(&mismatch)
+            .unwrap()
+            .contains("Storage Blob Data Reader"));
+        let rejected = http_error(StatusCode::Forbidden, "AuthenticationFailed");
+        assert!(hint(&rejected).unwrap().contains("expired"));
+        let container = http_error(StatusCode::NotFound, "ContainerNotFound");
+        assert!(hint(&container).unwrap().contains("container"));
+        let missing = http_error(StatusCode::NotFound, "BlobNotFound");
+        assert_eq!(hint(&missing), None);
+        let refused = azure_core::Error::new(
+            ErrorKind::Io,
+            std::io::Error::from(std::io::ErrorKind::ConnectionRefused),
+        );
+        assert!(hint(&refused).unwrap().contains("proxy"));
+    }
+
+    #[test]
+    fn test_is_auth_error() {
+        let err = |kind| azure_core::Error::message(kind, "error");
+        assert!(is_auth_error(&err(ErrorKind::Credential)));
+        for (status, expected) in [
+            (StatusCode::Unauthorized, true),
+            (StatusCode::Forbidden, true),
+            (StatusCode::NotFound, false),
+        ] {
+            let kind = ErrorKind::HttpResponse {
+                status,
+                error_code: None,
+            };
+            assert_eq!(is_auth_error(&err(kind)), expected);
+        }
+        assert!(!is_auth_error(&err(ErrorKind::Io)));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        // A service which accepts connections but never responds.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/c/b", listener.local_addr()?))?;
+
+        let mut config = Config::default();
+        config.request_timeout = Duration::from_millis(100);
+        let http_client = redirect::new_http_client(&config)?;
+        let request = azure_core::Request::new(url, azure_core::Method::Get);
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            http_client.execute_request(&request),
+        )
+        .await?
+        .unwrap_err();
+        assert_eq!(fail_reason(&err).as_deref(), Some("Timeout"));
+        assert!(crate::retry::is_transient(&err));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fail_reason() {
+        let http_error = |status, error_code: Option<&str>| {
+            let kind = ErrorKind::HttpResponse {
+                status,
+                error_code: error_code.map(str::to_string),
+            };
+            azure_core::Error::message(kind, "error")
+        };
+        let io_error = |err| azure_core::Error::new(ErrorKind::Io, err);
+        for (err, expected) in [
+            (
+                http_error(StatusCode::NotFound, Some("BlobNotFound")),
+                Some("HttpError404"),
+            ),
+            (
+                http_error(StatusCode::Forbidden, Some("AuthorizationFailure")),
+                Some("HttpError403"),
+            ),
+            (
+                http_error(StatusCode::InternalServerError, Some("OperationTimedOut")),
+                Some("Timeout"),
+            ),
+            (
+                io_error(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+                Some("Timeout"),
+            ),
+            (
+                io_error(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+                Some("ConnectionRefused"),
+            ),
+            (
+                io_error(std::io::Error::other("dns error: no such host")),
+                Some("ResolveFailure"),
+            ),
+            (io_error(std::io::Error::other("reset")), None),
+            (
+                azure_core::Error::message(ErrorKind::Credential, "error"),
+                None,
+            ),
+        ] {
+            assert_eq!(fail_reason(&err).as_deref(), expected, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_is_permission_mismatch() {
+        let err = |error_code: Option<&str>| {
+            let kind = ErrorKind::HttpResponse {
+                status: StatusCode::Forbidden,
+                error_code: error_code.map(str::to_string),
+            };
+            azure_core::Error::message(kind, "error")
         };
+        assert!(is_permission_mismatch(&err(Some(
+            "AuthorizationPermissionMismatch"
+        ))));
+        assert!(!is_permission_mismatch(&err(Some("AuthenticationFailed"))));
+        assert!(!is_permission_mismatch(&err(None)));
+    }
+
+    #[test]
+    fn test_service_clients() -> Result<(), Box<dyn std::error::Error>> {
+        let registry = AzureRegistry::new(&Config::default())?;
+        let config = Config::default();
+        let blob_client = |container, sas_token| {
+            registry.get_blob_client(
+                "account",
+                Cloud::Public,
+                container,
+                "dists/stable/InRelease",
+                sas_token,
+                &config,
+            )
+        };
+        let (client, credential) = blob_client("repo", Some("sv=2022-11-02&sig=a"))?;
+        assert!(credential.is_none());
+        assert_eq!(client.container_client().container_name(), "repo");
+        blob_client("other", Some("sv=2022-11-02&sig=a"))?;
+        assert_eq!(registry.service_clients.lock().unwrap().len(), 1);
+
+        blob_client("repo", Some("sv=2022-11-02&sig=b"))?;
+        assert_eq!(registry.service_clients.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_being_rehydrated() {
+        let http_error = |status, error_code: Option<&str>| {
+            let kind = ErrorKind::HttpResponse {
+                status,
+                error_code: error_code.map(str::to_string),
+            };
+            azure_core::Error::message(kind, "error")
+        };
+        assert!(is_being_rehydrated(&http_error(
+            StatusCode::Conflict,
+            Some("BlobBeingRehydrated")
+        )));
+        assert!(!is_being_rehydrated(&http_error(
+            StatusCode::Conflict,
+            Some("LeaseIdMissing")
+        )));
+        assert!(!is_being_rehydrated(&http_error(
+            StatusCode::Forbidden,
+            None
+        )));
+    }
+
+    #[test]
+    fn test_is_blob_changed() {
+        let http_error = |status| {
+            let kind = ErrorKind::HttpResponse {
+                status,
+                error_code: Some("ConditionNotMet".to_string()),
+            };
+            azure_core::Error::message(kind, "error")
+        };
+        assert!(is_blob_changed(&http_error(StatusCode::PreconditionFailed)));
+        assert!(!is_blob_changed(&http_error(StatusCode::NotModified)));
+        assert!(!is_blob_changed(&azure_core::Error::message(
+            ErrorKind::Io,
+            "reset"
+        )));
+    }
+
+    #[test]
+    fn test_split_pin() -> Result<(), Box<dyn std::error::Error>> {
+        let split = |url: &str| -> Result<(Option<UrlPin>, String), String> {
+            let url = Url::parse(url).map_err(|err| err.to_string())?;
+            let (pin, url) = split_pin(&url)?;
+            Ok((pin, url.to_string()))
+        };
+        assert_eq!(
+            split("blob://a/c/dists/stable/Release")?,
+            (None, "blob://a/c/dists/stable/Release".to_string())
+        );
+        assert_eq!(
+            split("blob://a/c?sv=1&sig=abc/dists/stable/Release")?,
+            (
+                None,
+                "blob://a/c?sv=1&sig=abc/dists/stable/Release".to_string()
+            )
+        );
+        assert_eq!(
+            split("blob://a/c/pool/a.deb?versionid=2024-05-29T12%3A00%3A00.0000000Z")?,
+            (
+                Some(UrlPin::Version("2024-05-29T12:00:00.0000000Z".to_string())),
+                "blob://a/c/pool/a.deb".to_string()
+            )
+        );
+
+        // As apt builds URIs from a sources.list entry with a snapshot.
+        assert_eq!(
+            split("blob://a/c?snapshot=2024-05-29T12:00:00.0000000Z/dists/stable/Release")?,
+            (
+                Some(UrlPin::Snapshot("2024-05-29T12:00:00.0000000Z".to_string())),
+                "blob://a/c/dists/stable/Release".to_string()
+            )
+        );
+        assert_eq!(
+            split("blob://a/c/?sv=1&snapshot=2024-05-29&sig=abc/dists/stable/Release")?,
+            (
+                Some(UrlPin::Snapshot("2024-05-29".to_string())),
+                "blob://a/c/?sv=1&sig=abc/dists/stable/Release".to_string()
+            )
+        );
+
+        assert!(split("blob://a/c?snapshot=1&versionid=2/dists/stable/Release").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dfs_blob_name() {
+        let name = |path: &str| dfs_blob_name(path.split('/'));
+        assert_eq!(
+            name("dists/stable/Release").as_deref(),
+            Ok("dists/stable/Release")
+        );
+        assert_eq!(
+            name("dists//stable/Release").as_deref(),
+            Ok("dists/stable/Release")
+        );
+        assert!(name("dists/stable/").is_err());
+        assert!(name("").is_err());
+    }
+
+    #[test]
+    fn test_check_url() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::default();
+        for url in [
+            "blob://myaccount/repo/dists/stable/Release",
+            "blob://myaccount.blob.core.windows.net/repo/dists/stable/Release",
+            "blob+https://myaccount.blob.core.windows.net/repo/dists/stable/Release",
+            "https://myaccount.blob.core.windows.net/repo/dists/stable/Release",
+            "https://myaccount.blob.core.chinacloudapi.cn/repo/dists/stable/Release",
+            "https://myaccount.dfs.core.windows.net/repo/dists/stable/Release",
+        ] {
+            assert_eq!(check_url(&Url::parse(url)?, &config), Ok(()), "{}", url);
+        }
+        for url in [
+            "https://cdn.example.com/repo/dists/stable/Release",
+            "https://myaccount/repo/dists/stable/Release",
+            "http://myaccount.blob.core.windows.net/repo/dists/stable/Release",
+            "ftp://myaccount.blob.core.windows.net/repo/dists/stable/Release",
+        ] {
+            assert!(check_url(&Url::parse(url)?, &config).is_err(), "{}", url);
+        }
+
+        let mut stack = Config::default();
+        stack.endpoint_suffix = Some("local.azurestack.external".to_string());
+        let url = Url::parse("https://myaccount.blob.local.azurestack.external/repo/a.deb")?;
+        assert!(check_url(&url, &config).is_err());
+        assert_eq!(check_url(&url, &stack), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_cloud_tokens() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::from_message(&Message::new(
+            MessageType::Configuration,
+            vec![
+                (
+                    "Config-Item",
+                    "Acquire::blob::Authority-Host=https://login.example.com",
+                ),
+                (
+                    "Config-Item",
+                    "Acquire::blob::Cloud::=local.azurestack.external https://login.local https://storage.local/.default",
+                ),
+                ("Config-Item", "Acquire::blob::Cloud::=private.example.com"),
+            ],
+        ))?;
+        let stack = Cloud::Custom("local.azurestack.external".to_string());
+        let private = Cloud::Custom("private.example.com".to_string());
+        assert_eq!(authority_host(&stack, &config), "https://login.local");
+        assert_eq!(
+            authority_host(&private, &config),
+            "https://login.example.com"
+        );
+        assert_eq!(
+            authority_host(&Cloud::China, &config),
+            "https://login.example.com"
+        );
+        assert_eq!(
+            storage_scope(&stack, &config),
+            "https://storage.local/.default"
+        );
+        assert_eq!(storage_scope(&private, &config), STORAGE_SCOPE);
+        assert_eq!(storage_scope(&Cloud::Public, &config), STORAGE_SCOPE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        assert_eq!(chunk_ranges(0..0, 10), vec![]);
+        assert_eq!(chunk_ranges(0..5, 10), vec![0..5]);
+        assert_eq!(chunk_ranges(0..10, 10), vec![0..10]);
+        assert_eq!(chunk_ranges(0..25, 10), vec![0..10, 10..20, 20..25]);
+        assert_eq!(chunk_ranges(15..25, 10), vec![15..25]);
+        assert_eq!(chunk_ranges(5..30, 10), vec![5..15, 15..25, 25..30]);
+    }
+
+    #[tokio::test]
+    async fn test_open_for_download() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("partial");
+        let filename = path.to_str().unwrap();
+        std::fs::write(&path, b"hello world")?;
+
+        // Resuming keeps and hashes the content up to the resume point.
+        let (mut file, mut hasher) =
+            open_for_download(filename, 6, UnsafeDestination::Refuse).await?;
+        file.write_all(b"there").await?;
+        file.flush().await?;
+        hasher.update(b"there");
+        assert_eq!(std::fs::read(&path)?, b"hello there");
+        let mut expected = Hasher::new();
+        expected.update(b"hello there");
+        assert_eq!(hasher.finish(), expected.finish());
+
+        // Otherwise the file is started afresh.
+        let (file, hasher) = open_for_download(filename, 0, UnsafeDestination::Refuse).await?;
+        drop(file);
+        assert_eq!(hasher.size(), 0);
+        assert_eq!(std::fs::read(&path)?, b"");
+
+        // The file can't be shorter than the resume point.
+        assert!(open_for_download(filename, 6, UnsafeDestination::Refuse)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_for_download_symlink() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("target");
+        std::fs::write(&target, b"hello world")?;
+        let path = dir.path().join("partial");
+        let filename = path.to_str().unwrap();
+        std::os::unix::fs::symlink(&target, &path)?;
+
+        // Symlinks are refused, whether resuming or not, and left alone.
+        for resume_from in [0, 6] {
+            let opened = open_for_download(filename, resume_from, UnsafeDestination::Refuse).await;
+            assert!(opened.is_err());
+            assert!(std::fs::symlink_metadata(&path)?.is_symlink());
+            assert_eq!(std::fs::read(&target)?, b"hello world");
+        }
+
+        // Or replaced by a new file, without touching what they lead to.
+        let (mut file, _) = open_for_download(filename, 0, UnsafeDestination::Replace).await?;
+        file.write_all(b"downloaded").await?;
+        file.flush().await?;
+        assert!(std::fs::symlink_metadata(&path)?.is_file());
+        assert_eq!(std::fs::read(&path)?, b"downloaded");
+        assert_eq!(std::fs::read(&target)?, b"hello world");
 
-        // Get the client builder.
-        ClientBuilder::new(account, storage_credentials).blob_client(container_name, blob_name)
+        // Directories are always refused.
+        let subdir = dir.path().join("dir");
+        std::fs::create_dir(&subdir)?;
+        let opened =
+            open_for_download(subdir.to_str().unwrap(), 0, UnsafeDestination::Replace).await;
+        assert!(opened.is_err());
+        assert!(subdir.is_dir());
+        Ok(())
     }
 }
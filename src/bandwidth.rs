@@ -0,0 +1,109 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// The limit for the whole run. Data arrives deep within downloads, several
+// of which may be under way at once, so it's kept here rather than threaded
+// through.
+static LIMITER: Limiter = Limiter::new();
+
+/// Limits the rate data is downloaded at, across all downloads at once. Each
+/// chunk of data reserves the time it takes at the limit after those before
+/// it, and its download waits until that time has passed before carrying
+/// on, so downloads share the limit in the order their data arrives.
+#[derive(Debug)]
+pub struct Limiter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    // Bytes a second, if there's a limit.
+    rate: Option<u64>,
+    // When the data reserved so far will have taken its time at the limit.
+    reserved_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub const fn new() -> Self {
+        Limiter {
+            state: Mutex::new(State {
+                rate: None,
+                reserved_until: None,
+            }),
+        }
+    }
+
+    /// Set the limit, in bytes a second, or that there's none.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.rate = rate.filter(|rate| *rate > 0);
+        state.reserved_until = None;
+    }
+
+    /// Wait for as long as the data downloaded takes at the limit, if there
+    /// is one.
+    pub async fn consume(&self, bytes: usize) {
+        let until = {
+            let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+            let Some(rate) = state.rate else {
+                return;
+            };
+            let now = Instant::now();
+            let start = state
+                .reserved_until
+                .filter(|until| *until > now)
+                .unwrap_or(now);
+            let until = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            state.reserved_until = Some(until);
+            until
+        };
+        tokio::time::sleep_until(until).await;
+    }
+}
+
+/// Set the limit on the rate data is downloaded at, in bytes a second, or
+/// that there's none.
+pub fn set_rate(rate: Option<u64>) {
+    LIMITER.set_rate(rate)
+}
+
+/// Wait for as long as the data downloaded takes at the limit, if there is
+/// one.
+pub async fn consume(bytes: usize) {
+    LIMITER.consume(bytes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limiter = Limiter::new();
+        let started = Instant::now();
+        limiter.consume(1 << 30).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_limit_shared() {
+        let limiter = Limiter::new();
+        limiter.set_rate(Some(1_000_000));
+        let started = Instant::now();
+        // Two downloads at once take as long as one of both their sizes.
+        tokio::join!(limiter.consume(100_000), limiter.consume(100_000));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        // A limit of zero is none.
+        limiter.set_rate(Some(0));
+        let started = Instant::now();
+        limiter.consume(1 << 30).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
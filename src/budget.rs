@@ -0,0 +1,59 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Tracks the wall-clock time spent on acquisitions which failed. Once the
+/// limit is reached the budget is exhausted, and remaining acquisitions
+/// should be failed immediately rather than holding up the run.
+#[derive(Debug, Default)]
+pub struct FailureBudget {
+    limit: Option<Duration>,
+    spent: Mutex<Duration>,
+}
+
+impl FailureBudget {
+    /// Create a budget with the given limit; with no limit the budget is
+    /// never exhausted.
+    pub fn new(limit: Option<Duration>) -> Self {
+        FailureBudget {
+            limit,
+            spent: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Record time spent on a failed acquisition.
+    pub fn record(&self, elapsed: Duration) {
+        let mut spent = self.spent.lock().unwrap();
+        *spent += elapsed;
+    }
+
+    pub fn exhausted(&self) -> bool {
+        match self.limit {
+            Some(limit) => *self.spent.lock().unwrap() >= limit,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let budget = FailureBudget::new(None);
+        budget.record(Duration::from_secs(3600));
+        assert!(!budget.exhausted());
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let budget = FailureBudget::new(Some(Duration::from_secs(60)));
+        assert!(!budget.exhausted());
+        budget.record(Duration::from_secs(45));
+        assert!(!budget.exhausted());
+        budget.record(Duration::from_secs(15));
+        assert!(budget.exhausted());
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use azure_core::HttpClient;
+
+use crate::azure::authority_host;
+use crate::cloud::Cloud;
+use crate::config::{Config, DEFAULT_LOG_FILE};
+use crate::credentials::redact_secrets;
+use crate::identity::{self, STORAGE_SCOPE};
+use crate::redirect;
+
+// Lines from the end of the log to include.
+const LOG_TAIL_LINES: usize = 1000;
+
+// Most recent sessions to include transcripts of.
+const TRANSCRIPTS: usize = 5;
+
+// Time to wait for a token when probing token credentials.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Logged at the start of each session with apt.
+const SESSION_START: &str = "Ready to receive messages";
+
+/// Write a tarball to attach to issues, holding the tail of the log, the
+/// effective configuration, a description of the system, the results of
+/// probing each kind of credential and transcripts of the most recent
+/// sessions with apt. SAS signatures and other secrets are redacted.
+pub async fn write(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::from_apt_config();
+    let log_file = match &config {
+        Ok(config) => config.log_file.as_str(),
+        Err(_) => DEFAULT_LOG_FILE,
+    };
+    let log = read_log(log_file);
+    let config_json = match &config {
+        Ok(config) => serde_json::to_string_pretty(&config.dump())? + "\n",
+        Err(err) => format!("{}\n", err),
+    };
+    let credentials = probe_credentials(&config.unwrap_or_default()).await;
+
+    let mut files = vec![
+        ("log.txt".to_string(), tail(&log, LOG_TAIL_LINES)),
+        ("config.json".to_string(), config_json),
+        ("environment.txt".to_string(), environment()),
+        ("credentials.txt".to_string(), credentials),
+    ];
+    for (number, transcript) in transcripts(&log, TRANSCRIPTS).into_iter().enumerate() {
+        files.push((
+            format!("transcripts/session-{}.txt", number + 1),
+            transcript,
+        ));
+    }
+    write_tar(&mut std::fs::File::create(path)?, &files)?;
+    Ok(())
+}
+
+// The log, with secrets redacted, as records logged before the logger
+// redacted them all may still hold some.
+fn read_log(log_file: &str) -> String {
+    match std::fs::read_to_string(log_file) {
+        Ok(log) => redact_secrets(&log),
+        Err(err) => format!("Failed to read {}: {}\n", log_file, err),
+    }
+}
+
+// The last lines of the text.
+fn tail(text: &str, lines: usize) -> String {
+    let all: Vec<_> = text.lines().collect();
+    let start = all.len().saturating_sub(lines);
+    all[start..]
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+// Transcripts of the most recent sessions in the log, oldest first, with
+// messages from apt marked `<<` and those sent to it `>>`. Messages are only
+// logged when debug logging is enabled.
+fn transcripts(log: &str, sessions: usize) -> Vec<String> {
+    let mut transcripts: Vec<String> = vec![];
+    for line in log.lines() {
+        if line.contains(SESSION_START) {
+            transcripts.push(String::new());
+            continue;
+        }
+        let Some(transcript) = transcripts.last_mut() else {
+            continue;
+        };
+        let (marker, message) = match (line.split_once("Buffer: "), line.split_once("Sent: ")) {
+            (Some((_, message)), _) => ("<<", message),
+            (None, Some((_, message))) => (">>", message),
+            (None, None) => continue,
+        };
+        for message_line in unquote(message).lines() {
+            let line = format!("{} {}", marker, message_line);
+            transcript.push_str(line.trim_end());
+            transcript.push('\n');
+        }
+    }
+    transcripts.retain(|transcript| !transcript.is_empty());
+    let start = transcripts.len().saturating_sub(sessions);
+    transcripts.split_off(start)
+}
+
+// Undo the quoting of a string logged with `{:?}`.
+fn unquote(quoted: &str) -> String {
+    let inner = quoted
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or(quoted);
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unquoted.push('\n'),
+            Some('t') => unquoted.push('\t'),
+            Some(other) => unquoted.push(other),
+            None => unquoted.push('\\'),
+        }
+    }
+    unquoted
+}
+
+// A description of the system the method is running on.
+fn environment() -> String {
+    let os = std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|release| {
+            release.lines().find_map(|line| {
+                let name = line.strip_prefix("PRETTY_NAME=")?;
+                Some(name.trim_matches('"').to_string())
+            })
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    let apt = Command::new("apt-get")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            stdout.lines().next().map(str::to_string)
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    format!(
+        "Version: {}\nTarget: {}-{}\nOS: {}\nKernel: {}\napt: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        os,
+        kernel,
+        apt
+    )
+}
+
+// Describe the credentials available to the method, trying to get a token
+// from the token credentials.
+async fn probe_credentials(config: &Config) -> String {
+    let mut results = vec![
+        format!(
+            "SAS token file {}: {}",
+            config.sas_file,
+            describe_file(&config.sas_file)
+        ),
+        format!(
+            "Account key file {}: {}",
+            config.key_file,
+            describe_file(&config.key_file)
+        ),
+    ];
+    for var in [
+        "AZURE_STORAGE_CONNECTION_STRING",
+        "AZURE_STORAGE_ACCOUNT",
+        "AZURE_STORAGE_KEY",
+        "AZURE_STORAGE_BEARER_TOKEN",
+    ] {
+        let set = std::env::var_os(var).is_some();
+        results.push(format!("{}: {}", var, if set { "set" } else { "not set" }));
+    }
+
+    let authority_host = authority_host(&Cloud::Public, config);
+    let token = match redirect::new_http_client(config) {
+        Ok(http_client) => probe_token(config, &authority_host, http_client).await,
+        Err(err) => format!("failed: {}", err),
+    };
+    results.push(format!(
+        "Token credentials from {}: {}",
+        authority_host, token
+    ));
+    results
+        .iter()
+        .map(|result| format!("{}\n", result))
+        .collect()
+}
+
+// Get a token from the configured sources, describing how it went.
+async fn probe_token(
+    config: &Config,
+    authority_host: &str,
+    http_client: Arc<dyn HttpClient>,
+) -> String {
+    let credential = identity::token_credential(
+        &config.token_sources,
+        config.managed_identity_client_id.as_deref(),
+        authority_host,
+        http_client,
+    );
+    let started = Instant::now();
+    match tokio::time::timeout(PROBE_TIMEOUT, credential.get_token(&[STORAGE_SCOPE])).await {
+        Ok(Ok(token)) => format!(
+            "ok in {}ms, expires {}",
+            started.elapsed().as_millis(),
+            token.expires_on
+        ),
+        Ok(Err(err)) => format!("failed: {}", err),
+        Err(_) => format!("timed out after {}s", PROBE_TIMEOUT.as_secs()),
+    }
+}
+
+// Whether a file exists, and who may read it if so, as credential files
+// should only be readable by root.
+fn describe_file(path: &str) -> String {
+    match std::fs::metadata(path) {
+        Ok(metadata) => format!("present, mode {:o}", metadata.permissions().mode() & 0o777),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => "missing".to_string(),
+        Err(err) => err.to_string(),
+    }
+}
+
+// Write the files to a tar archive.
+fn write_tar(writer: impl Write, files: &[(String, String)]) -> std::io::Result<()> {
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut builder = tar::Builder::new(writer);
+    for (name, contents) in files {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, name, contents.as_bytes())?;
+    }
+    builder.into_inner()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(tail("a\nb", 5), "a\nb\n");
+        assert_eq!(tail("", 5), "");
+    }
+
+    #[test]
+    fn test_read_log() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("blob.log");
+        std::fs::write(
+            &log_file,
+            "x [DEBUG] <blob:1> Buffer: \"Config-Item: Acquire::http::Proxy=http://u:p@h\\n\"\n",
+        )?;
+        assert_eq!(
+            read_log(&log_file.display().to_string()),
+            "x [DEBUG] <blob:1> Buffer: \"Config-Item: Acquire::http::Proxy=http://REDACTED@h\\n\"\n"
+        );
+        assert!(read_log("/nonexistent/blob.log").starts_with("Failed to read"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote(r#""600 URI Acquire\n""#), "600 URI Acquire\n");
+        assert_eq!(unquote(r#""a \"b\" \\ c""#), r#"a "b" \ c"#);
+    }
+
+    #[test]
+    fn test_transcripts() {
+        let log = "\
+            x [INFO] <blob:1> Ready to receive messages\n\
+            x [DEBUG] <blob:1> Buffer: \"601 Configuration\\n\"\n\
+            x [INFO] <blob:1> Ready to receive messages\n\
+            x [DEBUG] <blob:1> Buffer: \"600 URI Acquire\\n\"\n\
+            x [DEBUG] <blob:1> Buffer: \"URI: blob://a/c/x\\n\"\n\
+            x [DEBUG] <blob:1> Buffer: \"\\n\"\n\
+            x [INFO] <blob:1> Acquiring URI: blob://a/c/x\n\
+            x [DEBUG] <blob:1> Sent: \"201 URI Done\\nURI: blob://a/c/x\\n\\n\"\n\
+            x [INFO] <blob:1> Ready to receive messages\n";
+        assert_eq!(
+            transcripts(log, 5),
+            vec![
+                "<< 601 Configuration\n",
+                "<< 600 URI Acquire\n\
+                 << URI: blob://a/c/x\n\
+                 <<\n\
+                 >> 201 URI Done\n\
+                 >> URI: blob://a/c/x\n\
+                 >>\n",
+            ]
+        );
+        assert_eq!(transcripts(log, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_write_tar() -> Result<(), Box<dyn std::error::Error>> {
+        let files = vec![
+            ("a.txt".to_string(), "hello\n".to_string()),
+            ("dir/b.txt".to_string(), "x".repeat(512)),
+        ];
+        let mut tar = vec![];
+        write_tar(&mut tar, &files)?;
+
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut read = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut contents)?;
+            read.push((entry.path()?.display().to_string(), contents));
+        }
+        assert_eq!(read, files);
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use azure_storage::CloudLocation;
+
+/// The Azure clouds that storage accounts can be in.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Cloud {
+    Public,
+    China,
+    UsGovernment,
+    /// Azure Stack Hub or another cloud with the given endpoint suffix, e.g.
+    /// `local.azurestack.external`.
+    Custom(String),
+    /// The Azurite storage emulator at the given `host:port`, which takes
+    /// path-style URLs over http.
+    Emulator(String),
+}
+
+impl Cloud {
+    const KNOWN: [Cloud; 3] = [Cloud::Public, Cloud::China, Cloud::UsGovernment];
+
+    /// The suffix of blob service hostnames in this cloud, following the
+    /// account name. The emulator doesn't name accounts in hostnames.
+    pub fn blob_suffix(&self) -> Option<String> {
+        let endpoint_suffix = match self {
+            Cloud::Public => "core.windows.net",
+            Cloud::China => "core.chinacloudapi.cn",
+            Cloud::UsGovernment => "core.usgovcloudapi.net",
+            Cloud::Custom(endpoint_suffix) => endpoint_suffix,
+            Cloud::Emulator(_) => return None,
+        };
+        Some(format!(".blob.{}", endpoint_suffix))
+    }
+
+    /// The suffix of Data Lake Storage hostnames in this cloud, following the
+    /// account name. Accounts with a hierarchical namespace serve their files
+    /// from this endpoint, and as blobs from the blob service.
+    pub fn dfs_suffix(&self) -> Option<String> {
+        Some(self.blob_suffix()?.replacen(".blob.", ".dfs.", 1))
+    }
+
+    /// The Microsoft Entra ID authority that issues tokens for this cloud.
+    /// Other clouds are assumed to use the public cloud's.
+    pub fn authority_host(&self) -> &'static str {
+        match self {
+            Cloud::Public | Cloud::Custom(_) | Cloud::Emulator(_) => {
+                "https://login.microsoftonline.com"
+            }
+            Cloud::China => "https://login.chinacloudapi.cn",
+            Cloud::UsGovernment => "https://login.microsoftonline.us",
+        }
+    }
+
+    /// Where the blob service for the account is in this cloud.
+    pub fn location(&self, account: &str) -> CloudLocation {
+        let account = account.to_string();
+        match self {
+            Cloud::Public => CloudLocation::Public { account },
+            Cloud::China => CloudLocation::China { account },
+            Cloud::UsGovernment | Cloud::Custom(_) => CloudLocation::Custom {
+                uri: format!(
+                    "https://{}{}",
+                    account,
+                    self.blob_suffix().unwrap_or_default()
+                ),
+                account,
+            },
+            Cloud::Emulator(address) => CloudLocation::Custom {
+                uri: format!("http://{}/{}", address, account),
+                account,
+            },
+        }
+    }
+
+    /// Split a blob service or Data Lake Storage hostname into the storage
+    /// account and the cloud it's in, given the endpoint suffixes of any
+    /// other clouds in use. A hostname without a known suffix is taken to be
+    /// the name of an account in the public cloud.
+    pub fn from_host<'a>(host: &'a str, endpoint_suffixes: &[&str]) -> (&'a str, Cloud) {
+        Self::split_host(host, endpoint_suffixes)
+            .map(|(account, cloud, _)| (account, cloud))
+            .unwrap_or((host, Cloud::Public))
+    }
+
+    /// Whether the hostname is a blob service's, or Data Lake Storage's, in
+    /// a known cloud or one with the given endpoint suffixes.
+    pub fn is_blob_host(host: &str, endpoint_suffixes: &[&str]) -> bool {
+        Self::split_host(host, endpoint_suffixes)
+            .is_some_and(|(account, _, _)| !account.is_empty() && !account.contains('.'))
+    }
+
+    /// Whether the hostname is Data Lake Storage's, in a known cloud or the
+    /// one with the given endpoint suffixes.
+    pub fn is_dfs_host(host: &str, endpoint_suffixes: &[&str]) -> bool {
+        Self::split_host(host, endpoint_suffixes).is_some_and(|(_, _, dfs)| dfs)
+    }
+
+    // Split a hostname with a known suffix into the account, the cloud, and
+    // whether it's Data Lake Storage's rather than the blob service's.
+    fn split_host<'a>(host: &'a str, endpoint_suffixes: &[&str]) -> Option<(&'a str, Cloud, bool)> {
+        let custom = endpoint_suffixes
+            .iter()
+            .map(|suffix| Cloud::Custom(suffix.to_string()));
+        custom.chain(Self::KNOWN).find_map(|cloud| {
+            if let Some(account) = host.strip_suffix(&cloud.blob_suffix()?) {
+                return Some((account, cloud, false));
+            }
+            let account = host.strip_suffix(&cloud.dfs_suffix()?)?;
+            Some((account, cloud, true))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_storage::clients::ServiceType;
+
+    #[test]
+    fn test_from_host() {
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.core.windows.net", &[]),
+            ("myaccount", Cloud::Public)
+        );
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.core.chinacloudapi.cn", &[]),
+            ("myaccount", Cloud::China)
+        );
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.core.usgovcloudapi.net", &[]),
+            ("myaccount", Cloud::UsGovernment)
+        );
+        assert_eq!(
+            Cloud::from_host("myaccount", &[]),
+            ("myaccount", Cloud::Public)
+        );
+
+        assert_eq!(
+            Cloud::from_host("myaccount.dfs.core.windows.net", &[]),
+            ("myaccount", Cloud::Public)
+        );
+
+        let stack = &["local.azurestack.external"];
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.local.azurestack.external", stack),
+            (
+                "myaccount",
+                Cloud::Custom("local.azurestack.external".to_string())
+            )
+        );
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.core.windows.net", stack),
+            ("myaccount", Cloud::Public)
+        );
+
+        // Accounts in several other clouds can be used together.
+        let clouds = &["local.azurestack.external", "private.example.com"];
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.private.example.com", clouds),
+            (
+                "myaccount",
+                Cloud::Custom("private.example.com".to_string())
+            )
+        );
+        assert_eq!(
+            Cloud::from_host("myaccount.blob.local.azurestack.external", clouds),
+            (
+                "myaccount",
+                Cloud::Custom("local.azurestack.external".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_blob_host() {
+        assert!(Cloud::is_blob_host("myaccount.blob.core.windows.net", &[]));
+        assert!(Cloud::is_blob_host(
+            "myaccount.blob.core.chinacloudapi.cn",
+            &[]
+        ));
+        assert!(!Cloud::is_blob_host("cdn.example.com", &[]));
+        assert!(!Cloud::is_blob_host(".blob.core.windows.net", &[]));
+        assert!(!Cloud::is_blob_host(
+            "myaccount.blob.local.azurestack.external",
+            &[]
+        ));
+        assert!(Cloud::is_blob_host(
+            "myaccount.blob.local.azurestack.external",
+            &["local.azurestack.external"]
+        ));
+        assert!(Cloud::is_blob_host("myaccount.dfs.core.windows.net", &[]));
+    }
+
+    #[test]
+    fn test_is_dfs_host() {
+        assert!(Cloud::is_dfs_host("myaccount.dfs.core.windows.net", &[]));
+        assert!(Cloud::is_dfs_host(
+            "myaccount.dfs.core.usgovcloudapi.net",
+            &[]
+        ));
+        assert!(!Cloud::is_dfs_host("myaccount.blob.core.windows.net", &[]));
+        assert!(!Cloud::is_dfs_host("myaccount", &[]));
+        assert!(Cloud::is_dfs_host(
+            "myaccount.dfs.local.azurestack.external",
+            &["local.azurestack.external"]
+        ));
+    }
+
+    #[test]
+    fn test_location() -> Result<(), Box<dyn std::error::Error>> {
+        let custom = Cloud::Custom("local.azurestack.external".to_string());
+        for cloud in Cloud::KNOWN.into_iter().chain([custom]) {
+            let url = cloud.location("myaccount").url(ServiceType::Blob)?;
+            let expected = format!("myaccount{}", cloud.blob_suffix().unwrap());
+            assert_eq!(url.host_str(), Some(expected.as_str()));
+            assert_eq!(url.scheme(), "https");
+        }
+
+        let emulator = Cloud::Emulator("127.0.0.1:10000".to_string());
+        let url = emulator
+            .location("devstoreaccount1")
+            .url(ServiceType::Blob)?;
+        assert_eq!(url.as_str(), "http://127.0.0.1:10000/devstoreaccount1");
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::sync::Arc;
+
+use azure_core::headers::CLIENT_REQUEST_ID;
+use azure_core::{Context, Policy, PolicyResult, Request};
+
+use crate::logging;
+
+/// Sends the ID of the acquisition each request is made for as its
+/// `x-ms-client-request-id`, which storage analytics logs record, so that
+/// requests can be matched with the method's log. Retries of a request keep
+/// its ID.
+#[derive(Debug)]
+pub struct ClientRequestIdPolicy;
+
+#[async_trait::async_trait]
+impl Policy for ClientRequestIdPolicy {
+    async fn send(
+        &self,
+        ctx: &Context,
+        request: &mut Request,
+        next: &[Arc<dyn Policy>],
+    ) -> PolicyResult {
+        if let Some(request_id) = logging::request_id() {
+            request.insert_header(CLIENT_REQUEST_ID, request_id);
+        }
+        next[0].send(ctx, request, &next[1..]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_core::error::ErrorKind;
+    use azure_core::Method;
+    use url::Url;
+
+    // Fails each request with its client request ID, if it has one.
+    #[derive(Debug)]
+    struct EchoRequestId;
+
+    #[async_trait::async_trait]
+    impl Policy for EchoRequestId {
+        async fn send(
+            &self,
+            _: &Context,
+            request: &mut Request,
+            _: &[Arc<dyn Policy>],
+        ) -> PolicyResult {
+            let request_id = request.headers().get_optional_string(&CLIENT_REQUEST_ID);
+            Err(azure_core::Error::message(
+                ErrorKind::Other,
+                request_id.unwrap_or_default(),
+            ))
+        }
+    }
+
+    async fn send() -> String {
+        let mut request = Request::new(
+            Url::parse("https://a.blob.core.windows.net/c/b").unwrap(),
+            Method::Get,
+        );
+        let next: [Arc<dyn Policy>; 1] = [Arc::new(EchoRequestId)];
+        let err = ClientRequestIdPolicy
+            .send(&Context::new(), &mut request, &next)
+            .await
+            .unwrap_err();
+        err.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_client_request_id() {
+        assert_eq!(send().await, "");
+        let (sent, expected) = logging::with_acquisition(Some("blob://a/c/b".to_string()), async {
+            (send().await, logging::request_id().unwrap())
+        })
+        .await;
+        assert_eq!(sent, expected);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::azure::{self, authority_host, AzureRegistry};
+use crate::cloud::Cloud;
+use crate::config::{Config, Credential};
+use crate::credentials::{redact_sas, split_sas};
+use crate::identity::{self, STORAGE_SCOPE};
+use crate::naming;
+use crate::redirect;
+
+// Time to wait for a token from each source.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The container an account's properties are got through when no container
+// is named. Any container's URL serves them.
+const ROOT_CONTAINER: &str = "$root";
+
+/// Check the method can access a storage account, or a container or blob
+/// given by URL, with apt's configuration: which credentials it has, whether
+/// each source of tokens gives one, and whether the storage service accepts
+/// a request. Each check is printed, with hints for any that fail. Returns
+/// whether they all passed.
+pub async fn run(target: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let config = match Config::from_apt_config() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("apt configuration: {}; using the defaults", err);
+            Config::default()
+        }
+    };
+    let url = target_url(target)?;
+    let host = url.host_str().ok_or("No host")?;
+    let (account, cloud) = Cloud::from_host(host, &config.endpoint_suffixes());
+    naming::check_account(account)?;
+    let container = url
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .filter(|container| !container.is_empty());
+    println!("Account: {} ({:?})", account, cloud);
+
+    let mut passed = check_credentials(account, &cloud, container, &url, &config).await;
+    passed &= check_access(&url, container, &config).await?;
+    Ok(passed)
+}
+
+// The URL to check access with: the one given, or one for the account given
+// by name or hostname.
+fn target_url(target: &str) -> Result<Url, Box<dyn std::error::Error>> {
+    if target.contains("://") {
+        return Ok(Url::parse(target)?);
+    }
+    let host = match target.contains('.') {
+        true => target.to_string(),
+        false => format!("{}{}", target, Cloud::Public.blob_suffix().unwrap()),
+    };
+    Ok(Url::parse(&format!("blob://{}/", host))?)
+}
+
+// Print the credentials the method could use for the account, in the order
+// it tries them, getting a token from each source of them. Returns whether
+// any are available.
+async fn check_credentials(
+    account: &str,
+    cloud: &Cloud,
+    container: Option<&str>,
+    url: &Url,
+    config: &Config,
+) -> bool {
+    let mut available = false;
+    if split_sas(url).is_some() {
+        println!("SAS token: given in the URL");
+        available = true;
+    }
+    if let Some(container) = container {
+        if AzureRegistry::sas_token_from_file(account, container, config).is_some() {
+            println!("SAS token: in {}", config.sas_file);
+            available = true;
+        }
+    }
+    for credential in &config.credential_order {
+        match credential {
+            Credential::Key => match AzureRegistry::account_key(account, config) {
+                Some(_) => {
+                    println!("Account key: available");
+                    available = true;
+                }
+                None => println!(
+                    "Account key: none in {} or the environment",
+                    config.key_file
+                ),
+            },
+            Credential::Bearer => match std::env::var_os("AZURE_STORAGE_BEARER_TOKEN") {
+                Some(_) => {
+                    println!("Bearer token: AZURE_STORAGE_BEARER_TOKEN is set");
+                    available = true;
+                }
+                None => println!("Bearer token: AZURE_STORAGE_BEARER_TOKEN isn't set"),
+            },
+            Credential::Token => {
+                let authority_host = authority_host(cloud, config);
+                let http_client = match redirect::new_http_client(config) {
+                    Ok(http_client) => http_client,
+                    Err(err) => {
+                        println!("Token credentials: {}", err);
+                        continue;
+                    }
+                };
+                println!("Token credentials from {}:", authority_host);
+                for source in &config.token_sources {
+                    let credential = identity::token_credential(
+                        &[*source],
+                        config.managed_identity_client_id.as_deref(),
+                        &authority_host,
+                        http_client.clone(),
+                    );
+                    let started = Instant::now();
+                    let token =
+                        tokio::time::timeout(TOKEN_TIMEOUT, credential.get_token(&[STORAGE_SCOPE]))
+                            .await;
+                    let elapsed = started.elapsed().as_millis();
+                    match token {
+                        Ok(Ok(token)) => {
+                            let identity = identity::describe_token(&token)
+                                .map(|identity| format!(" as {}", identity))
+                                .unwrap_or_default();
+                            println!("  {}: ok{} ({}ms)", source.as_str(), identity, elapsed);
+                            available = true;
+                        }
+                        // The error says how long the source took, or why
+                        // it was skipped.
+                        Ok(Err(err)) => println!("  {}", err),
+                        Err(_) => println!(
+                            "  {}: timed out after {}s",
+                            source.as_str(),
+                            TOKEN_TIMEOUT.as_secs()
+                        ),
+                    }
+                }
+            }
+        }
+    }
+    if !available {
+        println!(
+            "Hint: no credentials are available; give a SAS token in {}, a key in {}, \
+             or assign this machine a managed identity",
+            config.sas_file, config.key_file
+        );
+        if config.allow_anonymous {
+            println!("Anonymous access is allowed, so public containers can still be read");
+            return true;
+        }
+    }
+    available
+}
+
+// Make the cheapest request that needs access to what the URL names: the
+// blob's properties, whether the container exists, or the account's
+// properties. Returns whether it succeeded.
+async fn check_access(
+    url: &Url,
+    container: Option<&str>,
+    config: &Config,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut url = url.clone();
+    if container.is_none() {
+        url.set_path(&format!("/{}/", ROOT_CONTAINER));
+    }
+    let registry = AzureRegistry::new(config)?;
+    let mut blob = registry.get_blob(&url, None, config)?;
+    let is_blob = url
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .is_some_and(|(_, blob_name)| !blob_name.is_empty());
+    // What was found, if anything was.
+    let (what, result) = if is_blob {
+        let result = blob
+            .info()
+            .await
+            .map(|info| info.map(|info| format!("{} bytes, ETag {}", info.size, info.etag)));
+        ("Blob properties", result)
+    } else if container.is_some() {
+        let result = blob
+            .container_exists()
+            .await
+            .map(|exists| exists.then(|| "exists".to_string()));
+        ("Container", result)
+    } else {
+        let result = blob
+            .account_information()
+            .await
+            .map(|(kind, sku)| Some(format!("{}, {}", kind, sku)));
+        ("Account properties", result)
+    };
+    match result {
+        Ok(Some(found)) => {
+            println!("{}: {}", what, found);
+            Ok(true)
+        }
+        Ok(None) => {
+            println!("{}: not found", what);
+            println!("Hint: check the path in {}", redact_sas(url.as_str()));
+            Ok(false)
+        }
+        Err(err) => {
+            println!("{}: failed: {}", what, redact_sas(&err.to_string()));
+            if let Some(hint) = err
+                .downcast_ref::<azure_core::Error>()
+                .and_then(azure::hint)
+            {
+                println!("Hint: {}", hint);
+            }
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_url() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            target_url("myaccount")?.as_str(),
+            "blob://myaccount.blob.core.windows.net/"
+        );
+        assert_eq!(
+            target_url("myaccount.blob.core.chinacloudapi.cn")?.as_str(),
+            "blob://myaccount.blob.core.chinacloudapi.cn/"
+        );
+        assert_eq!(
+            target_url("blob://myaccount.blob.core.windows.net/repo/dists/stable/Release")?
+                .as_str(),
+            "blob://myaccount.blob.core.windows.net/repo/dists/stable/Release"
+        );
+        Ok(())
+    }
+}
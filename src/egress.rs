@@ -0,0 +1,174 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::atomic;
+
+/// Counts the bytes downloaded from each storage account in the current
+/// month, keeping the counts in a file so they accumulate across runs. A
+/// warning is logged for each download from an account over its monthly
+/// budget.
+#[derive(Debug, Default)]
+pub struct EgressCounter {
+    path: Option<PathBuf>,
+    budget: Option<u64>,
+    // Held while the file is updated, so concurrent downloads don't lose
+    // each other's counts.
+    lock: Arc<Mutex<()>>,
+}
+
+// The counts kept in the file.
+#[derive(Debug, Default, PartialEq)]
+struct Counts {
+    month: String,
+    accounts: BTreeMap<String, u64>,
+}
+
+impl EgressCounter {
+    /// Create a counter keeping its counts in the given file; with no file,
+    /// nothing is counted.
+    pub fn new(path: Option<&str>, budget: Option<u64>) -> Self {
+        EgressCounter {
+            path: path.map(PathBuf::from),
+            budget,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Record bytes downloaded from an account. Failing to update the file is
+    /// logged, but doesn't fail the download.
+    pub async fn record(&self, account: &str, bytes: u64) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let (file, month, name) = (
+            path.clone(),
+            month(OffsetDateTime::now_utc()),
+            account.to_string(),
+        );
+        let updated = atomic::update(&self.lock, move || {
+            Self::update(&file, &month, &name, bytes)
+        })
+        .await;
+        match updated {
+            Ok(total) => {
+                info!("Downloaded {} bytes from {} this month", total, account);
+                if let Some(budget) = self.budget.filter(|budget| total > *budget) {
+                    warn!(
+                        "Downloads from {} this month ({} bytes) exceed the budget of {} bytes",
+                        account, total, budget
+                    );
+                }
+            }
+            Err(err) => warn!(
+                "Failed to update egress counts in {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    // Add to the count for an account in the file, returning its new total
+    // for the month.
+    fn update(
+        path: &Path,
+        month: &str,
+        account: &str,
+        bytes: u64,
+    ) -> Result<u64, atomic::UpdateError> {
+        let mut counts = match std::fs::read_to_string(path) {
+            Ok(contents) => Counts::parse(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Counts::default(),
+            Err(err) => return Err(err.into()),
+        };
+        // Start counting afresh each month.
+        if counts.month != month {
+            counts = Counts {
+                month: month.to_string(),
+                accounts: BTreeMap::new(),
+            };
+        }
+        let total = counts.accounts.entry(account.to_string()).or_default();
+        *total += bytes;
+        let total = *total;
+
+        atomic::write(path, counts.to_json())?;
+        Ok(total)
+    }
+}
+
+impl Counts {
+    fn parse(contents: &str) -> Result<Self, atomic::UpdateError> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        let month = value["month"].as_str().ok_or("No month")?.to_string();
+        let accounts = value["accounts"]
+            .as_object()
+            .ok_or("No accounts")?
+            .iter()
+            .filter_map(|(account, bytes)| Some((account.clone(), bytes.as_u64()?)))
+            .collect();
+        Ok(Counts { month, accounts })
+    }
+
+    fn to_json(&self) -> String {
+        json!({ "month": self.month, "accounts": self.accounts }).to_string()
+    }
+}
+
+// The month counts are kept for, e.g. `2024-05`.
+fn month(now: OffsetDateTime) -> String {
+    format!("{:04}-{:02}", now.year(), u8::from(now.month()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_month() {
+        let now = OffsetDateTime::from_unix_timestamp(1716984000).unwrap();
+        assert_eq!(month(now), "2024-05");
+    }
+
+    #[test]
+    fn test_update() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("egress").join("egress.json");
+
+        assert_eq!(EgressCounter::update(&path, "2024-05", "a", 100)?, 100);
+        assert_eq!(EgressCounter::update(&path, "2024-05", "a", 50)?, 150);
+        assert_eq!(EgressCounter::update(&path, "2024-05", "b", 10)?, 10);
+        let counts = Counts::parse(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(counts.month, "2024-05");
+        assert_eq!(counts.accounts["a"], 150);
+        assert_eq!(counts.accounts["b"], 10);
+
+        // A new month starts from zero.
+        assert_eq!(EgressCounter::update(&path, "2024-06", "a", 5)?, 5);
+        let counts = Counts::parse(&std::fs::read_to_string(&path)?)?;
+        assert!(!counts.accounts.contains_key("b"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("egress.json");
+
+        // Without a file nothing is counted.
+        EgressCounter::new(None, Some(1)).record("a", 100).await;
+
+        let counter = EgressCounter::new(path.to_str(), Some(100));
+        counter.record("a", 60).await;
+        counter.record("a", 60).await;
+        let counts = Counts::parse(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(counts.accounts["a"], 120);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::message::Message;
+
+// The reasons for failures which acquiring the URI again straight away would
+// only repeat, after the same requests and retries: the storage service's
+// answers, and its being unreachable. Failures of a particular download,
+// such as corruption, aren't remembered.
+const REMEMBERED_REASONS: [&str; 4] = [
+    "Timeout",
+    "ConnectionRefused",
+    "ResolveFailure",
+    "BlobArchived",
+];
+
+/// Remembers the URIs which failed recently, and how, so that apt asking for
+/// one again straight away, as its own retries do, fails fast rather than
+/// going through the same slow requests and retries to the same failure.
+#[derive(Debug, Default)]
+pub struct RecentFailures {
+    ttl: Option<Duration>,
+    failures: Mutex<HashMap<String, (Instant, Message)>>,
+}
+
+impl RecentFailures {
+    /// Remember failures for the given time; with none, nothing is
+    /// remembered.
+    pub fn new(ttl: Option<Duration>) -> Self {
+        RecentFailures {
+            ttl,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember a URI Failure, if it's one which would be repeated.
+    pub fn record(&self, failure: &Message) {
+        if self.ttl.is_none() || !is_remembered(failure.fail_reason()) {
+            return;
+        }
+        let Ok(uri) = failure.uri() else {
+            return;
+        };
+        let mut failures = self.failures.lock().unwrap();
+        failures.insert(uri.to_string(), (Instant::now(), failure.clone()));
+    }
+
+    /// The failure the URI had recently, if it had one.
+    pub fn recall(&self, uri: &str) -> Option<Message> {
+        let ttl = self.ttl?;
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, (failed, _)| failed.elapsed() < ttl);
+        failures.get(uri).map(|(_, failure)| failure.clone())
+    }
+}
+
+// Whether a failure with the reason is one which would be repeated.
+fn is_remembered(reason: Option<&str>) -> bool {
+    reason.is_some_and(|reason| {
+        reason.starts_with("HttpError") || REMEMBERED_REASONS.contains(&reason)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(uri: &str, reason: &str) -> Message {
+        Message::build_uri_failure(uri, "Error").with_header("FailReason", reason)
+    }
+
+    #[test]
+    fn test_recall() {
+        let failures = RecentFailures::new(Some(Duration::from_secs(60)));
+        failures.record(&failure("blob://a/c/missing", "HttpError404"));
+        failures.record(&failure("blob://a/c/busy", "HttpError503"));
+        failures.record(&failure("blob://a/c/corrupt", "HashSumMismatch"));
+        failures.record(&Message::build_uri_failure("blob://a/c/other", "Error"));
+
+        assert_eq!(
+            failures.recall("blob://a/c/missing"),
+            Some(failure("blob://a/c/missing", "HttpError404"))
+        );
+        assert!(failures.recall("blob://a/c/busy").is_some());
+        assert_eq!(failures.recall("blob://a/c/corrupt"), None);
+        assert_eq!(failures.recall("blob://a/c/other"), None);
+        assert_eq!(failures.recall("blob://a/c/Release"), None);
+    }
+
+    #[test]
+    fn test_expired() {
+        let failures = RecentFailures::new(Some(Duration::ZERO));
+        failures.record(&failure("blob://a/c/missing", "HttpError404"));
+        assert_eq!(failures.recall("blob://a/c/missing"), None);
+        assert!(failures.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let failures = RecentFailures::new(None);
+        failures.record(&failure("blob://a/c/missing", "HttpError404"));
+        assert_eq!(failures.recall("blob://a/c/missing"), None);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
+
+use crate::atomic;
+use crate::azure::BlobInfo;
+use crate::config::SuspiciousLastModified;
+use crate::credentials::redact_sas;
+
+// Blobs can't have been modified before this, 2000-01-01, long before the
+// storage service existed; earlier times are placeholders such as the epoch.
+const EARLIEST_PLAUSIBLE: i64 = 946_684_800;
+
+// How far in the future a Last-Modified time may be, allowing for clocks
+// disagreeing, before it's implausible.
+const CLOCK_SKEW: Duration = Duration::DAY;
+
+/// Whether a blob's Last-Modified time is implausible, and so can't be used
+/// to tell whether it's changed.
+pub fn is_suspicious(last_modified: OffsetDateTime, now: OffsetDateTime) -> bool {
+    last_modified < earliest_plausible() || last_modified > now + CLOCK_SKEW
+}
+
+/// The Last-Modified time to report to apt for the blob, if any.
+pub fn reported_last_modified(
+    policy: SuspiciousLastModified,
+    info: &BlobInfo,
+    now: OffsetDateTime,
+) -> Option<OffsetDateTime> {
+    if !is_suspicious(info.last_modified, now) {
+        return Some(info.last_modified);
+    }
+    match policy {
+        SuspiciousLastModified::Keep => Some(info.last_modified),
+        // The blob was modified no earlier than it was created, which the
+        // service records itself, and no later than now.
+        SuspiciousLastModified::Clamp => {
+            let earliest = match is_suspicious(info.created, now) {
+                true => earliest_plausible(),
+                false => info.created,
+            };
+            Some(info.last_modified.clamp(earliest, now))
+        }
+        SuspiciousLastModified::Omit => None,
+    }
+}
+
+fn earliest_plausible() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(EARLIEST_PLAUSIBLE).unwrap()
+}
+
+/// The ETag of the blob each URI was last downloaded from, kept in a file so
+/// that a later run can tell whether a blob has changed since apt's copy of
+/// it was downloaded, when its Last-Modified time can't be relied on.
+#[derive(Debug, Default)]
+pub struct ETagStore {
+    path: Option<PathBuf>,
+    // Held while the file is updated, so concurrent downloads don't lose
+    // each other's ETags.
+    lock: Arc<Mutex<()>>,
+}
+
+impl ETagStore {
+    /// Create a store keeping its ETags in the given file; with no file,
+    /// nothing is kept.
+    pub fn new(path: Option<&str>) -> Self {
+        ETagStore {
+            path: path.map(PathBuf::from),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The ETag of the blob the URI was last downloaded from, if it's known.
+    pub fn get(&self, uri: &str) -> Option<String> {
+        let path = self.path.as_ref()?;
+        let _lock = self.lock.lock().unwrap();
+        match read_etags(path) {
+            Ok(mut etags) => etags.remove(&redact_sas(uri)),
+            Err(err) => {
+                warn!("Failed to read ETags from {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Record the ETag of the blob the URI was downloaded from. Failing to
+    /// update the file is logged, but doesn't fail the download.
+    pub async fn record(&self, uri: &str, etag: &str) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let (file, uri, etag) = (path.clone(), redact_sas(uri), etag.to_string());
+        let updated = atomic::update(&self.lock, move || Self::update(&file, &uri, &etag)).await;
+        if let Err(err) = updated {
+            warn!("Failed to update ETags in {}: {}", path.display(), err);
+        }
+    }
+
+    fn update(path: &Path, uri: &str, etag: &str) -> Result<(), atomic::UpdateError> {
+        let mut etags = read_etags(path)?;
+        etags.insert(uri.to_string(), etag.to_string());
+
+        atomic::write(path, json!({ "etags": etags }).to_string())?;
+        Ok(())
+    }
+}
+
+fn read_etags(path: &Path) -> Result<BTreeMap<String, String>, atomic::UpdateError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let value: serde_json::Value = serde_json::from_str(&contents)?;
+    Ok(value["etags"]
+        .as_object()
+        .ok_or("No ETags")?
+        .iter()
+        .filter_map(|(uri, etag)| Some((uri.clone(), etag.as_str()?.to_string())))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    fn info(last_modified: OffsetDateTime, created: OffsetDateTime) -> BlobInfo {
+        BlobInfo {
+            size: 0,
+            last_modified,
+            created,
+            etag: "\"0x8DC7FD2A1B2C3D4\"".to_string(),
+            content_md5: None,
+            content_encoding: None,
+            archived: false,
+        }
+    }
+
+    #[test]
+    fn test_is_suspicious() {
+        let now = time(1716984000);
+        assert!(!is_suspicious(now, now));
+        assert!(!is_suspicious(now - Duration::days(365), now));
+        assert!(!is_suspicious(now + Duration::hours(1), now));
+        assert!(is_suspicious(time(0), now));
+        assert!(is_suspicious(now + Duration::days(2), now));
+    }
+
+    #[test]
+    fn test_reported_last_modified() {
+        let now = time(1716984000);
+        let created = now - Duration::days(30);
+        let report = |policy, last_modified, created| {
+            reported_last_modified(policy, &info(last_modified, created), now)
+        };
+        for policy in [
+            SuspiciousLastModified::Keep,
+            SuspiciousLastModified::Clamp,
+            SuspiciousLastModified::Omit,
+        ] {
+            assert_eq!(report(policy, now, created), Some(now));
+        }
+
+        assert_eq!(
+            report(SuspiciousLastModified::Keep, time(0), created),
+            Some(time(0))
+        );
+        assert_eq!(
+            report(SuspiciousLastModified::Clamp, time(0), created),
+            Some(created)
+        );
+        assert_eq!(
+            report(SuspiciousLastModified::Clamp, time(0), time(0)),
+            Some(earliest_plausible())
+        );
+        assert_eq!(
+            report(
+                SuspiciousLastModified::Clamp,
+                now + Duration::days(365),
+                created
+            ),
+            Some(now)
+        );
+        assert_eq!(report(SuspiciousLastModified::Omit, time(0), created), None);
+    }
+
+    #[tokio::test]
+    async fn test_etag_store() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("etags").join("etags.json");
+
+        // Without a file nothing is kept.
+        let store = ETagStore::new(None);
+        store
+            .record("blob://a/c/dists/stable/Release", "\"1\"")
+            .await;
+        assert_eq!(store.get("blob://a/c/dists/stable/Release"), None);
+
+        let store = ETagStore::new(path.to_str());
+        assert_eq!(store.get("blob://a/c/dists/stable/Release"), None);
+        store
+            .record("blob://a/c/dists/stable/Release", "\"1\"")
+            .await;
+        store
+            .record("blob://a/c/dists/stable/InRelease", "\"2\"")
+            .await;
+        store
+            .record("blob://a/c/dists/stable/Release", "\"3\"")
+            .await;
+        assert_eq!(
+            store.get("blob://a/c/dists/stable/Release").as_deref(),
+            Some("\"3\"")
+        );
+        assert_eq!(
+            store.get("blob://a/c/dists/stable/InRelease").as_deref(),
+            Some("\"2\"")
+        );
+
+        // SAS tokens aren't kept.
+        store
+            .record("blob://a/c?sv=1&sig=secret/dists/stable/Release", "\"4\"")
+            .await;
+        assert!(!std::fs::read_to_string(&path)?.contains("secret"));
+        assert_eq!(
+            store
+                .get("blob://a/c?sv=1&sig=other/dists/stable/Release")
+                .as_deref(),
+            Some("\"4\"")
+        );
+        Ok(())
+    }
+}
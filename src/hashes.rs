@@ -0,0 +1,170 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::AsyncReadExt;
+
+/// Computes the hashes apt verifies downloads with, as data is streamed
+/// through it, and the MD5 the storage service may have for the blob.
+#[derive(Clone, Default)]
+pub struct Hasher {
+    size: u64,
+    md5: Md5,
+    sha256: Sha256,
+    sha512: Sha512,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.md5.update(data);
+        self.sha256.update(data);
+        self.sha512.update(data);
+    }
+
+    /// The number of bytes hashed so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn finish(self) -> Hashes {
+        Hashes {
+            size: self.size,
+            md5: format!("{:x}", self.md5.finalize()),
+            sha256: format!("{:x}", self.sha256.finalize()),
+            sha512: format!("{:x}", self.sha512.finalize()),
+        }
+    }
+}
+
+/// The size and hex-encoded hashes of a downloaded file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hashes {
+    pub size: u64,
+    /// Only compared with the blob's Content-MD5; it's too weak to give apt.
+    pub md5: String,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl Hashes {
+    /// The hashes as apt `<Type>-Hash` message headers.
+    pub fn headers(&self) -> Vec<(&'static str, &str)> {
+        vec![("SHA256-Hash", &self.sha256), ("SHA512-Hash", &self.sha512)]
+    }
+
+    /// Check the hashes against those apt expects, given as `(type, value)`
+    /// pairs. Hash types which aren't computed are skipped. Returns a
+    /// description of the first mismatch found.
+    pub fn verify<'a>(
+        &self,
+        expected: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), String> {
+        let size = self.size.to_string();
+        for (hash_type, value) in expected {
+            let actual = match hash_type.to_ascii_lowercase().as_str() {
+                "sha256" => &self.sha256,
+                "sha512" => &self.sha512,
+                "checksum-filesize" => &size,
+                _ => continue,
+            };
+            if !actual.eq_ignore_ascii_case(value) {
+                return Err(format!(
+                    "{} mismatch: expected {}, got {}",
+                    hash_type, value, actual
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash a file as it is on disk.
+pub async fn hash_file(path: &str) -> std::io::Result<Hashes> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Convert a Content-MD5 digest to the hex encoding apt uses.
+pub fn md5_to_hex(md5: &[u8]) -> String {
+    md5.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        let mut hasher = Hasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let hashes = hasher.finish();
+        assert_eq!(hashes.size, 11);
+        assert_eq!(hashes.md5, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(
+            hashes.sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(
+            hashes.sha512,
+            "309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f\
+             989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f"
+        );
+        assert_eq!(hashes.headers()[0], ("SHA256-Hash", hashes.sha256.as_str()));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut hasher = Hasher::new();
+        hasher.update(b"hello world");
+        let hashes = hasher.finish();
+
+        assert_eq!(hashes.verify(vec![]), Ok(()));
+        assert_eq!(
+            hashes.verify(vec![
+                (
+                    "SHA256",
+                    "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9"
+                ),
+                ("Checksum-FileSize", "11"),
+                ("MD5Sum", "not checked"),
+            ]),
+            Ok(())
+        );
+        assert!(hashes.verify(vec![("SHA512", "0000")]).is_err());
+        assert!(hashes.verify(vec![("Checksum-FileSize", "12")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hash_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("hello.deb");
+        std::fs::write(&path, "hello world")?;
+        let mut hasher = Hasher::new();
+        hasher.update(b"hello world");
+        assert_eq!(hash_file(path.to_str().unwrap()).await?, hasher.finish());
+        assert!(hash_file(dir.path().join("missing").to_str().unwrap())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_md5_to_hex() {
+        assert_eq!(md5_to_hex(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::process::Stdio;
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio::process::Command;
+
+use crate::config::Config;
+use crate::hashes::Hashes;
+
+/// Run the post-download hook, if one is configured, on a downloaded file.
+/// The hook is passed the URI, the filename and the file's SHA256 and SHA512
+/// hashes as arguments. Returns why the hook failed, if it did.
+pub async fn post_download(
+    config: &Config,
+    uri: &str,
+    filename: &str,
+    hashes: &Hashes,
+) -> Result<(), String> {
+    match &config.post_download_hook {
+        Some(hook) => {
+            let args = [uri, filename, &hashes.sha256, &hashes.sha512];
+            run(hook, &args, config.hook_timeout).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Run the quiesce check, if one is configured, which succeeds when the
+/// method should quiesce, such as when the connection is metered. Returns
+/// why it didn't succeed, if it didn't.
+pub async fn quiesce_check(config: &Config) -> Result<(), String> {
+    match &config.quiesce_check {
+        Some(hook) => run(hook, &[], config.hook_timeout).await,
+        None => Err("no quiesce check is configured".to_string()),
+    }
+}
+
+// Run a hook, failing if it can't be run, exits unsuccessfully or outlives
+// the timeout. Its output is logged rather than passed through, as stdout is
+// reserved for messages to apt.
+async fn run(hook: &str, args: &[&str], timeout: Duration) -> Result<(), String> {
+    info!("Running hook {}", hook);
+    let child = Command::new(hook)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("Failed to run {}: {}", hook, err))?;
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| format!("{} timed out after {}s", hook, timeout.as_secs()))?
+        .map_err(|err| format!("Failed to run {}: {}", hook, err))?;
+    debug!(
+        "Hook {} output: {}{}",
+        hook,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", hook, output.status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Write an executable shell script into the directory.
+    fn script(dir: &tempfile::TempDir, body: &str) -> String {
+        let path = dir.path().join("hook");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // Arguments are passed through.
+        let hook = script(&dir, "[ \"$1\" = uri ] && [ \"$2\" = file ]");
+        assert_eq!(run(&hook, &["uri", "file"], timeout).await, Ok(()));
+        assert!(run(&hook, &["other", "file"], timeout).await.is_err());
+
+        assert!(run("/nonexistent/hook", &[], timeout).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = script(&dir, "sleep 10");
+        let err = run(&hook, &[], Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_no_hook() {
+        let hashes = Hashes {
+            size: 0,
+            md5: String::new(),
+            sha256: String::new(),
+            sha512: String::new(),
+        };
+        assert_eq!(
+            post_download(&Config::default(), "uri", "file", &hashes).await,
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use url::{Host, Url};
+
+/// Put the URL's hostname in its canonical ASCII form: lowercase, with any
+/// internationalized labels punycoded. URLs with special schemes such as
+/// `https` already have it, but those with others, such as `blob`, keep
+/// their hostname as given, which depending on apt's version may be raw,
+/// percent-encoded or punycoded. Hostnames are compared in this form to
+/// match configuration and endpoints against them.
+pub fn normalize(url: &mut Url) -> Result<(), String> {
+    let Some(Host::Domain(domain)) = url.host() else {
+        return Ok(());
+    };
+    let ascii = to_ascii(domain)?;
+    if ascii != domain {
+        url.set_host(Some(&ascii))
+            .map_err(|err| format!("Invalid hostname {:?}: {}", ascii, err))?;
+    }
+    Ok(())
+}
+
+// The ASCII form of a hostname, which may be percent-encoded. Hostnames in
+// https URLs are parsed as WHATWG specifies, decoding and applying IDNA.
+fn to_ascii(domain: &str) -> Result<String, String> {
+    let url = Url::parse(&format!("https://{}/", domain))
+        .map_err(|err| format!("Invalid hostname {:?}: {}", domain, err))?;
+    Ok(url.host_str().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> Result<String, String> {
+        let mut url = Url::parse(url).map_err(|err| err.to_string())?;
+        normalize(&mut url)?;
+        Ok(url.to_string())
+    }
+
+    #[test]
+    fn test_normalize() {
+        let punycoded = "blob://xn--bcher-kva.example/repo/dists/stable/Release";
+        assert_eq!(normalized(punycoded).as_deref(), Ok(punycoded));
+        assert_eq!(
+            normalized("blob://bücher.example/repo/dists/stable/Release").as_deref(),
+            Ok(punycoded)
+        );
+        assert_eq!(
+            normalized("blob://b%C3%BCcher.example/repo/dists/stable/Release").as_deref(),
+            Ok(punycoded)
+        );
+        assert_eq!(
+            normalized("blob://BÜCHER.Example/repo/dists/stable/Release").as_deref(),
+            Ok(punycoded)
+        );
+        assert_eq!(
+            normalized("https://bücher.example/repo/dists/stable/Release").as_deref(),
+            Ok("https://xn--bcher-kva.example/repo/dists/stable/Release")
+        );
+
+        // The rest of the URL, including any SAS token, is left alone.
+        assert_eq!(
+            normalized("blob://MyAccount.blob.core.windows.net:443/repo?sv=1&sig=A%2B/pool/a.deb")
+                .as_deref(),
+            Ok("blob://myaccount.blob.core.windows.net:443/repo?sv=1&sig=A%2B/pool/a.deb")
+        );
+        assert_eq!(
+            normalized("blob://127.0.0.1:10000/devstoreaccount1/repo").as_deref(),
+            Ok("blob://127.0.0.1:10000/devstoreaccount1/repo")
+        );
+        assert!(normalized("blob://xn--a.example/repo").is_err());
+    }
+}
@@ -0,0 +1,672 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use azure_core::auth::{AccessToken, Secret, TokenCredential};
+use azure_core::error::{Error, ErrorKind};
+use azure_core::{HttpClient, Method, Request, Url};
+use azure_identity::{
+    federated_credentials_flow, AzureCliCredential, EnvironmentCredential, TokenCredentialOptions,
+};
+use log::{debug, info};
+use time::{Duration, OffsetDateTime};
+
+use crate::config::TokenSource;
+
+// The Azure Instance Metadata Service endpoint for managed identity tokens.
+const IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const IMDS_API_VERSION: &str = "2018-02-01";
+
+/// The scope of tokens for the storage service.
+pub const STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+
+// Cached tokens are refreshed once they're this close to expiring.
+const EXPIRY_MARGIN: Duration = Duration::minutes(5);
+
+/// Build a credential which gets tokens from the given sources, trying each
+/// in turn. Sources which can't be used, such as environment credentials
+/// without the environment variables set, are left out. A managed identity
+/// is the user-assigned one with the client ID if one is given, and the
+/// system-assigned one otherwise. Tokens are requested with the HTTP client,
+/// so that they're got through the same proxy, and trusting the same
+/// certificates, as requests to the storage service.
+pub fn token_credential(
+    sources: &[TokenSource],
+    client_id: Option<&str>,
+    authority_host: &str,
+    http_client: Arc<dyn HttpClient>,
+) -> Arc<dyn TokenCredential> {
+    let mut options = TokenCredentialOptions::from(http_client);
+    options.set_authority_host(authority_host.to_string());
+
+    let mut credentials: Vec<(TokenSource, Box<dyn TokenCredential>)> = vec![];
+    let mut unavailable = vec![];
+    for source in sources {
+        let credential: Box<dyn TokenCredential> = match source {
+            TokenSource::WorkloadIdentity => match WorkloadIdentityCredential::new(
+                options.http_client(),
+                options.authority_host(),
+                std::env::var("AZURE_TENANT_ID").ok(),
+                std::env::var("AZURE_CLIENT_ID").ok(),
+                std::env::var("AZURE_FEDERATED_TOKEN_FILE").ok(),
+            ) {
+                Some(credential) => Box::new(credential),
+                None => {
+                    debug!("Not using workload identity, as it isn't set up");
+                    unavailable.push((*source, "not set up".to_string()));
+                    continue;
+                }
+            },
+            TokenSource::Environment => match EnvironmentCredential::create(options.clone()) {
+                Ok(credential) => Box::new(credential),
+                Err(err) => {
+                    debug!("Not using environment credentials: {}", err);
+                    unavailable.push((*source, err.to_string()));
+                    continue;
+                }
+            },
+            TokenSource::ManagedIdentity => Box::new(ManagedIdentityCredential::new(
+                options.http_client(),
+                Url::parse(IMDS_ENDPOINT).unwrap(),
+                client_id,
+            )),
+            TokenSource::AzureCli => Box::new(AzureCliCredential::new()),
+        };
+        credentials.push((*source, credential));
+    }
+    Arc::new(ChainedCredential {
+        credentials,
+        unavailable,
+    })
+}
+
+/// Gets tokens from the first of several credentials to provide one. Each
+/// attempt is logged with how long it took, and if none gives a token, the
+/// error says how each failed, so slow or failing sources can be told apart.
+#[derive(Debug)]
+pub struct ChainedCredential {
+    credentials: Vec<(TokenSource, Box<dyn TokenCredential>)>,
+    // Sources which were left out, and why.
+    unavailable: Vec<(TokenSource, String)>,
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ChainedCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        let mut errors = vec![];
+        for (source, credential) in &self.credentials {
+            let started = Instant::now();
+            let result = credential.get_token(scopes).await;
+            let elapsed = started.elapsed().as_millis();
+            match result {
+                Ok(token) => {
+                    debug!("Got token from {} in {}ms", source.as_str(), elapsed);
+                    if !errors.is_empty() {
+                        info!(
+                            "Got token from {} after failing with: {}",
+                            source.as_str(),
+                            errors.join("; ")
+                        );
+                    }
+                    return Ok(token);
+                }
+                Err(err) => {
+                    debug!(
+                        "Failed to get token from {} in {}ms: {}",
+                        source.as_str(),
+                        elapsed,
+                        err
+                    );
+                    errors.push(format!("{}: {} ({}ms)", source.as_str(), err, elapsed));
+                }
+            }
+        }
+        errors.extend(
+            self.unavailable
+                .iter()
+                .map(|(source, reason)| format!("{}: skipped, {}", source.as_str(), reason)),
+        );
+        if errors.is_empty() {
+            errors.push("no token sources are configured".to_string());
+        }
+        Err(Error::message(
+            ErrorKind::Credential,
+            format!("Failed to get a token: {}", errors.join("; ")),
+        ))
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        for (_, credential) in &self.credentials {
+            credential.clear_cache().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets tokens for a Kubernetes workload identity, exchanging the service
+/// account token projected into the pod for a Microsoft Entra ID token. The
+/// projected token is rotated, so it's read afresh for each new token.
+#[derive(Debug)]
+pub struct WorkloadIdentityCredential {
+    http_client: Arc<dyn HttpClient>,
+    authority_host: Url,
+    tenant_id: String,
+    client_id: String,
+    token_file: String,
+}
+
+impl WorkloadIdentityCredential {
+    // Create the credential if the tenant, client ID and token file are all
+    // given, as they are by the workload identity webhook.
+    fn new(
+        http_client: Arc<dyn HttpClient>,
+        authority_host: azure_core::Result<Url>,
+        tenant_id: Option<String>,
+        client_id: Option<String>,
+        token_file: Option<String>,
+    ) -> Option<Self> {
+        Some(WorkloadIdentityCredential {
+            http_client,
+            authority_host: authority_host.ok()?,
+            tenant_id: tenant_id?,
+            client_id: client_id?,
+            token_file: token_file?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for WorkloadIdentityCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        let assertion = std::fs::read_to_string(&self.token_file).map_err(|err| {
+            Error::message(
+                ErrorKind::Credential,
+                format!("Failed to read {}: {}", self.token_file, err),
+            )
+        })?;
+        let response = federated_credentials_flow::perform(
+            self.http_client.clone(),
+            &self.client_id,
+            assertion.trim(),
+            scopes,
+            &self.tenant_id,
+            &self.authority_host,
+        )
+        .await?;
+        let expires_on = OffsetDateTime::now_utc() + Duration::seconds(response.expires_in as i64);
+        Ok(AccessToken::new(
+            response.access_token().clone(),
+            expires_on,
+        ))
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Gets tokens for the VM's managed identity from the Instance Metadata
+/// Service, optionally for a particular user-assigned identity.
+#[derive(Debug)]
+pub struct ManagedIdentityCredential {
+    http_client: Arc<dyn HttpClient>,
+    endpoint: Url,
+    client_id: Option<String>,
+}
+
+impl ManagedIdentityCredential {
+    fn new(http_client: Arc<dyn HttpClient>, endpoint: Url, client_id: Option<&str>) -> Self {
+        ManagedIdentityCredential {
+            http_client,
+            endpoint,
+            client_id: client_id.map(str::to_string),
+        }
+    }
+
+    async fn fetch_token(&self, resource: &str) -> azure_core::Result<AccessToken> {
+        let mut url = self.endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("api-version", IMDS_API_VERSION)
+            .append_pair("resource", resource);
+        if let Some(client_id) = &self.client_id {
+            url.query_pairs_mut().append_pair("client_id", client_id);
+        }
+        let mut request = Request::new(url, Method::Get);
+        request.insert_header("metadata", "true");
+
+        let response = self.http_client.execute_request(&request).await?;
+        let status = response.status();
+        let body = response.into_body().collect_string().await?;
+        if !status.is_success() {
+            return Err(Error::message(
+                ErrorKind::Credential,
+                format!("Managed identity endpoint returned {}: {}", status, body),
+            ));
+        }
+        parse_token_response(&body)
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ManagedIdentityCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        self.fetch_token(scope_to_resource(scopes)?).await
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Gets tokens with a fixed scope from another credential, whatever scope
+/// they're asked for, for clouds whose storage service takes tokens with
+/// another scope than the storage client asks for.
+#[derive(Debug)]
+pub struct ScopedCredential {
+    credential: Arc<dyn TokenCredential>,
+    scope: String,
+}
+
+impl ScopedCredential {
+    pub fn new(credential: Arc<dyn TokenCredential>, scope: &str) -> Self {
+        ScopedCredential {
+            credential,
+            scope: scope.to_string(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ScopedCredential {
+    async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        self.credential.get_token(&[&self.scope]).await
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        self.credential.clear_cache().await
+    }
+}
+
+/// Keeps the tokens got from another credential, so that a session makes one
+/// request for a token rather than one for each blob, and gets a new token
+/// shortly before the old one expires rather than having requests rejected.
+#[derive(Debug)]
+pub struct CachedCredential {
+    credential: Arc<dyn TokenCredential>,
+    // Tokens by the scopes they're for.
+    cache: TokenCache,
+    // Held while getting a token, so that concurrent requests wait for it
+    // rather than each getting their own.
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl CachedCredential {
+    pub fn new(credential: Arc<dyn TokenCredential>) -> Self {
+        CachedCredential {
+            credential,
+            cache: TokenCache::default(),
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for CachedCredential {
+    async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+        let key = scopes.join(" ");
+        if let Some(token) = self.cache.get(&key) {
+            return Ok(token);
+        }
+        let _refreshing = self.refreshing.lock().await;
+        if let Some(token) = self.cache.get(&key) {
+            return Ok(token);
+        }
+        let token = self.credential.get_token(scopes).await?;
+        debug!("Got token expiring {}", token.expires_on);
+        self.cache.insert(key, token.clone());
+        Ok(token)
+    }
+
+    async fn clear_cache(&self) -> azure_core::Result<()> {
+        self.cache.clear();
+        self.credential.clear_cache().await
+    }
+}
+
+// Tokens which have been got, by what they're for, kept until they're close
+// to expiring.
+#[derive(Debug, Default)]
+struct TokenCache(Mutex<HashMap<String, AccessToken>>);
+
+impl TokenCache {
+    fn get(&self, key: &str) -> Option<AccessToken> {
+        let tokens = self.0.lock().unwrap();
+        let token = tokens.get(key)?;
+        (token.expires_on > OffsetDateTime::now_utc() + EXPIRY_MARGIN).then(|| token.clone())
+    }
+
+    fn insert(&self, key: String, token: AccessToken) {
+        self.0.lock().unwrap().insert(key, token);
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Describe the identity a token was issued to, from the object ID, client
+/// ID and tenant in its claims. The token is decoded locally rather than
+/// checked, as this is only to tell users who they were authenticated as.
+pub fn describe_token(token: &AccessToken) -> Option<String> {
+    let payload = token.token.secret().split('.').nth(1)?;
+    let padding = "=".repeat((4 - payload.len() % 4) % 4);
+    let claims = azure_core::base64::decode_url_safe(format!("{}{}", payload, padding)).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&claims).ok()?;
+    let described: Vec<String> = [
+        ("object ID", "oid"),
+        ("client ID", "appid"),
+        ("client ID", "azp"),
+        ("tenant", "tid"),
+    ]
+    .iter()
+    .filter_map(|(name, claim)| Some(format!("{} {}", name, claims[claim].as_str()?)))
+    .collect();
+    (!described.is_empty()).then(|| described.join(", "))
+}
+
+// The resource a token is for, from the single scope requested.
+fn scope_to_resource<'a>(scopes: &[&'a str]) -> azure_core::Result<&'a str> {
+    match scopes {
+        [scope] => Ok(scope.strip_suffix("/.default").unwrap_or(scope)),
+        _ => Err(Error::message(
+            ErrorKind::Credential,
+            "Managed identity tokens are for a single scope",
+        )),
+    }
+}
+
+// Parse a token from the Instance Metadata Service, which gives its expiry
+// as a string of seconds since the epoch.
+fn parse_token_response(body: &str) -> azure_core::Result<AccessToken> {
+    let invalid = |message: &str| {
+        Error::message(
+            ErrorKind::Credential,
+            format!("Invalid managed identity token response: {}", message),
+        )
+    };
+    let value: serde_json::Value =
+        serde_json::from_str(body).map_err(|err| invalid(&err.to_string()))?;
+    let token = value["access_token"]
+        .as_str()
+        .ok_or_else(|| invalid("no access_token"))?;
+    let expires_on = value["expires_on"]
+        .as_str()
+        .and_then(|expires_on| expires_on.parse().ok())
+        .and_then(|expires_on| OffsetDateTime::from_unix_timestamp(expires_on).ok())
+        .ok_or_else(|| invalid("no expires_on"))?;
+    Ok(AccessToken::new(Secret::new(token.to_string()), expires_on))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[derive(Debug)]
+    struct FixedCredential(Option<&'static str>);
+
+    #[async_trait::async_trait]
+    impl TokenCredential for FixedCredential {
+        async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<AccessToken> {
+            match self.0 {
+                Some(token) => Ok(AccessToken::new(
+                    Secret::new(token.to_string()),
+                    OffsetDateTime::now_utc(),
+                )),
+                None => Err(Error::message(ErrorKind::Credential, "unavailable")),
+            }
+        }
+
+        async fn clear_cache(&self) -> azure_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chained_credential() {
+        let chain = ChainedCredential {
+            credentials: vec![
+                (TokenSource::Environment, Box::new(FixedCredential(None))),
+                (
+                    TokenSource::AzureCli,
+                    Box::new(FixedCredential(Some("cli"))),
+                ),
+            ],
+            unavailable: vec![],
+        };
+        let token = chain.get_token(&["scope"]).await.unwrap();
+        assert_eq!(token.token.secret(), "cli");
+
+        // Failures say how long each source took, and why any were skipped.
+        let chain = ChainedCredential {
+            credentials: vec![(TokenSource::Environment, Box::new(FixedCredential(None)))],
+            unavailable: vec![(TokenSource::WorkloadIdentity, "not set up".to_string())],
+        };
+        let err = chain.get_token(&["scope"]).await.unwrap_err().to_string();
+        assert!(err.contains("environment: unavailable ("), "{}", err);
+        assert!(
+            err.contains("ms); workload-identity: skipped, not set up"),
+            "{}",
+            err
+        );
+
+        let chain = ChainedCredential {
+            credentials: vec![],
+            unavailable: vec![],
+        };
+        assert!(chain.get_token(&["scope"]).await.is_err());
+    }
+
+    // Gives tokens which are the scopes they were asked for.
+    #[derive(Debug)]
+    struct EchoScopes;
+
+    #[async_trait::async_trait]
+    impl TokenCredential for EchoScopes {
+        async fn get_token(&self, scopes: &[&str]) -> azure_core::Result<AccessToken> {
+            Ok(AccessToken::new(
+                Secret::new(scopes.join(" ")),
+                OffsetDateTime::now_utc(),
+            ))
+        }
+
+        async fn clear_cache(&self) -> azure_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scoped_credential() {
+        let credential =
+            ScopedCredential::new(Arc::new(EchoScopes), "https://storage.local/.default");
+        let token = credential.get_token(&[STORAGE_SCOPE]).await.unwrap();
+        assert_eq!(token.token.secret(), "https://storage.local/.default");
+    }
+
+    #[test]
+    fn test_token_cache() {
+        let cache = TokenCache::default();
+        let token = |minutes| {
+            AccessToken::new(
+                Secret::new("abc".to_string()),
+                OffsetDateTime::now_utc() + Duration::minutes(minutes),
+            )
+        };
+        cache.insert("fresh".to_string(), token(60));
+        cache.insert("expiring".to_string(), token(1));
+        assert!(cache.get("fresh").is_some());
+        assert!(cache.get("expiring").is_none());
+        assert!(cache.get("missing").is_none());
+        cache.clear();
+        assert!(cache.get("fresh").is_none());
+    }
+
+    // Counts the tokens got from it, which expire after the given minutes.
+    #[derive(Debug)]
+    struct CountingCredential(Mutex<usize>, i64);
+
+    #[async_trait::async_trait]
+    impl TokenCredential for CountingCredential {
+        async fn get_token(&self, _scopes: &[&str]) -> azure_core::Result<AccessToken> {
+            *self.0.lock().unwrap() += 1;
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            Ok(AccessToken::new(
+                Secret::new("abc".to_string()),
+                OffsetDateTime::now_utc() + Duration::minutes(self.1),
+            ))
+        }
+
+        async fn clear_cache(&self) -> azure_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_credential() {
+        let counting = Arc::new(CountingCredential(Mutex::new(0), 60));
+        let credential = CachedCredential::new(counting.clone());
+        let scopes = ["https://storage.azure.com/.default"];
+        let (first, second) =
+            tokio::join!(credential.get_token(&scopes), credential.get_token(&scopes));
+        assert_eq!(first.unwrap().token.secret(), "abc");
+        assert_eq!(second.unwrap().token.secret(), "abc");
+        credential.get_token(&scopes).await.unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 1);
+
+        // Tokens for other scopes are got separately.
+        credential.get_token(&["other"]).await.unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 2);
+
+        credential.clear_cache().await.unwrap();
+        credential.get_token(&scopes).await.unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 3);
+
+        // Tokens about to expire are replaced.
+        let counting = Arc::new(CountingCredential(Mutex::new(0), 1));
+        let credential = CachedCredential::new(counting.clone());
+        credential.get_token(&scopes).await.unwrap();
+        credential.get_token(&scopes).await.unwrap();
+        assert_eq!(*counting.0.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_workload_identity() {
+        let new = |tenant_id: Option<&str>, token_file: Option<&str>| {
+            WorkloadIdentityCredential::new(
+                azure_core::new_http_client(),
+                Ok(Url::parse("https://login.microsoftonline.com").unwrap()),
+                tenant_id.map(str::to_string),
+                Some("client".to_string()),
+                token_file.map(str::to_string),
+            )
+        };
+        assert!(new(None, Some("/token")).is_none());
+        assert!(new(Some("tenant"), None).is_none());
+
+        // The token file is only read when a token is needed.
+        let credential = new(Some("tenant"), Some("/nonexistent/token")).unwrap();
+        let err = credential.get_token(&["scope"]).await.unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/token"));
+    }
+
+    #[test]
+    fn test_describe_token() {
+        let token = |claims: &str| {
+            let payload = azure_core::base64::encode_url_safe(claims);
+            AccessToken::new(
+                Secret::new(format!("e30.{}.sig", payload.trim_end_matches('='))),
+                OffsetDateTime::now_utc(),
+            )
+        };
+        assert_eq!(
+            describe_token(&token(
+                r#"{"oid": "o", "appid": "a", "tid": "t", "aud": "x"}"#
+            ))
+            .as_deref(),
+            Some("object ID o, client ID a, tenant t")
+        );
+        assert_eq!(
+            describe_token(&token(r#"{"oid": "o", "azp": "a"}"#)).as_deref(),
+            Some("object ID o, client ID a")
+        );
+        assert_eq!(describe_token(&token(r#"{"aud": "x"}"#)), None);
+        assert_eq!(describe_token(&token("not json")), None);
+        let opaque = AccessToken::new(Secret::new("opaque".to_string()), OffsetDateTime::now_utc());
+        assert_eq!(describe_token(&opaque), None);
+    }
+
+    #[test]
+    fn test_scope_to_resource() {
+        assert_eq!(
+            scope_to_resource(&["https://storage.azure.com/.default"]).unwrap(),
+            "https://storage.azure.com"
+        );
+        assert!(scope_to_resource(&["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        let token =
+            parse_token_response(r#"{"access_token": "abc", "expires_on": "1716984000"}"#).unwrap();
+        assert_eq!(token.token.secret(), "abc");
+        assert_eq!(token.expires_on.unix_timestamp(), 1716984000);
+
+        assert!(parse_token_response(r#"{"access_token": "abc"}"#).is_err());
+        assert!(parse_token_response("not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_managed_identity() {
+        // Answer a single token request, passing back the request line.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/token", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let expires_on = OffsetDateTime::now_utc().unix_timestamp() + 3600;
+            let body = format!(
+                r#"{{"access_token": "abc", "expires_on": "{}"}}"#,
+                expires_on
+            );
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request_line
+        });
+
+        let credential = ManagedIdentityCredential::new(
+            azure_core::new_http_client(),
+            Url::parse(&endpoint).unwrap(),
+            Some("my-client-id"),
+        );
+        let scopes = ["https://storage.azure.com/.default"];
+        let token = credential.get_token(&scopes).await.unwrap();
+        assert_eq!(token.token.secret(), "abc");
+        let request_line = server.join().unwrap();
+        assert!(request_line.contains("client_id=my-client-id"));
+        assert!(request_line.contains("resource=https%3A%2F%2Fstorage.azure.com"));
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::os::unix::net::UnixDatagram;
+
+use log::{Level, Log, Metadata, Record};
+
+// The socket journald takes entries on, in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// The name the method's entries are logged under.
+const SYSLOG_IDENTIFIER: &str = "apt-transport-blob";
+
+/// Logs each record to systemd-journald as an entry with structured fields:
+/// the message and priority, and the module, file and line it was logged
+/// from, so they can be filtered on with `journalctl`.
+#[derive(Debug)]
+pub struct JournaldAppender {
+    socket: UnixDatagram,
+}
+
+impl JournaldAppender {
+    pub fn new() -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(JournaldAppender { socket })
+    }
+}
+
+impl Log for JournaldAppender {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // There's nowhere to report an entry journald wouldn't take.
+        let _ = self.socket.send(&encode(record));
+    }
+
+    fn flush(&self) {}
+}
+
+// Encode a record as a journal entry in journald's native protocol.
+fn encode(record: &Record) -> Vec<u8> {
+    let mut entry = vec![];
+    add_field(&mut entry, "MESSAGE", &record.args().to_string());
+    add_field(&mut entry, "PRIORITY", priority(record.level()));
+    add_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    add_field(&mut entry, "TARGET", record.target());
+    if let Some(module) = record.module_path() {
+        add_field(&mut entry, "CODE_MODULE", module);
+    }
+    if let Some(file) = record.file() {
+        add_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        add_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+// Add a field to an entry. Values with newlines are given with their length
+// rather than ending at the newline.
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+// The syslog priority of a log level.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let entry = encode(
+            &Record::builder()
+                .args(format_args!("Downloaded blob"))
+                .level(Level::Warn)
+                .target("blob")
+                .module_path(Some("blob::processor"))
+                .file(Some("src/processor.rs"))
+                .line(Some(42))
+                .build(),
+        );
+        assert_eq!(
+            String::from_utf8(entry).unwrap(),
+            "MESSAGE=Downloaded blob\n\
+             PRIORITY=4\n\
+             SYSLOG_IDENTIFIER=apt-transport-blob\n\
+             TARGET=blob\n\
+             CODE_MODULE=blob::processor\n\
+             CODE_FILE=src/processor.rs\n\
+             CODE_LINE=42\n"
+        );
+    }
+
+    #[test]
+    fn test_add_field() {
+        let mut entry = vec![];
+        add_field(&mut entry, "MESSAGE", "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+}
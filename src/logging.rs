@@ -0,0 +1,335 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::{OnceLock, RwLock};
+
+use log::{warn, LevelFilter, Log, Metadata, Record};
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::delete::DeleteRoller;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::roll::Roll;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{self, Encode};
+use log4rs::filter::threshold::ThresholdFilter;
+use log4rs::filter::{Filter, Response};
+use serde_json::json;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::config::{self, LogFormat, LogTarget};
+use crate::credentials::{redact_sas, redact_secrets};
+
+// The logger, kept so the configuration from apt can change where it logs.
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+tokio::task_local! {
+    // The acquisition the task is working on, for JSON records to say.
+    static CONTEXT: RefCell<Context>;
+}
+
+#[derive(Clone, Debug, Default)]
+struct Context {
+    // A UUID unique to the acquisition, sent with its requests to the
+    // storage service as their client request ID.
+    request_id: Option<String>,
+    uri: Option<String>,
+    account: Option<String>,
+}
+
+// Redacts secrets from each record before handing it to log4rs, whose
+// configuration can be replaced.
+struct Logger {
+    inner: RwLock<log4rs::Logger>,
+    stderr: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    // Secrets can end up in records however carefully messages are written,
+    // from buffers, URLs and errors formatted with them, so they're
+    // redacted from every record before it's written anywhere.
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = redact_secrets(&record.args().to_string());
+        self.inner.read().unwrap().log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}", message))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        Log::flush(&*self.inner.read().unwrap())
+    }
+}
+
+#[derive(Debug)]
+pub struct AzureTransportFilter {}
+impl Filter for AzureTransportFilter {
+    fn filter(&self, record: &Record) -> Response {
+        match record.module_path() {
+            Some(module) => {
+                if module.starts_with("azure_core::policies::transport") {
+                    Response::Reject
+                } else {
+                    Response::Neutral
+                }
+            }
+            None => Response::Neutral,
+        }
+    }
+}
+
+/// Writes each record as a JSON object on a line of its own, with the
+/// acquisition it was logged during, so records can be correlated without
+/// parsing messages.
+#[derive(Debug)]
+pub struct JsonEncoder {}
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        let context = CONTEXT
+            .try_with(|context| context.borrow().clone())
+            .unwrap_or_default();
+        serde_json::to_writer(&mut *w, &to_json(record, &context))?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+fn to_json(record: &Record, context: &Context) -> serde_json::Value {
+    json!({
+        "timestamp": azure_core::date::to_rfc3339(&OffsetDateTime::now_utc()),
+        "level": record.level().as_str(),
+        "module": record.module_path(),
+        "uri": context.uri,
+        "account": context.account,
+        "request_id": context.request_id,
+        "message": record.args().to_string(),
+    })
+}
+
+/// Run an acquisition of the URI, so each record logged for it in JSON says
+/// which acquisition and URI it's for. Tasks it spawns aren't included.
+pub async fn with_acquisition<F: Future>(uri: Option<String>, future: F) -> F::Output {
+    let context = Context {
+        request_id: Some(Uuid::new_v4().to_string()),
+        uri: uri.as_deref().map(redact_sas),
+        account: None,
+    };
+    CONTEXT.scope(RefCell::new(context), future).await
+}
+
+/// The ID of the current acquisition, if there is one.
+pub fn request_id() -> Option<String> {
+    CONTEXT
+        .try_with(|context| context.borrow().request_id.clone())
+        .ok()
+        .flatten()
+}
+
+/// Note the storage account the current acquisition is for, once it's known.
+pub fn set_account(account: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().account = Some(account.to_string()));
+}
+
+// LCOV_EXCL_START
+
+/// Start logging as configured, or to stderr when asked so that tests can
+/// run without touching the system log and without the log mixing with the
+/// messages on stdout.
+pub fn init(config: &config::Config, stderr: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let inner = log4rs::Logger::new(build(config, stderr)?);
+    log::set_max_level(inner.max_log_level());
+    let logger = LOGGER.get_or_init(|| Logger {
+        inner: RwLock::new(inner),
+        stderr,
+    });
+    log::set_logger(logger)?;
+    Ok(())
+}
+
+/// Log where, and how, the configuration says from now on. Logging to
+/// stderr isn't changed. This resets the maximum log level, so it must be
+/// set afterwards.
+pub fn configure(config: &config::Config) {
+    let Some(logger) = LOGGER.get().filter(|logger| !logger.stderr) else {
+        return;
+    };
+    match build(config, logger.stderr) {
+        Ok(log_config) => *logger.inner.write().unwrap() = log4rs::Logger::new(log_config),
+        Err(err) => warn!("Failed to log to {}: {}", config.log_target.as_str(), err),
+    }
+}
+
+// Build the logger's configuration, with an appender for each place logged
+// to. apt runs methods as the unprivileged `_apt` user, which may not be
+// allowed to write the log file; warnings and errors go to stderr instead
+// then, which apt shows.
+fn build(config: &config::Config, stderr: bool) -> Result<Config, Box<dyn std::error::Error>> {
+    let target = config.log_target;
+    let encoder = || -> Box<dyn Encode> {
+        match config.log_format {
+            LogFormat::Text => Box::new(PatternEncoder::new("{d} [{l}] <{M}:{L}> {m}{n}")),
+            LogFormat::Json => Box::new(JsonEncoder {}),
+        }
+    };
+    let console = || {
+        ConsoleAppender::builder()
+            .encoder(encoder())
+            .target(Target::Stderr)
+            .build()
+    };
+    // Each appender, with the least severe level it logs if it doesn't log
+    // everything.
+    let mut appenders: Vec<(&str, Box<dyn Append>, Option<LevelFilter>)> = vec![];
+    if stderr {
+        appenders.push(("stderr", Box::new(console()), None));
+    } else {
+        if matches!(target, LogTarget::File | LogTarget::Both) {
+            match file_appender(config, encoder()) {
+                Ok(appender) => appenders.push(("file", appender, None)),
+                Err(_) => appenders.push(("stderr", Box::new(console()), Some(LevelFilter::Warn))),
+            }
+        }
+        if matches!(target, LogTarget::Journald | LogTarget::Both) {
+            appenders.push(("journald", journald_appender()?, None));
+        }
+        if target == LogTarget::Syslog {
+            appenders.push(("syslog", syslog_appender()?, None));
+        }
+    }
+
+    let mut config = Config::builder();
+    let mut root = Root::builder();
+    for (name, appender, threshold) in appenders {
+        let mut builder = Appender::builder()
+            // Ensure secure logs aren't logged out
+            .filter(Box::new(AzureTransportFilter {}));
+        if let Some(threshold) = threshold {
+            builder = builder.filter(Box::new(ThresholdFilter::new(threshold)));
+        }
+        config = config.appender(builder.build(name, appender));
+        root = root.appender(name);
+    }
+    Ok(config.build(root.build(LevelFilter::Debug))?)
+}
+
+// The appender for the log file, which rolls it over to `<log file>.1` and
+// so on once it's grown too large, if it's limited.
+fn file_appender(
+    config: &config::Config,
+    encoder: Box<dyn Encode>,
+) -> Result<Box<dyn Append>, Box<dyn std::error::Error>> {
+    if config.log_max_size == 0 {
+        let appender = FileAppender::builder()
+            .encoder(encoder)
+            .build(&config.log_file)?;
+        return Ok(Box::new(appender));
+    }
+    let roller: Box<dyn Roll> = match config.log_max_files {
+        0 => Box::new(DeleteRoller::new()),
+        count => Box::new(
+            FixedWindowRoller::builder()
+                .base(1)
+                .build(&format!("{}.{{}}", config.log_file), count)?,
+        ),
+    };
+    let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(config.log_max_size)), roller);
+    let appender = RollingFileAppender::builder()
+        .encoder(encoder)
+        .build(&config.log_file, Box::new(policy))?;
+    Ok(Box::new(appender))
+}
+
+#[cfg(feature = "journald")]
+fn journald_appender() -> Result<Box<dyn Append>, Box<dyn std::error::Error>> {
+    Ok(Box::new(crate::journald::JournaldAppender::new()?))
+}
+
+#[cfg(not(feature = "journald"))]
+fn journald_appender() -> Result<Box<dyn Append>, Box<dyn std::error::Error>> {
+    Err("the method wasn't built with the journald feature".into())
+}
+
+#[cfg(feature = "syslog")]
+fn syslog_appender() -> Result<Box<dyn Append>, Box<dyn std::error::Error>> {
+    Ok(Box::new(crate::syslog::SyslogAppender::new()?))
+}
+
+#[cfg(not(feature = "syslog"))]
+fn syslog_appender() -> Result<Box<dyn Append>, Box<dyn std::error::Error>> {
+    Err("the method wasn't built with the syslog feature".into())
+}
+
+// LCOV_EXCL_STOP
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_appender_rolls() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let log_file = dir.path().join("blob.log");
+        let mut config = config::Config::default();
+        config.log_file = log_file.to_str().unwrap().to_string();
+        config.log_max_size = 100;
+        config.log_max_files = 2;
+        let appender = file_appender(&config, Box::new(PatternEncoder::new("{m}{n}")))?;
+        for _ in 0..10 {
+            appender.append(
+                &Record::builder()
+                    .args(format_args!("{}", "x".repeat(60)))
+                    .build(),
+            )?;
+        }
+        assert!(dir.path().join("blob.log.1").exists());
+        assert!(dir.path().join("blob.log.2").exists());
+        assert!(!dir.path().join("blob.log.3").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_to_json() {
+        let record = Record::builder()
+            .args(format_args!("Downloaded blob"))
+            .level(log::Level::Info)
+            .module_path(Some("blob::processor"))
+            .build();
+        let uri = "blob://account/container/hello.deb?sig=secret";
+        let (first, second) = with_acquisition(Some(uri.to_string()), async {
+            let first = CONTEXT.with(|context| to_json(&record, &context.borrow()));
+            set_account("account");
+            let second = CONTEXT.with(|context| to_json(&record, &context.borrow()));
+            (first, second)
+        })
+        .await;
+
+        assert_eq!(first["level"], "INFO");
+        assert_eq!(first["module"], "blob::processor");
+        assert_eq!(first["message"], "Downloaded blob");
+        assert_eq!(first["uri"], redact_sas(uri));
+        assert!(first["account"].is_null());
+        assert_eq!(second["account"], "account");
+        assert_eq!(first["request_id"], second["request_id"]);
+        assert!(Uuid::parse_str(first["request_id"].as_str().unwrap()).is_ok());
+        assert_eq!(request_id(), None);
+        assert!(azure_core::date::parse_rfc3339(first["timestamp"].as_str().unwrap()).is_ok());
+    }
+}
@@ -1,80 +1,221 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
+use std::any::Any;
+use std::future::Future;
+use std::io::Write;
+use std::panic::Location;
+
 use bytes::BufMut;
 
-use log::{debug, error, info, LevelFilter, Record};
-use log4rs::filter::{Filter, Response};
+use log::{debug, error, info};
 use message::{Message, MessageType};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
 
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
-use log4rs::encode::pattern::PatternEncoder;
-
+mod atomic;
 mod azure;
+mod bandwidth;
+mod budget;
+mod bundle;
+mod cloud;
+mod config;
+mod correlation;
+mod credentials;
+mod diagnose;
+mod egress;
+mod failures;
+mod freshness;
+mod hashes;
+mod hooks;
+mod hostname;
+mod identity;
+#[cfg(feature = "journald")]
+mod journald;
+mod logging;
 mod message;
+mod metrics;
+mod naming;
+mod policy;
 mod processor;
+mod profile;
+mod progress;
+mod proxy;
+mod quiesce;
+mod redirect;
+mod retry;
+mod sources;
+mod store;
+#[cfg(feature = "syslog")]
+mod syslog;
+mod uri_context;
+
+// The optional subsystems built into the method, so fleet inventories can
+// tell which builds support what. Ones behind a Cargo feature are listed
+// only when it's enabled, with `cfg!(feature = ...)`.
+const FEATURES: &[(&str, bool)] = &[
+    ("compress", true),
+    ("dfs", true),
+    ("hooks", true),
+    ("ims", true),
+    ("journald", cfg!(feature = "journald")),
+    ("pin", true),
+    ("profile", true),
+    ("redirect", true),
+    ("rehydrate", true),
+    ("resume", true),
+    ("routes", true),
+    ("syslog", cfg!(feature = "syslog")),
+];
+
+// The version of the method, with the features built into it as semver
+// build metadata, e.g. `0.2.0+compress.dfs`.
+fn version() -> String {
+    let features: Vec<_> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    match features.is_empty() {
+        true => env!("CARGO_PKG_VERSION").to_string(),
+        false => format!("{}+{}", env!("CARGO_PKG_VERSION"), features.join(".")),
+    }
+}
 
 // Hard-coded function to send the capabilities of this transport
 fn send_capabilities() {
-    let version = env!("CARGO_PKG_VERSION");
-    Message::new(
+    let version = version();
+    processor::send(&Message::new(
         MessageType::Capabilities,
         vec![
-            ("Version", version),
+            ("Version", version.as_str()),
             ("Send-Config", "true"),
             ("Single-Instance", "true"),
+            ("Pipeline", "true"),
         ],
-    )
-    .send()
+    ));
+}
+
+// Describe a panic on a single line, so it can be sent to apt in a message
+// header.
+fn panic_message(payload: &(dyn Any + Send), location: Option<&Location>) -> String {
+    let payload = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let message = match location {
+        Some(location) => format!("Panic at {}: {}", location, payload),
+        None => format!("Panic: {}", payload),
+    };
+    message.replace('\n', " ")
 }
 
 // LCOV_EXCL_START
 
-#[derive(Debug)]
-pub struct AzureTransportFilter {}
-impl Filter for AzureTransportFilter {
-    fn filter(&self, record: &Record) -> Response {
-        match record.module_path() {
-            Some(module) => {
-                if module.starts_with("azure_core::policies::transport") {
-                    Response::Reject
-                } else {
-                    Response::Neutral
-                }
-            }
-            None => Response::Neutral,
+// Tell apt why the method is exiting if it panics, rather than leaving it to
+// report that the method exited unexpectedly.
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let message = panic_message(info.payload(), info.location());
+        error!("{}", message);
+        log::logger().flush();
+        Message::send_general_failure(&message);
+        let _ = std::io::stdout().flush();
+        std::process::exit(101);
+    }));
+}
+
+// Wait to be told to stop, as apt does when it's interrupted, returning the
+// name and number of the signal.
+fn shutdown_signal() -> std::io::Result<impl Future<Output = (&'static str, i32)>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => ("SIGTERM", 15),
+            _ = interrupt.recv() => ("SIGINT", 2),
         }
-    }
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let azure_filter = Box::new(AzureTransportFilter {});
-
-    // Set up the logger to log to the /var/log directory
-    let appender = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} [{l}] <{M}:{L}> {m}{n}")))
-        .build("/var/log/apt-transport-blob.log")?;
-
-    let config = Config::builder()
-        .appender(
-            Appender::builder()
-                // Ensure secure logs aren't logged out
-                .filter(azure_filter)
-                .build("default", Box::new(appender)),
-        )
-        .build(
-            Root::builder()
-                .appender("default")
-                .build(LevelFilter::Debug),
-        )?;
-
-    let _handle = log4rs::init_config(config)?;
+    if std::env::args().any(|arg| arg == "--version") {
+        println!("blob {}", version());
+        return Ok(());
+    }
+
+    // Print the effective configuration for troubleshooting, rather than
+    // running as an apt method.
+    if std::env::args().any(|arg| arg == "--dump-config") {
+        let config = config::Config::from_apt_config()?;
+        println!("{}", serde_json::to_string_pretty(&config.dump())?);
+        return Ok(());
+    }
+
+    // Collect diagnostics to attach to an issue, rather than running as an
+    // apt method.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(position) = args.iter().position(|arg| arg == "--support-bundle") {
+        let path = args
+            .get(position + 1)
+            .ok_or("--support-bundle requires a path")?;
+        bundle::write(path).await?;
+        println!("Wrote support bundle to {}", path);
+        return Ok(());
+    }
+
+    // Acquire a URI with apt's configuration, printing the messages that
+    // would be sent to apt, to troubleshoot without writing them to stdin.
+    if let Some(position) = args.iter().position(|arg| arg == "--fetch") {
+        let (Some(uri), Some(filename)) = (args.get(position + 1), args.get(position + 2)) else {
+            return Err("--fetch requires a URI and a filename".into());
+        };
+        let config = config::Config::from_apt_config()?;
+        logging::init(&config, true)?;
+        log::set_max_level(config.log_level());
+        let response = processor::Processor::fetch(&config, uri, filename).await?;
+        processor::send(&response);
+        return match response.message_type {
+            MessageType::URIDone => Ok(()),
+            _ => Err(format!("Failed to fetch {}", credentials::redact_sas(uri)).into()),
+        };
+    }
+
+    // Check access to a storage account or URL, printing hints for what
+    // fails, to troubleshoot refused requests.
+    if let Some(position) = args.iter().position(|arg| arg == "--diagnose") {
+        let target = args
+            .get(position + 1)
+            .ok_or("--diagnose requires a storage account or URL")?;
+        return match diagnose::run(target).await? {
+            true => Ok(()),
+            false => Err("Some checks failed".into()),
+        };
+    }
+
+    // Log where the environment says until apt's configuration arrives.
+    let config = config::Config::from_env()?;
+    logging::init(&config, std::env::args().any(|arg| arg == "--log-stderr"))?;
+    install_panic_hook();
+
+    // Only log at debug level once the configuration asks for it.
+    log::set_max_level(config.log_level());
 
     // Set up a message Processor
-    let processor = processor::Processor::new()?;
+    let mut processor = processor::Processor::new()?;
 
     let mut input_buffer = vec![];
+    // Messages are read asynchronously, so that reading further ones from
+    // apt never holds up a worker thread that downloads could run on.
+    let mut stdin = BufReader::new(tokio::io::stdin());
+
+    // apt interrupted with Ctrl-C closes the method's stdin, and may send it
+    // SIGTERM; the signal cancels acquisitions in flight rather than letting
+    // them finish.
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+    let mut signalled = None;
 
     // Print our capabilities
     send_capabilities();
@@ -84,13 +225,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Read the input on a loop until there's a double newline
     loop {
         let mut buffer = String::new();
-        let bytes = std::io::stdin().read_line(&mut buffer)?;
+        let bytes = tokio::select! {
+            bytes = stdin.read_line(&mut buffer) => bytes?,
+            signal = &mut shutdown => {
+                signalled = Some(signal);
+                break;
+            }
+        };
         if bytes == 0 {
             debug!("EOF reached");
             break;
         }
 
-        debug!("Buffer: {:?}", buffer);
+        debug!("Buffer: {:?}", credentials::redact_sas(&buffer));
         // Write the buffer to our message buffer
         input_buffer.put(buffer.as_bytes());
 
@@ -109,7 +256,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             // This is an unexpected error; log a general
                             // failure then exit.
                             error!("Error: {:?}", err);
-                            Message::send_general_failure(&format!("Error: {}", err));
+                            processor::send(&Message::build_general_failure(&format!(
+                                "Error: {}",
+                                err
+                            )));
                             return Err(err);
                         }
                     }
@@ -125,6 +275,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // Let any in-flight acquisitions complete before exiting, unless the
+    // method is told to stop first.
+    let finished = match signalled {
+        Some(_) => None,
+        None => tokio::select! {
+            result = processor.finish() => Some(result),
+            signal = &mut shutdown => {
+                signalled = Some(signal);
+                None
+            }
+        },
+    };
+    let result = match finished {
+        Some(result) => result,
+        None => processor.cancel().await,
+    };
+    if let Err(err) = result {
+        error!("Error: {:?}", err);
+        processor::send(&Message::build_general_failure(&format!("Error: {}", err)));
+        return Err(err);
+    }
+
+    // Exit straight away, as the conventional status for the signal. The
+    // runtime would otherwise wait for the read from stdin under way to
+    // finish, which it won't until apt closes it.
+    if let Some((name, number)) = signalled {
+        info!("Exiting on {}", name);
+        log::logger().flush();
+        let _ = std::io::stdout().flush();
+        std::process::exit(128 + number);
+    }
+
     Ok(())
 }
 
@@ -149,6 +331,32 @@ mod tests {
         let _ = error.source();
     }
 
+    #[test]
+    fn test_panic_message() {
+        let location = Location::caller();
+        assert_eq!(
+            panic_message(&"oh no", Some(location)),
+            format!("Panic at {}: oh no", location)
+        );
+        assert_eq!(
+            panic_message(&"two\nlines".to_string(), None),
+            "Panic: two lines"
+        );
+        assert_eq!(panic_message(&42, None), "Panic: Box<dyn Any>");
+    }
+
+    #[test]
+    fn test_version() {
+        let version = version();
+        let (release, features) = version.split_once('+').unwrap();
+        assert_eq!(release, env!("CARGO_PKG_VERSION"));
+        assert!(features.split('.').any(|feature| feature == "compress"));
+        // Build metadata identifiers may only hold alphanumerics and hyphens.
+        assert!(features
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'));
+    }
+
     #[test]
     fn test_send_capabilities() {
         send_capabilities()
@@ -1,6 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 use std::fmt::Display;
+use std::io::{Stdout, Write};
+use std::sync::{LazyLock, Mutex};
 
 use nom::bytes::complete::take_until;
 use nom::character::complete::{char, digit1, newline, space0};
@@ -8,8 +10,14 @@ use nom::combinator::map_res;
 use nom::multi::many0;
 use nom::IResult;
 
-use log::error;
 use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::credentials::redact_secrets;
+
+// Headers older releases of apt look for under other names, as
+// `(current, legacy)`.
+const LEGACY_HEADERS: [(&str, &str); 1] = [("MD5Sum-Hash", "MD5-Hash")];
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -23,11 +31,12 @@ pub enum Error {
     HeaderNotFound(String),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MessageType {
     Capabilities,
     Log,
     Status,
+    Redirect,
     URIStart,
     URIDone,
     URIFailure,
@@ -42,6 +51,7 @@ impl MessageType {
             MessageType::Capabilities => 100,
             MessageType::Log => 101,
             MessageType::Status => 102,
+            MessageType::Redirect => 103,
             MessageType::URIStart => 200,
             MessageType::URIDone => 201,
             MessageType::URIFailure => 400,
@@ -56,6 +66,7 @@ impl MessageType {
             MessageType::Capabilities => "Capabilities",
             MessageType::Log => "Log",
             MessageType::Status => "Status",
+            MessageType::Redirect => "Redirect",
             MessageType::URIStart => "URI Start",
             MessageType::URIDone => "URI Done",
             MessageType::URIFailure => "URI Failure",
@@ -76,6 +87,7 @@ impl MessageType {
             b"100" => Ok((input, MessageType::Capabilities)),
             b"101" => Ok((input, MessageType::Log)),
             b"102" => Ok((input, MessageType::Status)),
+            b"103" => Ok((input, MessageType::Redirect)),
             b"200" => Ok((input, MessageType::URIStart)),
             b"201" => Ok((input, MessageType::URIDone)),
             b"400" => Ok((input, MessageType::URIFailure)),
@@ -87,7 +99,7 @@ impl MessageType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     pub message_type: MessageType,
     pub headers: Vec<(String, String)>,
@@ -149,36 +161,85 @@ impl Message {
     }
 
     pub fn send(&self) {
-        print!("{}", self);
+        STDOUT.send(self);
+    }
+
+    pub fn build_status(message: &str) -> Self {
+        Self::new(MessageType::Status, vec![("Message", message)])
     }
 
-    pub fn send_status(message: &str) {
-        Self::new(MessageType::Status, vec![("Message", message)]).send()
+    pub fn build_general_failure(message: &str) -> Self {
+        let message = redact_secrets(message);
+        Self::new(MessageType::GeneralFailure, vec![("Message", &message)])
     }
 
     pub fn send_general_failure(message: &str) {
-        Self::new(MessageType::GeneralFailure, vec![("Message", message)]).send()
+        Self::build_general_failure(message).send()
     }
 
-    pub fn send_uri_start(uri: &str, size: u64, last_modified: &str) {
-        Self::new(
-            MessageType::URIStart,
-            vec![
-                ("URI", uri),
-                ("Size", &size.to_string()),
-                ("Last-Modified", last_modified),
-            ],
-        )
-        .send()
+    /// Build a URI Start. A non-zero resume point tells apt the transfer is
+    /// continuing from that offset in an existing partial file.
+    pub fn build_uri_start(
+        uri: &str,
+        size: u64,
+        last_modified: Option<&str>,
+        resume_point: u64,
+    ) -> Self {
+        let size = size.to_string();
+        let resume_point = resume_point.to_string();
+        let mut headers = vec![("URI", uri), ("Size", size.as_str())];
+        if let Some(last_modified) = last_modified {
+            headers.push(("Last-Modified", last_modified));
+        }
+        if resume_point != "0" {
+            headers.push(("Resume-Point", resume_point.as_str()));
+        }
+        Self::new(MessageType::URIStart, headers)
     }
 
+    /// Build a URI Failure. apt shows the message to the user and logs it,
+    /// so any secrets in it, such as signed URLs in errors from the storage
+    /// service, are redacted; the URI is left as apt gave it.
     pub fn build_uri_failure(uri: &str, message: &str) -> Self {
+        let message = redact_secrets(message);
         Self::new(
             MessageType::URIFailure,
-            vec![("URI", uri), ("Message", message)],
+            vec![("URI", uri), ("Message", &message)],
         )
     }
 
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Repeat headers under the names older releases of apt look for, e.g.
+    /// `MD5-Hash` for `MD5Sum-Hash`.
+    pub fn with_legacy_headers(mut self) -> Self {
+        for (current, legacy) in LEGACY_HEADERS {
+            if let Ok(value) = self.header(current) {
+                let value = value.to_string();
+                self = self.with_extra_headers(&[(legacy.to_string(), value)]);
+            }
+        }
+        self
+    }
+
+    /// Add headers configured for the message, after its own. Ones it has
+    /// already are skipped, so they can't change what the method reports.
+    pub fn with_extra_headers(mut self, headers: &[(String, String)]) -> Self {
+        for (key, value) in headers {
+            if !self
+                .headers
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(key))
+            {
+                self.headers.push((key.clone(), value.clone()));
+            }
+        }
+        self
+    }
+
     //
     // End of construction and logging functions
     //
@@ -206,6 +267,54 @@ impl Message {
     pub fn filename(&self) -> Result<&str, Error> {
         self.header("Filename")
     }
+
+    /// The hashes apt expects the acquired file to have, as `(type, value)`
+    /// pairs taken from the `Expected-<type>` headers.
+    pub fn expected_hashes(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix("Expected-")?, v.as_str())))
+            .collect()
+    }
+
+    /// The storage account to fetch from, overriding the one in the URI.
+    pub fn storage_account(&self) -> Option<&str> {
+        self.header("Storage-Account").ok()
+    }
+
+    /// A snapshot of the blob to fetch instead of its current content.
+    pub fn blob_snapshot(&self) -> Option<&str> {
+        self.header("Blob-Snapshot").ok()
+    }
+
+    /// A version of the blob to fetch instead of its current content.
+    pub fn blob_version_id(&self) -> Option<&str> {
+        self.header("Blob-Version-Id").ok()
+    }
+
+    /// When apt's copy of the file was last modified, if it has one, so that
+    /// it needn't be fetched again if the blob hasn't changed since.
+    pub fn last_modified(&self) -> Option<OffsetDateTime> {
+        azure_core::date::parse_rfc1123(self.header("Last-Modified").ok()?).ok()
+    }
+
+    /// Why an acquisition failed, as given to apt in a URI Failure.
+    pub fn fail_reason(&self) -> Option<&str> {
+        self.header("FailReason").ok()
+    }
+
+    /// Whether a URI Done says apt's copy of the file is up to date.
+    pub fn ims_hit(&self) -> bool {
+        self.header("IMS-Hit").is_ok_and(|value| value == "true")
+    }
+
+    /// Whether apt considers this acquisition optional, in which case a
+    /// failure to fetch it is not an error for the overall run.
+    pub fn fail_ignore(&self) -> bool {
+        self.header("Fail-Ignore")
+            .map(|value| matches!(value, "true" | "yes" | "1"))
+            .unwrap_or(false)
+    }
 }
 
 impl Display for Message {
@@ -224,10 +333,59 @@ impl Display for Message {
     }
 }
 
+// Where messages to apt are sent.
+static STDOUT: LazyLock<MessageSink<Stdout>> =
+    LazyLock::new(|| MessageSink::new(std::io::stdout()));
+
+/// Writes messages to apt, each whole and flushed in a single write, so that
+/// messages sent concurrently by several acquisitions never interleave.
+pub struct MessageSink<W: Write> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> MessageSink<W> {
+    pub fn new(writer: W) -> Self {
+        MessageSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn send(&self, message: &Message) {
+        let framed = message.to_string();
+        let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        // There's no one to tell if apt has gone away.
+        let _ = writer
+            .write_all(framed.as_bytes())
+            .and_then(|_| writer.flush());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::{cover_debug, cover_error};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    // A writer into a buffer shared with the test, which writes a byte at a
+    // time so that unsynchronised writes would interleave.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let Some(&byte) = buf.first() else {
+                return Ok(0);
+            };
+            self.0.lock().unwrap().push(byte);
+            std::thread::yield_now();
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
     fn check_parse(input: &[u8], expected: MessageType) {
         let (input, message) =
@@ -253,6 +411,7 @@ mod tests {
         assert_eq!(MessageType::Capabilities.code(), 100);
         assert_eq!(MessageType::Log.code(), 101);
         assert_eq!(MessageType::Status.code(), 102);
+        assert_eq!(MessageType::Redirect.code(), 103);
         assert_eq!(MessageType::URIStart.code(), 200);
         assert_eq!(MessageType::URIDone.code(), 201);
         assert_eq!(MessageType::URIFailure.code(), 400);
@@ -266,6 +425,7 @@ mod tests {
         assert_eq!(MessageType::Capabilities.description(), "Capabilities");
         assert_eq!(MessageType::Log.description(), "Log");
         assert_eq!(MessageType::Status.description(), "Status");
+        assert_eq!(MessageType::Redirect.description(), "Redirect");
         assert_eq!(MessageType::URIStart.description(), "URI Start");
         assert_eq!(MessageType::URIDone.description(), "URI Done");
         assert_eq!(MessageType::URIFailure.description(), "URI Failure");
@@ -279,6 +439,7 @@ mod tests {
         check_parse(b"100 Capabilities\n", MessageType::Capabilities);
         check_parse(b"101 Log\n", MessageType::Log);
         check_parse(b"102 Status\n", MessageType::Status);
+        check_parse(b"103 Redirect\n", MessageType::Redirect);
         check_parse(b"200 URI Start\n", MessageType::URIStart);
         check_parse(b"201 URI Done\n", MessageType::URIDone);
         check_parse(b"400 URI Failure\n", MessageType::URIFailure);
@@ -373,13 +534,225 @@ mod tests {
 
     #[test]
     fn test_send_messages() -> Result<(), Box<dyn std::error::Error>> {
-        Message::send_status("Hello, world");
+        Message::build_status("Hello, world").send();
         Message::send_general_failure("Goodbye, world");
-        Message::send_uri_start("http://example.com", 123, "2021-01-01T00:00:00Z");
+        Message::build_uri_start("http://example.com", 123, Some("2021-01-01T00:00:00Z"), 0).send();
+        Message::build_uri_start("http://example.com", 123, Some("2021-01-01T00:00:00Z"), 100)
+            .send();
+        Message::build_uri_start("http://example.com", 123, None, 0).send();
         let _ = Message::build_uri_failure("http://example.com", "Failed");
         Ok(())
     }
 
+    #[test]
+    fn test_concurrent_framing() {
+        const ACQUISITIONS: usize = 16;
+        const FILES: usize = 20;
+
+        // Simulate many acquisitions sending the messages for their files at
+        // once, with headers of varying lengths.
+        let buffer = SharedBuffer::default();
+        let sink = Arc::new(MessageSink::new(buffer.clone()));
+        let threads: Vec<_> = (0..ACQUISITIONS)
+            .map(|acquisition| {
+                let sink = sink.clone();
+                std::thread::spawn(move || {
+                    let mut rng = fastrand::Rng::with_seed(acquisition as u64);
+                    for file in 0..FILES {
+                        let uri = format!("blob://account/repo/{}/{}", acquisition, file);
+                        let size = rng.u64(..).to_string();
+                        let path = "x".repeat(rng.usize(..200));
+                        sink.send(&Message::new(
+                            MessageType::URIStart,
+                            vec![("URI", &uri), ("Size", &size)],
+                        ));
+                        sink.send(&Message::new(MessageType::Status, vec![("Message", &path)]));
+                        let done = if rng.bool() {
+                            Message::new(
+                                MessageType::URIDone,
+                                vec![("URI", &uri), ("Filename", &path)],
+                            )
+                        } else {
+                            Message::build_uri_failure(&uri, &path)
+                        };
+                        sink.send(&done);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The output must be a sequence of whole messages, in which each
+        // file is started then finished exactly once.
+        let output = buffer.0.lock().unwrap().clone();
+        let mut input = output.as_slice();
+        let mut states: HashMap<String, MessageType> = HashMap::new();
+        let mut messages = 0;
+        while !input.is_empty() {
+            let offset = output.len() - input.len();
+            let end = input
+                .windows(2)
+                .position(|window| window == b"\n\n")
+                .unwrap_or_else(|| panic!("Unterminated message at byte {}", offset))
+                + 2;
+            let message = Message::from_bytes(&input[..end])
+                .unwrap_or_else(|err| panic!("Malformed message at byte {}: {}", offset, err));
+            input = &input[end..];
+            messages += 1;
+            let Ok(uri) = message.uri().map(str::to_string) else {
+                assert_eq!(message.message_type, MessageType::Status);
+                continue;
+            };
+            let previous = states.remove(&uri);
+            match message.message_type {
+                MessageType::URIStart => assert_eq!(previous, None),
+                MessageType::URIDone | MessageType::URIFailure => {
+                    assert_eq!(previous, Some(MessageType::URIStart))
+                }
+                ref other => panic!("Unexpected {:?} for {}", other, uri),
+            }
+            states.insert(uri, message.message_type);
+        }
+        assert_eq!(messages, ACQUISITIONS * FILES * 3);
+        assert_eq!(states.len(), ACQUISITIONS * FILES);
+        assert!(states.values().all(|state| *state != MessageType::URIStart));
+    }
+
+    #[test]
+    fn test_fail_ignore() {
+        let message = Message::new(MessageType::URIAcquire, vec![("Fail-Ignore", "true")]);
+        assert!(message.fail_ignore());
+
+        let message = Message::new(MessageType::URIAcquire, vec![("Fail-Ignore", "false")]);
+        assert!(!message.fail_ignore());
+
+        let message = Message::new(MessageType::URIAcquire, vec![]);
+        assert!(!message.fail_ignore());
+    }
+
+    #[test]
+    fn test_expected_hashes() {
+        let message = Message::new(
+            MessageType::URIAcquire,
+            vec![
+                ("URI", "blob://a/b/c"),
+                ("Expected-SHA256", "abcd"),
+                ("Expected-Checksum-FileSize", "10"),
+            ],
+        );
+        assert_eq!(
+            message.expected_hashes(),
+            vec![("SHA256", "abcd"), ("Checksum-FileSize", "10")]
+        );
+    }
+
+    #[test]
+    fn test_blob_overrides() {
+        let message = Message::new(MessageType::URIAcquire, vec![("URI", "blob://a/b/c")]);
+        assert_eq!(message.storage_account(), None);
+        assert_eq!(message.blob_snapshot(), None);
+        assert_eq!(message.blob_version_id(), None);
+
+        let message = message
+            .with_header("Storage-Account", "other")
+            .with_header("Blob-Snapshot", "2024-01-01T00:00:00.0000000Z")
+            .with_header("Blob-Version-Id", "2024-02-01T00:00:00.0000000Z");
+        assert_eq!(message.storage_account(), Some("other"));
+        assert_eq!(
+            message.blob_snapshot(),
+            Some("2024-01-01T00:00:00.0000000Z")
+        );
+        assert_eq!(
+            message.blob_version_id(),
+            Some("2024-02-01T00:00:00.0000000Z")
+        );
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let message = Message::new(MessageType::URIAcquire, vec![("URI", "blob://a/b/c")]);
+        assert_eq!(message.last_modified(), None);
+        let message = message.with_header("Last-Modified", "Wed, 29 May 2024 12:00:00 GMT");
+        assert_eq!(
+            message.last_modified(),
+            OffsetDateTime::from_unix_timestamp(1716984000).ok()
+        );
+        let message = Message::new(
+            MessageType::URIAcquire,
+            vec![("Last-Modified", "yesterday")],
+        );
+        assert_eq!(message.last_modified(), None);
+    }
+
+    #[test]
+    fn test_with_header() {
+        let message = Message::build_uri_failure("blob://a/b/c", "Failed")
+            .with_header("FailReason", "HttpError404");
+        assert_eq!(
+            format!("{}", message),
+            "400 URI Failure\n\
+             URI: blob://a/b/c\n\
+             Message: Failed\n\
+             FailReason: HttpError404\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn test_build_uri_failure_redacts() {
+        let message = Message::build_uri_failure(
+            "blob://a/b/c?sig=secret",
+            "Failed to GET https://a.blob.core.windows.net/b/c?sv=1&sig=secret \
+             with Authorization: Bearer eyJ0eXAi",
+        );
+        assert_eq!(message.uri().unwrap(), "blob://a/b/c?sig=secret");
+        assert_eq!(
+            message.header("Message").unwrap(),
+            "Failed to GET https://a.blob.core.windows.net/b/c?sv=1&sig=REDACTED \
+             with Authorization: Bearer REDACTED"
+        );
+    }
+
+    #[test]
+    fn test_with_legacy_headers() {
+        let message = Message::new(
+            MessageType::URIDone,
+            vec![("URI", "blob://a/b/c"), ("MD5Sum-Hash", "5eb63bbb")],
+        );
+        assert_eq!(
+            format!("{}", message.clone().with_legacy_headers()),
+            "201 URI Done\n\
+             URI: blob://a/b/c\n\
+             MD5Sum-Hash: 5eb63bbb\n\
+             MD5-Hash: 5eb63bbb\n\
+             \n"
+        );
+
+        let message = Message::new(MessageType::URIDone, vec![("URI", "blob://a/b/c")]);
+        assert_eq!(
+            format!("{}", message.with_legacy_headers()),
+            "201 URI Done\nURI: blob://a/b/c\n\n"
+        );
+    }
+
+    #[test]
+    fn test_with_extra_headers() {
+        let message = Message::new(MessageType::URIDone, vec![("URI", "blob://a/b/c")])
+            .with_extra_headers(&[
+                ("X-Repo-Channel".to_string(), "prod".to_string()),
+                ("uri".to_string(), "blob://x/y/z".to_string()),
+            ]);
+        assert_eq!(
+            format!("{}", message),
+            "201 URI Done\n\
+             URI: blob://a/b/c\n\
+             X-Repo-Channel: prod\n\
+             \n"
+        );
+    }
+
     #[test]
     fn test_description() {
         let message = Message {
@@ -0,0 +1,277 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use time::OffsetDateTime;
+
+use crate::atomic;
+
+// The metrics for the whole run. Retries happen deep within requests to the
+// storage service, so they're counted here rather than threaded through.
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Counts of what the method has done in this run, written out in the
+/// Prometheus textfile collector format when it exits, for node_exporter
+/// to pick up.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    acquisitions: u64,
+    downloads: u64,
+    bytes: u64,
+    ims_hits: u64,
+    // Failed acquisitions by their `FailReason`, or `Unknown` without one.
+    failures: BTreeMap<String, u64>,
+    retries: u64,
+    // Time taken by successful downloads.
+    download_seconds: f64,
+    // Downloads and bytes fetched by storage account.
+    accounts: BTreeMap<String, AccountCounts>,
+}
+
+#[derive(Debug, Default)]
+struct AccountCounts {
+    downloads: u64,
+    bytes: u64,
+}
+
+/// Record apt asking for a URI.
+pub fn record_acquisition() {
+    METRICS.record_acquisition()
+}
+
+/// Record a blob downloaded in full from the storage account, of which
+/// `bytes` were fetched.
+pub fn record_download(account: &str, bytes: u64, elapsed: Duration) {
+    METRICS.record_download(account, bytes, elapsed)
+}
+
+/// Record an acquisition skipped because apt's copy was up to date.
+pub fn record_ims_hit() {
+    METRICS.record_ims_hit()
+}
+
+/// Record a failed acquisition, with the `FailReason` it was given.
+pub fn record_failure(reason: Option<&str>) {
+    METRICS.record_failure(reason)
+}
+
+/// Record a request being retried.
+pub fn record_retry() {
+    METRICS.record_retry()
+}
+
+/// Write the metrics to the given file, if there is one. Failing to write
+/// them is logged, but isn't an error.
+pub fn write(path: Option<&str>) {
+    let Some(path) = path else {
+        return;
+    };
+    match METRICS.write_to(Path::new(path), OffsetDateTime::now_utc()) {
+        Ok(()) => info!("Wrote metrics to {}", path),
+        Err(err) => warn!("Failed to write metrics to {}: {}", path, err),
+    }
+}
+
+impl Metrics {
+    fn record_acquisition(&self) {
+        self.counts.lock().unwrap().acquisitions += 1;
+    }
+
+    fn record_download(&self, account: &str, bytes: u64, elapsed: Duration) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.downloads += 1;
+        counts.bytes += bytes;
+        counts.download_seconds += elapsed.as_secs_f64();
+        let account = counts.accounts.entry(account.to_string()).or_default();
+        account.downloads += 1;
+        account.bytes += bytes;
+    }
+
+    fn record_ims_hit(&self) {
+        self.counts.lock().unwrap().ims_hits += 1;
+    }
+
+    fn record_failure(&self, reason: Option<&str>) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts
+            .failures
+            .entry(reason.unwrap_or("Unknown").to_string())
+            .or_default() += 1;
+    }
+
+    fn record_retry(&self) {
+        self.counts.lock().unwrap().retries += 1;
+    }
+
+    // Write the metrics atomically, so the collector never reads a partly
+    // written file. The temporary file's extension isn't `.prom`, so the
+    // collector ignores it.
+    fn write_to(&self, path: &Path, now: OffsetDateTime) -> std::io::Result<()> {
+        let text = self.counts.lock().unwrap().to_text(now);
+        atomic::write(path, text)
+    }
+}
+
+impl Counts {
+    // The metrics in the Prometheus text exposition format.
+    fn to_text(&self, now: OffsetDateTime) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(text, "# HELP apt_blob_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE apt_blob_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "apt_blob_{}{} {}", name, labels, value);
+            }
+        };
+        let sample = |value: String| vec![(String::new(), value)];
+        metric(
+            "acquisitions_total",
+            "counter",
+            "URIs apt asked for.",
+            &sample(self.acquisitions.to_string()),
+        );
+        metric(
+            "downloads_total",
+            "counter",
+            "Blobs downloaded.",
+            &sample(self.downloads.to_string()),
+        );
+        metric(
+            "downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded from the storage service.",
+            &sample(self.bytes.to_string()),
+        );
+        let by_account = |value: fn(&AccountCounts) -> u64| -> Vec<_> {
+            self.accounts
+                .iter()
+                .map(|(account, counts)| {
+                    let labels = format!("{{account=\"{}\"}}", account);
+                    (labels, value(counts).to_string())
+                })
+                .collect()
+        };
+        metric(
+            "account_downloads_total",
+            "counter",
+            "Blobs downloaded, by storage account.",
+            &by_account(|counts| counts.downloads),
+        );
+        metric(
+            "account_downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded from the storage service, by storage account.",
+            &by_account(|counts| counts.bytes),
+        );
+        metric(
+            "ims_hits_total",
+            "counter",
+            "Acquisitions skipped because apt's copy was up to date.",
+            &sample(self.ims_hits.to_string()),
+        );
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|(reason, count)| (format!("{{reason=\"{}\"}}", reason), count.to_string()))
+            .collect();
+        metric(
+            "failures_total",
+            "counter",
+            "Failed acquisitions, by FailReason.",
+            &failures,
+        );
+        metric(
+            "retries_total",
+            "counter",
+            "Requests to the storage service retried.",
+            &sample(self.retries.to_string()),
+        );
+        metric(
+            "download_duration_seconds",
+            "summary",
+            "Time taken by successful downloads.",
+            &[
+                ("_sum".to_string(), self.download_seconds.to_string()),
+                ("_count".to_string(), self.downloads.to_string()),
+            ],
+        );
+        metric(
+            "last_run_timestamp_seconds",
+            "gauge",
+            "When the method last exited.",
+            &sample(now.unix_timestamp().to_string()),
+        );
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_text() {
+        let metrics = Metrics::default();
+        metrics.record_acquisition();
+        metrics.record_download("a", 1000, Duration::from_millis(1500));
+        metrics.record_download("b", 24, Duration::from_millis(500));
+        metrics.record_download("b", 0, Duration::ZERO);
+        metrics.record_ims_hit();
+        metrics.record_failure(Some("HttpError404"));
+        metrics.record_failure(Some("HttpError404"));
+        metrics.record_failure(None);
+        metrics.record_retry();
+
+        let now = OffsetDateTime::from_unix_timestamp(1717000000).unwrap();
+        let text = metrics.counts.lock().unwrap().to_text(now);
+        for line in [
+            "# TYPE apt_blob_downloads_total counter",
+            "apt_blob_acquisitions_total 1",
+            "apt_blob_downloads_total 3",
+            "apt_blob_downloaded_bytes_total 1024",
+            "apt_blob_account_downloads_total{account=\"a\"} 1",
+            "apt_blob_account_downloads_total{account=\"b\"} 2",
+            "apt_blob_account_downloaded_bytes_total{account=\"a\"} 1000",
+            "apt_blob_account_downloaded_bytes_total{account=\"b\"} 24",
+            "apt_blob_ims_hits_total 1",
+            "apt_blob_failures_total{reason=\"HttpError404\"} 2",
+            "apt_blob_failures_total{reason=\"Unknown\"} 1",
+            "apt_blob_retries_total 1",
+            "# TYPE apt_blob_download_duration_seconds summary",
+            "apt_blob_download_duration_seconds_sum 2",
+            "apt_blob_download_duration_seconds_count 3",
+            "apt_blob_last_run_timestamp_seconds 1717000000",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing from {}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_to() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("apt_blob.prom");
+        let metrics = Metrics::default();
+        metrics.record_retry();
+        metrics.write_to(&path, OffsetDateTime::now_utc())?;
+        let text = std::fs::read_to_string(&path)?;
+        assert!(text.contains("apt_blob_retries_total 1\n"));
+        // Only the metrics are left, without a temporary file beside them.
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
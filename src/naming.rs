@@ -0,0 +1,103 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+// Containers with special purposes, whose names don't follow the usual rules.
+const SPECIAL_CONTAINERS: [&str; 3] = ["$root", "$web", "$logs"];
+
+/// Check a storage account name against Azure's naming rules: 3 to 24
+/// lowercase letters and digits. Returns why the name is invalid, if it is.
+pub fn check_account(name: &str) -> Result<(), String> {
+    let invalid = |reason: &str| {
+        Err(format!(
+            "Invalid storage account name {:?}: {}",
+            name, reason
+        ))
+    };
+    if !(3..=24).contains(&name.len()) {
+        return invalid("it must be 3 to 24 characters long");
+    }
+    if let Some(c) = name.chars().find(|c| c.is_ascii_uppercase()) {
+        return invalid(&format!("it contains the uppercase letter {:?}", c));
+    }
+    if let Some(c) = name.chars().find(|c| !c.is_ascii_alphanumeric()) {
+        return invalid(&format!(
+            "it contains {:?}, but may only contain lowercase letters and digits",
+            c
+        ));
+    }
+    Ok(())
+}
+
+/// Check a container name against Azure's naming rules: 3 to 63 lowercase
+/// letters, digits and hyphens, starting with a letter or digit, without
+/// consecutive hyphens and not ending with one. Returns why the name is
+/// invalid, if it is.
+pub fn check_container(name: &str) -> Result<(), String> {
+    if SPECIAL_CONTAINERS.contains(&name) {
+        return Ok(());
+    }
+    let invalid = |reason: &str| Err(format!("Invalid container name {:?}: {}", name, reason));
+    if !(3..=63).contains(&name.len()) {
+        return invalid("it must be 3 to 63 characters long");
+    }
+    if let Some(c) = name.chars().find(|c| c.is_ascii_uppercase()) {
+        return invalid(&format!("it contains the uppercase letter {:?}", c));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| !c.is_ascii_alphanumeric() && c != '-')
+    {
+        return invalid(&format!(
+            "it contains {:?}, but may only contain lowercase letters, digits and hyphens",
+            c
+        ));
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        return invalid("it may not start or end with a hyphen");
+    }
+    if name.contains("--") {
+        return invalid("it may not contain consecutive hyphens");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_account() {
+        assert_eq!(check_account("myaccount"), Ok(()));
+        assert_eq!(check_account("devstoreaccount1"), Ok(()));
+        assert_eq!(
+            check_account("MyAccount"),
+            Err(
+                "Invalid storage account name \"MyAccount\": it contains the uppercase letter 'M'"
+                    .to_string()
+            )
+        );
+        assert!(check_account("my-account")
+            .unwrap_err()
+            .contains("contains '-'"));
+        assert!(check_account("ab").unwrap_err().contains("3 to 24"));
+        assert!(check_account(&"a".repeat(25)).is_err());
+    }
+
+    #[test]
+    fn test_check_container() {
+        assert_eq!(check_container("repo"), Ok(()));
+        assert_eq!(check_container("my-repo-2"), Ok(()));
+        assert_eq!(check_container("$web"), Ok(()));
+        assert!(check_container("Repo")
+            .unwrap_err()
+            .contains("uppercase letter 'R'"));
+        assert!(check_container("my_repo").unwrap_err().contains("'_'"));
+        assert!(check_container("-repo").unwrap_err().contains("hyphen"));
+        assert!(check_container("repo-").unwrap_err().contains("hyphen"));
+        assert!(check_container("my--repo")
+            .unwrap_err()
+            .contains("consecutive"));
+        assert!(check_container("ab").unwrap_err().contains("3 to 63"));
+        assert!(check_container(&"a".repeat(64)).is_err());
+    }
+}
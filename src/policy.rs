@@ -0,0 +1,121 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use crate::config::Config;
+
+/// Check whether the configured allow and deny patterns permit fetching a
+/// blob, given as `account/container/blob`. A blob matching any deny pattern
+/// is refused, as is one that doesn't match an allow pattern when there are
+/// any. Returns why the blob is refused, if it is.
+pub fn check(config: &Config, path: &str) -> Result<(), String> {
+    if let Some(pattern) = config.deny.iter().find(|pattern| matches(pattern, path)) {
+        return Err(format!("{} is denied by {}", path, pattern));
+    }
+    if !config.allow.is_empty() && !config.allow.iter().any(|pattern| matches(pattern, path)) {
+        return Err(format!("{} is not allowed", path));
+    }
+    Ok(())
+}
+
+/// Check whether a blob, given as `account/container/blob`, is within the
+/// size limits configured for it. A blob matching several patterns must be
+/// within each of their limits. Returns why the blob is refused, if it is.
+pub fn check_size(config: &Config, path: &str, size: u64) -> Result<(), String> {
+    match config
+        .max_sizes
+        .iter()
+        .filter(|limit| matches(&limit.pattern, path))
+        .min_by_key(|limit| limit.max_size)
+    {
+        Some(limit) if size > limit.max_size => Err(format!(
+            "{} is {} bytes, more than the {} bytes allowed by {}",
+            path, size, limit.max_size, limit.pattern
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Match text against a pattern in which `*` stands for any run of
+// characters, including `/`, and `?` for any single character.
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The most recent `*` and the text position it's matched up to, to go
+    // back to if the rest of the pattern fails to match.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SizeLimit;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("a/c/Release", "a/c/Release"));
+        assert!(!matches("a/c/Release", "a/c/Release.gpg"));
+        assert!(matches("a/c/*", "a/c/dists/stable/Release"));
+        assert!(matches("a/*/Release", "a/c/dists/stable/Release"));
+        assert!(!matches("a/*/Release", "a/c/dists/stable/InRelease.gpg"));
+        assert!(matches("*", ""));
+        assert!(matches("a/c?/*", "a/c1/x"));
+        assert!(!matches("a/c?/*", "a/c/x"));
+        assert!(matches("*/pool/*.deb", "a/c/pool/main/h/hello.deb"));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut config = Config::default();
+        assert_eq!(check(&config, "a/c/Release"), Ok(()));
+
+        config.allow = vec!["a/approved/*".to_string(), "b/*".to_string()];
+        assert_eq!(check(&config, "a/approved/Release"), Ok(()));
+        assert_eq!(check(&config, "b/any/Release"), Ok(()));
+        assert!(check(&config, "a/other/Release").is_err());
+
+        config.deny = vec!["*/pool/*/debug/*".to_string()];
+        assert_eq!(check(&config, "b/any/pool/main/x.deb"), Ok(()));
+        assert!(check(&config, "b/any/pool/main/debug/x.deb").is_err());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let mut config = Config::default();
+        assert_eq!(check_size(&config, "a/c/pool/x.deb", u64::MAX), Ok(()));
+
+        let limit = |pattern: &str, max_size| SizeLimit {
+            pattern: pattern.to_string(),
+            max_size,
+        };
+        config.max_sizes = vec![limit("*.deb", 1000), limit("a/c/pool/main/*", 100)];
+        assert_eq!(check_size(&config, "a/c/pool/x.deb", 1000), Ok(()));
+        assert!(check_size(&config, "a/c/pool/x.deb", 1001).is_err());
+        assert_eq!(check_size(&config, "a/c/pool/main/x.deb", 100), Ok(()));
+        assert_eq!(
+            check_size(&config, "a/c/pool/main/x.deb", 101),
+            Err("a/c/pool/main/x.deb is 101 bytes, more than the 100 bytes allowed by a/c/pool/main/*".to_string())
+        );
+        assert_eq!(check_size(&config, "a/c/dists/Release", 5000), Ok(()));
+    }
+}
@@ -1,51 +1,282 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use log::{debug, error, info, warn};
+use time::OffsetDateTime;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinSet};
 use url::Url;
 
 use crate::{
+    atomic,
     azure::AzureRegistry,
+    bandwidth,
+    budget::FailureBudget,
+    config::{AptCompat, Config, HookFailure},
+    credentials::redact_sas,
+    egress::EgressCounter,
+    failures::RecentFailures,
+    freshness::{self, ETagStore},
+    hashes, hooks, hostname, logging,
     message::{Message, MessageType},
+    metrics, policy,
+    profile::PerformanceProfile,
+    progress::Progress,
+    quiesce::{self, Action},
+    sources,
+    store::{Lookup, SharedStore},
+    uri_context::{Phase, UriContext},
 };
 
-macro_rules! unwrap_or_urifail {
-    ($uri: expr, $result:expr) => {
-        match $result {
-            Ok(value) => value,
-            Err(err) => {
-                let message = format!("Error: {}", err);
-                error!("URI failure for {}: {}", $uri, message);
-                return Ok(Message::build_uri_failure($uri, &message));
-            }
+// Whether the URL is for a repository index, rather than a package. Indexes
+// are kept under `dists/`.
+fn is_index(url: &Url) -> bool {
+    url.path().contains("/dists/") || url.query().is_some_and(|query| query.contains("/dists/"))
+}
+
+// How old a temporary file must be to be taken as left by a crashed run
+// rather than being written by one still running.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+// Errors from acquisitions cross task boundaries, so must be sendable.
+type AcquireError = Box<dyn std::error::Error + Send + Sync>;
+
+// Limits the number of acquisitions in flight at once, in all and for each
+// storage account, so that many for one account don't get it throttled.
+// Acquisitions over either limit wait in the order apt asked for them.
+struct Scheduler {
+    slots: Arc<Semaphore>,
+    account_slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+    account_depth: Option<usize>,
+}
+
+// What an acquisition holds while it's in flight.
+type Admission = (Option<OwnedSemaphorePermit>, OwnedSemaphorePermit);
+
+impl Scheduler {
+    fn new(config: &Config) -> Self {
+        Scheduler {
+            slots: Arc::new(Semaphore::new(config.pipeline_depth)),
+            account_slots: Mutex::new(HashMap::new()),
+            account_depth: config.account_pipeline_depth,
         }
+    }
+
+    // Wait for the acquisition to be admitted for the account. The account's
+    // slot is taken first, so that acquisitions waiting on a busy account
+    // don't hold slots others could use.
+    async fn admit(&self, account: &str) -> Result<Admission, AcquireError> {
+        let account_permit = match self.account_depth {
+            Some(depth) => {
+                let account_slots = self
+                    .account_slots
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .entry(account.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(depth)))
+                    .clone();
+                Some(account_slots.acquire_owned().await?)
+            }
+            None => None,
+        };
+        let permit = self.slots.clone().acquire_owned().await?;
+        Ok((account_permit, permit))
+    }
+}
+
+/// Send a message to apt, logging it for the transcripts in support bundles.
+/// `Message::send` can't log it itself, as the panic hook sends with it.
+pub fn send(message: &Message) {
+    debug!("Sent: {:?}", message.to_string());
+    message.send();
+}
+
+// The storage account an acquisition is for, to limit those in flight for
+// it: the one apt names, or else the first label of the URI's hostname, or
+// with the emulator, the first part of its path. It needn't be exact; the
+// URI is checked properly once the acquisition is admitted.
+fn account_of(message: &Message, config: &Config) -> String {
+    if let Some(account) = message.storage_account() {
+        return account.to_string();
+    }
+    let Some(url) = message.uri().ok().and_then(|uri| Url::parse(uri).ok()) else {
+        return String::new();
     };
+    let account = match config.emulator {
+        true => url.path().trim_start_matches('/').split('/').next(),
+        false => url.host_str().and_then(|host| host.split('.').next()),
+    };
+    account.unwrap_or_default().to_ascii_lowercase()
 }
 
 pub struct Processor {
-    azure_registry: AzureRegistry,
+    azure_registry: Arc<AzureRegistry>,
+    config: Arc<Config>,
+    scheduler: Arc<Scheduler>,
+    failure_budget: Arc<FailureBudget>,
+    recent_failures: Arc<RecentFailures>,
+    egress: Arc<EgressCounter>,
+    profile: Arc<PerformanceProfile>,
+    etags: Arc<ETagStore>,
+    acquisitions: JoinSet<Result<(), AcquireError>>,
+    // The URIs of the acquisitions in flight, to fail any which are cancelled.
+    pending: HashMap<task::Id, String>,
+    // Whether apt has sent its configuration. Legacy releases don't.
+    configured: bool,
 }
 
 impl Processor {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config::default();
         Ok(Processor {
-            azure_registry: AzureRegistry::new()?,
+            azure_registry: Arc::new(AzureRegistry::new(&config)?),
+            scheduler: Arc::new(Scheduler::new(&config)),
+            failure_budget: Arc::new(FailureBudget::new(config.failure_budget)),
+            recent_failures: Arc::new(RecentFailures::new(config.failure_memory())),
+            egress: Arc::new(EgressCounter::default()),
+            profile: Arc::new(PerformanceProfile::default()),
+            etags: Arc::new(ETagStore::default()),
+            config: Arc::new(config),
+            acquisitions: JoinSet::new(),
+            pending: HashMap::new(),
+            configured: false,
         })
     }
 
-    pub async fn process(&self, message: Message) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn process(&mut self, message: Message) -> Result<(), Box<dyn std::error::Error>> {
         debug!("Handling message: {}", message.description());
         match message.message_type {
             MessageType::Configuration => {
                 info!("Configuration message received");
-                // Currently, nothing is done with the configuration
+                let config = Config::from_message(&message)?;
+                // Switching where the method logs resets the log level, so
+                // it's done first.
+                logging::configure(&config);
+                log::set_max_level(config.log_level());
+                debug!("Configuration: {:?}", config);
+                self.scheduler = Arc::new(Scheduler::new(&config));
+                // The HTTP client's timeouts are fixed when it's built.
+                self.azure_registry = Arc::new(AzureRegistry::new(&config)?);
+                self.failure_budget = Arc::new(FailureBudget::new(config.failure_budget));
+                self.recent_failures = Arc::new(RecentFailures::new(config.failure_memory()));
+                self.egress = Arc::new(EgressCounter::new(
+                    config.egress_file.as_deref(),
+                    config.egress_budget,
+                ));
+                self.profile = Arc::new(PerformanceProfile::load(config.profile_file.as_deref()));
+                self.etags = Arc::new(ETagStore::new(config.etag_file.as_deref()));
+                Self::remove_stale_temp_files(&config);
+                bandwidth::set_rate(config.dl_limit);
+                let quiesced = quiesce::detect(&config).await;
+                if let Some(reason) = &quiesced {
+                    info!("Quiescing, as {}", reason);
+                }
+                quiesce::set(quiesced);
+                self.config = Arc::new(config);
+                if self.config.prefetch_tokens {
+                    self.prefetch_tokens();
+                }
+                self.configured = true;
             }
             MessageType::URIAcquire => {
                 info!("URI Acquire message received");
-                Message::send_status("Waiting for headers");
+                metrics::record_acquisition();
+
+                // Surface any terminal errors from earlier acquisitions.
+                while let Some(result) = self.acquisitions.try_join_next_with_id() {
+                    let (id, result) = result?;
+                    self.pending.remove(&id);
+                    result.map_err(|err| err as Box<dyn std::error::Error>)?;
+                }
+
+                // Hand the acquisition straight to a task, which waits for a
+                // free slot itself, so that reading further messages from apt
+                // never blocks on downloads.
+                let scheduler = self.scheduler.clone();
+                let account = account_of(&message, &self.config);
+                let azure_registry = self.azure_registry.clone();
+                let config = self.config.clone();
+                let failure_budget = self.failure_budget.clone();
+                let recent_failures = self.recent_failures.clone();
+                let egress = self.egress.clone();
+                let profile = self.profile.clone();
+                let etags = self.etags.clone();
+                let uri = message.uri().ok().map(str::to_string);
+                let legacy = self.is_legacy_apt();
+                let acquisition = async move {
+                    let _admission = scheduler.admit(&account).await?;
+
+                    // Once too much time has gone on failures, fail the rest
+                    // straight away so apt can try again later.
+                    if failure_budget.exhausted() {
+                        let response = Self::budget_exhausted(&message)?;
+                        metrics::record_failure(response.fail_reason());
+                        send(&response);
+                        return Ok(());
+                    }
+
+                    // apt retrying a URI which just failed would only get
+                    // the same failure, after the same wait.
+                    let recalled = message
+                        .uri()
+                        .ok()
+                        .and_then(|uri| recent_failures.recall(uri));
+                    if let Some(failure) = recalled {
+                        info!(
+                            "Failing {} again, as it failed moments ago",
+                            redact_sas(message.uri()?)
+                        );
+                        metrics::record_failure(failure.fail_reason());
+                        send(&failure);
+                        return Ok(());
+                    }
+
+                    send(&Message::build_status("Waiting for headers"));
 
-                // Try and acquire the URI.  A message will be returned on
-                // success (or failure), which is then sent.
-                self.uri_acquire(message).await?.send();
+                    // Try and acquire the URI.  A message will be returned on
+                    // success (or failure), which is then sent.
+                    let started = Instant::now();
+                    let response = Self::uri_acquire(
+                        &azure_registry,
+                        &config,
+                        &egress,
+                        &profile,
+                        &etags,
+                        message,
+                    )
+                    .await?;
+                    let response = match response.message_type {
+                        MessageType::URIFailure => {
+                            failure_budget.record(started.elapsed());
+                            recent_failures.record(&response);
+                            metrics::record_failure(response.fail_reason());
+                            response
+                        }
+                        MessageType::URIDone => {
+                            if response.ims_hit() {
+                                metrics::record_ims_hit();
+                            }
+                            let response = match legacy {
+                                true => response.with_legacy_headers(),
+                                false => response,
+                            };
+                            response.with_extra_headers(&config.done_headers)
+                        }
+                        _ => response,
+                    };
+                    send(&response);
+                    Ok(())
+                };
+                let acquisition = self
+                    .acquisitions
+                    .spawn(logging::with_acquisition(uri.clone(), acquisition));
+                if let Some(uri) = uri {
+                    self.pending.insert(acquisition.id(), uri);
+                }
             }
             _ => {
                 warn!("Unhandled message type: {}", message.description());
@@ -54,55 +285,658 @@ impl Processor {
         Ok(())
     }
 
+    // Remove temporary files left beside the state files by runs which
+    // crashed while writing them.
+    fn remove_stale_temp_files(config: &Config) {
+        let state_files = [
+            &config.etag_file,
+            &config.profile_file,
+            &config.egress_file,
+            &config.metrics_file,
+        ];
+        for path in state_files.into_iter().flatten() {
+            match atomic::remove_stale_temp(Path::new(path), STALE_TEMP_AGE) {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} stale temporary files beside {}", removed, path),
+                Err(err) => warn!(
+                    "Failed to remove the temporary files beside {}: {}",
+                    path, err
+                ),
+            }
+        }
+    }
+
+    // Whether to emit messages for a legacy release of apt: as configured,
+    // or when apt asks for a URI without having sent its configuration,
+    // which current releases always do for methods asking for it.
+    fn is_legacy_apt(&self) -> bool {
+        match self.config.apt_compat {
+            AptCompat::Auto => !self.configured,
+            AptCompat::Modern => false,
+            AptCompat::Legacy => true,
+        }
+    }
+
+    /// Wait for all in-flight acquisitions to complete, returning the first
+    /// terminal error encountered. Any still in flight once the drain timeout
+    /// has passed are cancelled, and fail transiently.
+    pub async fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let drain_timeout = self.config.drain_timeout;
+        match tokio::time::timeout(drain_timeout, self.join_all()).await {
+            Ok(result) => result?,
+            Err(_) => {
+                warn!(
+                    "{} acquisitions still in flight after {}s, cancelling them",
+                    self.acquisitions.len(),
+                    drain_timeout.as_secs()
+                );
+                self.cancel_all().await?;
+            }
+        }
+        metrics::write(self.config.metrics_file.as_deref());
+        Ok(())
+    }
+
+    /// Cancel all in-flight acquisitions straight away, as when apt has been
+    /// interrupted, failing each transiently. Their files are left as they
+    /// are, for apt to resume downloading into when it asks again.
+    pub async fn cancel(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.acquisitions.is_empty() {
+            warn!(
+                "Cancelling {} acquisitions in flight",
+                self.acquisitions.len()
+            );
+        }
+        self.cancel_all().await?;
+        metrics::write(self.config.metrics_file.as_deref());
+        Ok(())
+    }
+
+    // Wait for all in-flight acquisitions to complete.
+    async fn join_all(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        while let Some(result) = self.acquisitions.join_next_with_id().await {
+            let (id, result) = result?;
+            self.pending.remove(&id);
+            result.map_err(|err| err as Box<dyn std::error::Error>)?;
+        }
+        Ok(())
+    }
+
+    // Cancel all in-flight acquisitions, failing each transiently so apt can
+    // try it again. Any which finished meanwhile are waited for as usual.
+    async fn cancel_all(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.acquisitions.abort_all();
+        let mut first_error = None;
+        while let Some(result) = self.acquisitions.join_next_with_id().await {
+            let err: Box<dyn std::error::Error> = match result {
+                Ok((id, result)) => {
+                    self.pending.remove(&id);
+                    match result {
+                        Ok(()) => continue,
+                        Err(err) => err,
+                    }
+                }
+                Err(err) if err.is_cancelled() => {
+                    if let Some(uri) = self.pending.remove(&err.id()) {
+                        warn!("Cancelled acquiring {}", redact_sas(&uri));
+                        let failure =
+                            Message::build_uri_failure(&uri, "Cancelled as the method is exiting")
+                                .with_header("Transient-Failure", "true");
+                        metrics::record_failure(failure.fail_reason());
+                        send(&failure);
+                    }
+                    continue;
+                }
+                Err(err) => err.into(),
+            };
+            first_error.get_or_insert(err);
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Build a transient failure for an acquisition skipped because the
+    // failure budget is exhausted.
+    fn budget_exhausted(message: &Message) -> Result<Message, AcquireError> {
+        let uri = message.uri()?;
+        warn!("Failure budget exhausted, skipping {}", redact_sas(uri));
+        Ok(Message::build_uri_failure(uri, "Failure budget exhausted")
+            .with_header("Transient-Failure", "true"))
+    }
+
+    // The offset to resume a download from, given the size of the blob and
+    // when it was last modified. A partial file left by an earlier attempt is
+    // resumed if it's shorter than the blob and, as with apt's http method,
+    // no older than it, so that part of an earlier version isn't joined to
+    // the rest of this one. Anything else, including a symlink, is
+    // downloaded afresh.
+    fn resume_point(filename: &str, size: u64, last_modified: OffsetDateTime) -> u64 {
+        let metadata = match std::fs::symlink_metadata(filename) {
+            Ok(metadata) if metadata.is_file() && metadata.len() < size => metadata,
+            _ => return 0,
+        };
+        match metadata.modified() {
+            Ok(modified) if OffsetDateTime::from(modified) >= last_modified => metadata.len(),
+            _ => {
+                debug!("Not resuming {}, as it's older than the blob", filename);
+                0
+            }
+        }
+    }
+
+    /// Acquire a URI outside of apt, for troubleshooting, with the same
+    /// authentication and download as for a URI Acquire message. Neither
+    /// state kept between runs, the shared store nor hooks are used, so it's
+    /// always fetched, in full.
+    pub async fn fetch(
+        config: &Config,
+        uri: &str,
+        filename: &str,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let mut config = config.clone();
+        config.post_download_hook = None;
+        config.shared_store = None;
+        bandwidth::set_rate(config.dl_limit);
+        let message = Message::new(
+            MessageType::URIAcquire,
+            vec![("URI", uri), ("Filename", filename)],
+        );
+        // A file already at the destination isn't a download of the blob
+        // left part way, as apt's partial files are, so it's never resumed.
+        if std::fs::symlink_metadata(filename).is_ok_and(|metadata| metadata.is_file()) {
+            tokio::fs::remove_file(filename).await?;
+        }
+        let azure_registry = AzureRegistry::new(&config)?;
+        let (egress, profile, etags) = Default::default();
+        let acquisition =
+            Self::uri_acquire(&azure_registry, &config, &egress, &profile, &etags, message);
+        logging::with_acquisition(Some(uri.to_string()), acquisition)
+            .await
+            .map_err(|err| err as Box<dyn std::error::Error>)
+    }
+
+    // Start getting tokens for the accounts in apt's source lists in the
+    // background, so that the first acquisitions from them don't wait for
+    // one. Any failure is left for those acquisitions to report.
+    fn prefetch_tokens(&self) {
+        for uri in sources::blob_uris(&self.config) {
+            let log_uri = redact_sas(uri.as_str());
+            let blob = match self.azure_registry.get_blob(&uri, None, &self.config) {
+                Ok(blob) => blob,
+                Err(err) => {
+                    debug!("Not prefetching a token for {}: {}", log_uri, err);
+                    continue;
+                }
+            };
+            debug!("Prefetching a token for {}", log_uri);
+            task::spawn(async move {
+                if let Err(err) = blob.prefetch_token().await {
+                    debug!("Failed to prefetch a token for {}: {}", log_uri, err);
+                }
+            });
+        }
+    }
+
+    // Set the modification time of a downloaded file.
+    fn set_modified(filename: &str, modified: OffsetDateTime) -> std::io::Result<()> {
+        std::fs::File::options()
+            .write(true)
+            .open(filename)?
+            .set_modified(modified.into())
+    }
+
     pub async fn uri_acquire(
-        &self,
+        azure_registry: &AzureRegistry,
+        config: &Config,
+        egress: &EgressCounter,
+        profile: &PerformanceProfile,
+        etags: &ETagStore,
         message: Message,
-    ) -> Result<Message, Box<dyn std::error::Error>> {
+    ) -> Result<Message, AcquireError> {
         // Get the URI. It's part of the interface to have this field here,
         // so a missing URI is a terminal error.
-        let uri = message.uri()?;
-        info!("Acquiring URI: {}", uri);
+        let context = UriContext::new(message.uri()?).with_hints(config.failure_hints);
+        // Steps that fail give the response to send apt as their error.
+        let response = Self::acquire(
+            &context,
+            azure_registry,
+            config,
+            egress,
+            profile,
+            etags,
+            &message,
+        )
+        .await;
+        Ok(response.unwrap_or_else(|failure| failure))
+    }
+
+    async fn acquire(
+        context: &UriContext<'_>,
+        azure_registry: &AzureRegistry,
+        config: &Config,
+        egress: &EgressCounter,
+        profile: &PerformanceProfile,
+        etags: &ETagStore,
+        message: &Message,
+    ) -> Result<Message, Message> {
+        let uri = context.uri();
+        // apt matches responses to requests by URI, so it's sent back as is,
+        // but any SAS token in it is kept out of the log.
+        let log_uri = redact_sas(uri);
+        info!("Acquiring URI: {}", log_uri);
+        if let Some(request_id) = logging::request_id() {
+            info!("Client request ID: {}", request_id);
+        }
 
         // Get the filename to download to.
-        let filename = unwrap_or_urifail!(uri, message.filename());
+        let filename = context.check(Phase::Request, message.filename())?;
         info!("Filename: {}", filename);
 
         // Parse the url.
-        let url = unwrap_or_urifail!(uri, Url::parse(uri));
-        info!("URL: {}", url);
+        let mut url = context.check(Phase::Request, Url::parse(uri))?;
+        context.check(Phase::Request, hostname::normalize(&mut url))?;
+        info!("URL: {}", redact_sas(url.as_str()));
 
-        let blob = unwrap_or_urifail!(uri, self.azure_registry.get_blob(&url));
+        // Start from what earlier runs saw of the host's performance.
+        let host = url.host_str().unwrap_or_default();
+        let tuned = profile.tune(host, config);
+        let config = &*tuned;
+
+        let mut blob = context.check(
+            Phase::Request,
+            azure_registry.get_blob(&url, message.storage_account(), config),
+        )?;
         debug!("AzureBlob: {:?}", blob);
+        logging::set_account(blob.account());
+
+        // Refuse blobs the configured policy doesn't permit before making
+        // any requests for them.
+        if let Err(err) = policy::check(config, &blob.path()) {
+            warn!("Refusing {}: {}", log_uri, err);
+            let message = Message::build_uri_failure(uri, &format!("Denied by policy: {}", err))
+                .with_header("FailReason", "PolicyDenied");
+            return Ok(message);
+        }
+
+        let requested = Instant::now();
+
+        // A snapshot or version requested for this URI takes precedence over
+        // one in the URI itself, which takes precedence over the configured
+        // point in time; otherwise if the repository is pinned to a point in
+        // time, the blob exists if it had a version at that time. Whether the
+        // blob exists and its URI start fields are got together.
+        let info = match (message.blob_snapshot(), message.blob_version_id()) {
+            (Some(_), Some(_)) => {
+                let message = "Only one of Blob-Snapshot and Blob-Version-Id may be given";
+                error!("URI failure for {}: {}", log_uri, message);
+                return Ok(Message::build_uri_failure(uri, message));
+            }
+            (Some(snapshot), None) => {
+                blob.pin_snapshot(snapshot);
+                context.check(Phase::Properties, blob.info().await)?
+            }
+            (None, Some(version_id)) => {
+                blob.pin_version(version_id);
+                context.check(Phase::Properties, blob.info().await)?
+            }
+            (None, None) if blob.is_pinned() => {
+                context.check(Phase::Properties, blob.info().await)?
+            }
+            (None, None) => match config.as_of {
+                Some(as_of)
+                    if !context.check(Phase::Properties, blob.pin_as_of(as_of).await)? =>
+                {
+                    None
+                }
+                _ => context.check(Phase::Properties, blob.info().await)?,
+            },
+        };
+        let Some(info) = info else {
+            // Optional files (Translations, Contents, ...) are expected to be
+            // missing from many repositories; apt ignores the failure, so
+            // don't make noise about it.
+            if message.fail_ignore() {
+                info!("Optional blob doesn't exist: {}", log_uri);
+            } else {
+                warn!("Blob doesn't exist! {}", log_uri);
+            }
+            // Report the failure the same way the http method does for a 404
+            let text = match config.failure_hints {
+                true => {
+                    "Blob does not exist; hint: check the account, container and path in \
+                         the source list"
+                }
+                false => "Blob does not exist",
+            };
+            let message =
+                Message::build_uri_failure(uri, text).with_header("FailReason", "HttpError404");
+            return Ok(message);
+        };
+
+        // The time the blob's properties took is the latency of a request,
+        // unless versions had to be listed first.
+        let latency = requested.elapsed();
+
+        info!("Blob size: {}", info.size);
+        info!("Last modified: {}", info.last_modified);
+
+        // Refuse blobs larger than the policy allows for them before
+        // downloading any of them.
+        if let Err(err) = policy::check_size(config, &blob.path(), info.size) {
+            warn!("Refusing {}: {}", log_uri, err);
+            let message = Message::build_uri_failure(uri, &format!("Denied by policy: {}", err))
+                .with_header("FailReason", "PolicyDenied");
+            return Ok(message);
+        }
+
+        // In a dry run, stop once the blob is known to be accessible, without
+        // downloading or writing anything. The acquisition fails, so that apt
+        // doesn't take the file it would have been written to.
+        if config.dry_run {
+            let last_modified = azure_core::date::to_rfc1123(&info.last_modified);
+            send(&Message::build_uri_start(
+                uri,
+                info.size,
+                Some(&last_modified),
+                0,
+            ));
+            info!("Dry run, not downloading {}", log_uri);
+            let message = Message::build_uri_failure(
+                uri,
+                &format!("Dry run: blob is accessible ({} bytes)", info.size),
+            )
+            .with_header("FailReason", "DryRun");
+            return Ok(message);
+        }
 
-        let blob_exists = unwrap_or_urifail!(uri, blob.exists().await);
-        if !blob_exists {
-            warn!("Blob doesn't exist! {}", uri);
-            let message = Message::build_uri_failure(uri, "Blob does not exist");
+        // Leave apt's copy of the file be if the blob hasn't changed since it
+        // was downloaded. A Last-Modified time which can't be relied on isn't
+        // compared; the blob's ETag is, with the one recorded when apt's copy
+        // was downloaded, if there is one.
+        let now = OffsetDateTime::now_utc();
+        if let Some(since) = message.last_modified() {
+            let unchanged = if freshness::is_suspicious(info.last_modified, now) {
+                etags.get(uri).is_some_and(|etag| etag == info.etag)
+            } else {
+                info.last_modified <= since
+            };
+            if unchanged {
+                info!("Not modified since {}: {}", since, log_uri);
+                let message = Message::new(
+                    MessageType::URIDone,
+                    vec![("URI", uri), ("Filename", filename), ("IMS-Hit", "true")],
+                );
+                return Ok(message);
+            }
+        }
+
+        // While quiesced, keep the indexes apt has rather than downloading
+        // changes to them, and put off downloading large packages.
+        if let Some(reason) = quiesce::reason() {
+            let have_copy = message.last_modified().is_some();
+            match quiesce::action(config, is_index(&url), have_copy, info.size) {
+                Action::Fetch => {}
+                Action::Keep => {
+                    info!("Quiesced, as {}; keeping apt's copy of {}", reason, log_uri);
+                    let message = Message::new(
+                        MessageType::URIDone,
+                        vec![("URI", uri), ("Filename", filename), ("IMS-Hit", "true")],
+                    );
+                    return Ok(message);
+                }
+                Action::Defer => {
+                    info!("Quiesced, as {}; putting off {}", reason, log_uri);
+                    let message = Message::build_uri_failure(
+                        uri,
+                        &format!(
+                            "Quiesced, as {}: {} bytes is more than {} bytes",
+                            reason, info.size, config.quiesce_max_size
+                        ),
+                    )
+                    .with_header("FailReason", "Quiesced")
+                    .with_header("Transient-Failure", "true");
+                    return Ok(message);
+                }
+            }
+        }
+
+        // Archived blobs can't be downloaded until they're rehydrated, which
+        // takes hours, so fail before starting; apt can try again once it's
+        // been asked for.
+        if info.archived {
+            warn!("Blob is archived: {}", log_uri);
+            if !config.rehydrate_archived {
+                let message = Message::build_uri_failure(
+                    uri,
+                    "Blob is archived; it must be rehydrated before it can be downloaded",
+                )
+                .with_header("FailReason", "BlobArchived");
+                return Ok(message);
+            }
+            let message = match context.check(Phase::Rehydrate, blob.rehydrate().await)? {
+                true => "Blob is archived; rehydration has been requested",
+                false => "Blob is archived and being rehydrated",
+            };
+            info!("{}: {}", message, log_uri);
+            let message = Message::build_uri_failure(uri, message)
+                .with_header("FailReason", "BlobArchived")
+                .with_header("Transient-Failure", "true");
             return Ok(message);
         }
 
-        // Get the blob's URI start fields.
-        let (size, last_modified) = unwrap_or_urifail!(uri, blob.uri_start_fields().await);
+        // An index that's too small has most likely been caught part way
+        // through publishing; fail transiently so apt tries it again.
+        if let Some(min_index_size) = config.min_index_size {
+            if info.size < min_index_size && is_index(&url) {
+                warn!(
+                    "Index {} is {} bytes, smaller than the minimum of {}",
+                    log_uri, info.size, min_index_size
+                );
+                let message = Message::build_uri_failure(uri, "Index is too small")
+                    .with_header("Transient-Failure", "true");
+                return Ok(message);
+            }
+        }
+
+        // Indexes compress well, so cut the bytes `apt update` transfers
+        // through a proxy or CDN which compresses responses by asking for
+        // them to be compressed in transit. Blobs stored compressed are
+        // fetched as they are.
+        if config.compress_indexes && is_index(&url) && info.content_encoding.is_none() {
+            blob.compress_in_transit();
+        }
+
+        // Fetch the content of the blob the properties above are for, not a
+        // replacement uploaded in the meantime.
+        blob.pin_etag(&info.etag);
 
-        info!("Blob size: {}", size);
-        info!("Last modified: {}", last_modified);
+        // Take the file from the shared store if a machine sharing it has
+        // already downloaded it, or claim downloading it for the others.
+        // Only content apt gives the SHA256 of can be looked up.
+        let store = config.shared_store.as_deref().map(SharedStore::new);
+        let expected_sha256 = message
+            .expected_hashes()
+            .into_iter()
+            .find(|(hash_type, _)| hash_type.eq_ignore_ascii_case("SHA256"))
+            .map(|(_, sha256)| sha256);
+        let lookup = match (&store, expected_sha256) {
+            (Some(store), Some(sha256)) => {
+                store.get(sha256, filename).await.unwrap_or_else(|err| {
+                    warn!("Failed to look {} up in the shared store: {}", log_uri, err);
+                    Lookup::Miss
+                })
+            }
+            _ => Lookup::Miss,
+        };
+        let (stored, claim) = match lookup {
+            Lookup::Hit(hashes) => (Some(hashes), None),
+            Lookup::Claimed(claim) => (None, Some(claim)),
+            Lookup::Miss => (None, None),
+        };
+
+        // Pick up from where an earlier, interrupted download left off.
+        let resume_from = match stored {
+            Some(_) => 0,
+            None => Self::resume_point(filename, info.size, info.last_modified),
+        };
+        if resume_from > 0 {
+            info!("Resuming {} from {} bytes", log_uri, resume_from);
+        }
 
         // Send a URI Start to indicate we're starting the transfer.
-        Message::send_uri_start(uri, size, &last_modified);
-        info!("Sent URI start: {}", last_modified);
+        let modified =
+            freshness::reported_last_modified(config.suspicious_last_modified, &info, now);
+        let last_modified = modified.as_ref().map(azure_core::date::to_rfc1123);
+        send(&Message::build_uri_start(
+            uri,
+            info.size,
+            last_modified.as_deref(),
+            resume_from,
+        ));
+        info!("Sent URI start: {:?}", last_modified);
 
-        // Now actually download the URI
-        let contents = unwrap_or_urifail!(uri, blob.download().await);
+        // Now actually download the URI, streaming it straight to the file
+        let hashes = match stored {
+            Some(hashes) => {
+                info!("Copied {} from the shared store", log_uri);
+                hashes
+            }
+            None => {
+                let mut progress = Progress::new(uri, info.size);
+                let started = Instant::now();
+                let hashes = context.check(
+                    Phase::Download,
+                    blob.download_to_file(filename, info.size, resume_from, config, &mut progress)
+                        .await,
+                )?;
+                info!("Downloaded blob: {} ({} bytes)", log_uri, hashes.size);
+                egress
+                    .record(blob.account(), hashes.size - resume_from)
+                    .await;
+                metrics::record_download(
+                    blob.account(),
+                    hashes.size - resume_from,
+                    started.elapsed(),
+                );
+                profile
+                    .record(host, latency, hashes.size - resume_from, started.elapsed())
+                    .await;
+                hashes
+            }
+        };
 
-        info!("Downloaded blob: {}", uri);
-        // Write the contents to the file
-        unwrap_or_urifail!(uri, std::fs::write(filename, contents));
+        // Storage that silently corrupts writes leaves a file that doesn't
+        // hash the same as what was written; fail transiently so apt
+        // downloads it again.
+        if config.verify_written {
+            let written = context.check(Phase::Verify, hashes::hash_file(filename).await)?;
+            if written != hashes {
+                error!(
+                    "{} doesn't match what was written: expected SHA256 {}, got {}",
+                    filename, hashes.sha256, written.sha256
+                );
+                if let Err(err) = std::fs::remove_file(filename) {
+                    warn!("Failed to remove {}: {}", filename, err);
+                }
+                let message = Message::build_uri_failure(
+                    uri,
+                    &format!(
+                        "Write corrupted: {} doesn't match what was written",
+                        filename
+                    ),
+                )
+                .with_header("FailReason", "HashSumMismatch")
+                .with_header("Transient-Failure", "true");
+                return Ok(message);
+            }
+        }
 
-        // Create a success response
-        let message = Message::new(
-            MessageType::URIDone,
-            vec![("URI", uri), ("Filename", filename)],
-        );
+        // A file that doesn't match the blob's Content-MD5 was corrupted or
+        // cut short on the way; fail transiently so apt downloads it again.
+        if let Some(md5) = info.content_md5.as_ref().filter(|md5| **md5 != hashes.md5) {
+            error!(
+                "Content-MD5 mismatch for {}: expected {}, got {}",
+                log_uri, md5, hashes.md5
+            );
+            if let Err(err) = std::fs::remove_file(filename) {
+                warn!("Failed to remove {}: {}", filename, err);
+            }
+            let message = Message::build_uri_failure(
+                uri,
+                &format!(
+                    "Download corrupted: Content-MD5 mismatch: expected {}, got {}",
+                    md5, hashes.md5
+                ),
+            )
+            .with_header("FailReason", "HashSumMismatch")
+            .with_header("Transient-Failure", "true");
+            return Ok(message);
+        }
+
+        // Don't hand apt a file that doesn't match what it asked for.
+        if let Err(mismatch) = hashes.verify(message.expected_hashes()) {
+            error!("Hash Sum mismatch for {}: {}", log_uri, mismatch);
+            if let Err(err) = std::fs::remove_file(filename) {
+                warn!("Failed to remove {}: {}", filename, err);
+            }
+            let message =
+                Message::build_uri_failure(uri, &format!("Hash Sum mismatch: {}", mismatch))
+                    .with_header("FailReason", "HashSumMismatch");
+            return Ok(message);
+        }
+
+        // Give the hook a chance to check the file before it's handed over.
+        if let Err(err) = hooks::post_download(config, &log_uri, filename, &hashes).await {
+            match config.hook_failure {
+                HookFailure::Fail => {
+                    error!("Post-download hook rejected {}: {}", log_uri, err);
+                    if let Err(err) = std::fs::remove_file(filename) {
+                        warn!("Failed to remove {}: {}", filename, err);
+                    }
+                    let message = Message::build_uri_failure(
+                        uri,
+                        &format!("Rejected by post-download hook: {}", err),
+                    );
+                    return Ok(message);
+                }
+                HookFailure::Ignore => warn!("Post-download hook failed for {}: {}", log_uri, err),
+            }
+        }
+
+        // Share the verified file with the machines waiting for it.
+        if let (Some(store), Some(claim)) = (&store, claim) {
+            if let Err(err) = store.insert(claim, filename).await {
+                warn!("Failed to add {} to the shared store: {}", log_uri, err);
+            }
+        }
+
+        etags.record(uri, &info.etag).await;
+
+        // apt compares its copy's modification time with the blob's on later
+        // runs, so give the file the blob's rather than the time it was
+        // downloaded.
+        if let Some(modified) = modified {
+            if let Err(err) = Self::set_modified(filename, modified) {
+                warn!(
+                    "Failed to set the modification time of {}: {}",
+                    filename, err
+                );
+            }
+        }
+
+        // Create a success response, including hashes for apt to verify.
+        let size = hashes.size.to_string();
+        let mut headers = vec![("URI", uri), ("Filename", filename), ("Size", &size)];
+        if let Some(last_modified) = &last_modified {
+            headers.push(("Last-Modified", last_modified));
+        }
+        if let Some(md5) = &info.content_md5 {
+            headers.push(("MD5Sum-Hash", md5));
+        }
+        headers.extend(hashes.headers());
+        let message = Message::new(MessageType::URIDone, headers);
         Ok(message)
     }
 }
@@ -111,21 +945,205 @@ impl Processor {
 mod tests {
     use super::*;
     use crate::tests::init_logger;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_configuration() -> Result<(), Box<dyn std::error::Error>> {
         init_logger();
         let message = Message::new(MessageType::Configuration, vec![]);
-        let processor = Processor::new()?;
+        let mut processor = Processor::new()?;
         processor.process(message).await?;
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_acquire_without_uri() -> Result<(), Box<dyn std::error::Error>> {
+        init_logger();
+        let message = Message::new(MessageType::URIAcquire, vec![("Filename", "/tmp/x")]);
+        let mut processor = Processor::new()?;
+        processor.process(message).await?;
+        assert!(processor.finish().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_acquire_does_not_block() -> Result<(), Box<dyn std::error::Error>> {
+        init_logger();
+        let mut processor = Processor::new()?;
+
+        // Fill the pipeline; further acquisitions must still be accepted.
+        let held = processor
+            .scheduler
+            .slots
+            .clone()
+            .acquire_many_owned(10)
+            .await?;
+        for _ in 0..3 {
+            let message = Message::new(MessageType::URIAcquire, vec![("Filename", "/tmp/x")]);
+            tokio::time::timeout(Duration::from_secs(5), processor.process(message)).await??;
+        }
+        assert_eq!(processor.acquisitions.len(), 3);
+
+        drop(held);
+        assert!(processor.finish().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finish_cancels_after_drain_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        init_logger();
+        let mut processor = Processor::new()?;
+        let mut config = Config::default();
+        config.drain_timeout = Duration::from_millis(100);
+        processor.config = Arc::new(config);
+
+        // Acquisitions waiting for a slot never finish by themselves.
+        let _held = processor
+            .scheduler
+            .slots
+            .clone()
+            .acquire_many_owned(10)
+            .await?;
+        for name in ["a", "b"] {
+            let uri = format!("blob://account/container/{}", name);
+            let message = Message::new(
+                MessageType::URIAcquire,
+                vec![("URI", uri.as_str()), ("Filename", "/tmp/x")],
+            );
+            processor.process(message).await?;
+        }
+        assert_eq!(processor.pending.len(), 2);
+
+        tokio::time::timeout(Duration::from_secs(5), processor.finish()).await??;
+        assert!(processor.acquisitions.is_empty());
+        assert!(processor.pending.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel() -> Result<(), Box<dyn std::error::Error>> {
+        init_logger();
+        let mut processor = Processor::new()?;
+
+        // Acquisitions waiting for a slot never finish by themselves.
+        let _held = processor
+            .scheduler
+            .slots
+            .clone()
+            .acquire_many_owned(10)
+            .await?;
+        let message = Message::new(
+            MessageType::URIAcquire,
+            vec![
+                ("URI", "blob://account/container/a"),
+                ("Filename", "/tmp/x"),
+            ],
+        );
+        processor.process(message).await?;
+        assert_eq!(processor.pending.len(), 1);
+
+        // Nothing's waited for, however long the drain timeout is.
+        tokio::time::timeout(Duration::from_secs(5), processor.cancel()).await??;
+        assert!(processor.acquisitions.is_empty());
+        assert!(processor.pending.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scheduler() -> Result<(), AcquireError> {
+        let mut config = Config::default();
+        config.pipeline_depth = 3;
+        config.account_pipeline_depth = Some(2);
+        let scheduler = Scheduler::new(&config);
+        let wait = Duration::from_millis(100);
+
+        let first = scheduler.admit("a").await?;
+        let _second = scheduler.admit("a").await?;
+        // The account's at its limit, though there's a slot left in all.
+        assert!(tokio::time::timeout(wait, scheduler.admit("a"))
+            .await
+            .is_err());
+        let _other = scheduler.admit("b").await?;
+        // Now there are no slots left at all.
+        assert!(tokio::time::timeout(wait, scheduler.admit("c"))
+            .await
+            .is_err());
+        drop(first);
+        let _third = tokio::time::timeout(wait, scheduler.admit("a")).await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_of() {
+        let mut config = Config::default();
+        let acquire = |headers| Message::new(MessageType::URIAcquire, headers);
+        let message = acquire(vec![("URI", "blob://Account.blob.core.windows.net/c/b")]);
+        assert_eq!(account_of(&message, &config), "account");
+        let message = acquire(vec![
+            ("URI", "blob://account.blob.core.windows.net/c/b"),
+            ("Storage-Account", "other"),
+        ]);
+        assert_eq!(account_of(&message, &config), "other");
+        config.emulator = true;
+        let message = acquire(vec![("URI", "blob://127.0.0.1:10000/devaccount/c/b")]);
+        assert_eq!(account_of(&message, &config), "devaccount");
+    }
+
+    #[test]
+    fn test_is_index() {
+        let is_index = |url| is_index(&Url::parse(url).unwrap());
+        assert!(is_index("blob://a/c/dists/stable/Release"));
+        assert!(is_index(
+            "blob://a/c/dists/stable/main/binary-amd64/Packages.xz"
+        ));
+        assert!(is_index("blob://a/c?sig=x/dists/stable/InRelease"));
+        assert!(!is_index(
+            "blob://a/c/pool/main/h/hello/hello_1.0_amd64.deb"
+        ));
+    }
+
+    #[test]
+    fn test_resume_point() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("partial");
+        let filename = path.to_str().unwrap();
+
+        let last_modified = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        assert_eq!(Processor::resume_point(filename, 100, last_modified), 0);
+        std::fs::write(&path, b"hello")?;
+        assert_eq!(Processor::resume_point(filename, 100, last_modified), 5);
+        assert_eq!(Processor::resume_point(filename, 5, last_modified), 0);
+        assert_eq!(Processor::resume_point(filename, 3, last_modified), 0);
+
+        // A partial of an earlier version of the blob is started again.
+        Processor::set_modified(filename, last_modified - time::Duration::days(1))?;
+        assert_eq!(Processor::resume_point(filename, 100, last_modified), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_modified() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Release");
+        std::fs::write(&path, b"hello")?;
+        let modified = OffsetDateTime::from_unix_timestamp(1716984000)?;
+        Processor::set_modified(path.to_str().unwrap(), modified)?;
+        assert_eq!(
+            std::fs::metadata(&path)?.modified()?,
+            std::time::SystemTime::from(modified)
+        );
+        assert!(
+            Processor::set_modified(dir.path().join("missing").to_str().unwrap(), modified)
+                .is_err()
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_unknown() -> Result<(), Box<dyn std::error::Error>> {
         init_logger();
         let message = Message::new(MessageType::Log, vec![]);
-        let processor = Processor::new()?;
+        let mut processor = Processor::new()?;
         processor.process(message).await?;
         Ok(())
     }
@@ -0,0 +1,367 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde_json::json;
+
+use crate::atomic;
+use crate::config::Config;
+
+// How much each new measurement moves a host's figures, so they follow
+// changes in its performance without jumping about with every download.
+const SMOOTHING: f64 = 0.3;
+
+// Downloads smaller than this are dominated by latency, so say little about
+// a host's throughput.
+const MIN_THROUGHPUT_SAMPLE: u64 = 1024 * 1024;
+
+// Chunks are sized to take about this long each at a host's throughput.
+const CHUNK_TIME: Duration = Duration::from_secs(2);
+
+// Bounds on the chunk sizes chosen, which are whole MiBs.
+const MIB: u64 = 1024 * 1024;
+const MIN_CHUNK_SIZE: u64 = MIB;
+const MAX_CHUNK_SIZE: u64 = 64 * MIB;
+
+// Each this much latency to a host calls for another chunk in flight at once,
+// so the time spent waiting for responses overlaps.
+const LATENCY_PER_CHUNK: Duration = Duration::from_millis(50);
+
+// Bounds on the chunks in flight at once for a single blob.
+const MIN_CHUNK_PARALLELISM: usize = 2;
+const MAX_CHUNK_PARALLELISM: usize = 16;
+
+// Requests are given this many times as long as they should take at a host's
+// throughput before timing out, within bounds.
+const TIMEOUT_MARGIN: f64 = 10.0;
+const MIN_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The throughput and latency seen for each storage host, kept in a file
+/// between runs so that a run can start with chunk sizes, parallelism and
+/// timeouts suited to each host, rather than the defaults. Options which are
+/// configured aren't tuned.
+#[derive(Debug, Default)]
+pub struct PerformanceProfile {
+    path: Option<PathBuf>,
+    // The hosts' figures as they were when the run started, which options
+    // are tuned with.
+    hosts: BTreeMap<String, HostProfile>,
+    // Held while the file is updated, so concurrent downloads don't lose
+    // each other's measurements.
+    lock: Arc<Mutex<()>>,
+}
+
+// What's been seen of a host's performance.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct HostProfile {
+    // Bytes per second downloads from the host achieve, once a download
+    // large enough to tell has been made.
+    throughput: Option<f64>,
+    // Seconds the host takes to answer a request.
+    latency: f64,
+}
+
+impl PerformanceProfile {
+    /// Load the profile kept in the given file; with no file, nothing is
+    /// tuned or kept. A file which can't be read is logged and treated as
+    /// empty.
+    pub fn load(path: Option<&str>) -> Self {
+        let Some(path) = path.map(PathBuf::from) else {
+            return PerformanceProfile::default();
+        };
+        let hosts = match read_hosts(&path) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                warn!(
+                    "Failed to read performance profile {}: {}",
+                    path.display(),
+                    err
+                );
+                BTreeMap::new()
+            }
+        };
+        PerformanceProfile {
+            path: Some(path),
+            hosts,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The configuration to download from the host with: the given one, with
+    /// any chunk size, parallelism and timeout which aren't configured tuned
+    /// to what's been seen of the host.
+    pub fn tune<'a>(&self, host: &str, config: &'a Config) -> Cow<'a, Config> {
+        let Some(profile) = self.hosts.get(host) else {
+            return Cow::Borrowed(config);
+        };
+        let mut tuned = config.clone();
+        profile.apply(&mut tuned);
+        if tuned == *config {
+            return Cow::Borrowed(config);
+        }
+        info!(
+            "Tuned for {}: chunk size {}, chunk parallelism {}, timeout {:?}",
+            host, tuned.chunk_size, tuned.chunk_parallelism, tuned.timeout
+        );
+        Cow::Owned(tuned)
+    }
+
+    /// Record how long a request to the host took to be answered, and the
+    /// bytes downloaded from it in the given time. Failing to update the file
+    /// is logged, but doesn't fail the download.
+    pub async fn record(&self, host: &str, latency: Duration, bytes: u64, elapsed: Duration) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let throughput = (bytes >= MIN_THROUGHPUT_SAMPLE && !elapsed.is_zero())
+            .then(|| bytes as f64 / elapsed.as_secs_f64());
+        let (file, name, latency) = (path.clone(), host.to_string(), latency.as_secs_f64());
+        let updated = atomic::update(&self.lock, move || {
+            Self::update(&file, &name, latency, throughput)
+        })
+        .await;
+        match updated {
+            Ok(profile) => debug!("Performance of {}: {:?}", host, profile),
+            Err(err) => warn!(
+                "Failed to update performance profile {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    // Fold the measurements into the host's figures in the file, returning
+    // its new figures.
+    fn update(
+        path: &Path,
+        host: &str,
+        latency: f64,
+        throughput: Option<f64>,
+    ) -> Result<HostProfile, atomic::UpdateError> {
+        let mut hosts = read_hosts(path)?;
+        let profile = match hosts.get(host) {
+            Some(profile) => HostProfile {
+                throughput: match (profile.throughput, throughput) {
+                    (Some(old), Some(new)) => Some(smooth(old, new)),
+                    (old, new) => new.or(old),
+                },
+                latency: smooth(profile.latency, latency),
+            },
+            None => HostProfile {
+                throughput,
+                latency,
+            },
+        };
+        hosts.insert(host.to_string(), profile);
+
+        atomic::write(path, to_json(&hosts))?;
+        Ok(profile)
+    }
+}
+
+impl HostProfile {
+    // Tune the options which aren't configured to the host.
+    fn apply(&self, config: &mut Config) {
+        let latency = Duration::from_secs_f64(self.latency);
+        if !config.is_set("Acquire::blob::Chunk-Parallelism") {
+            let chunks = latency.div_duration_f64(LATENCY_PER_CHUNK).ceil() as usize;
+            config.chunk_parallelism = chunks.clamp(MIN_CHUNK_PARALLELISM, MAX_CHUNK_PARALLELISM);
+        }
+        let Some(throughput) = self.throughput else {
+            return;
+        };
+        if !config.is_set("Acquire::blob::Chunk-Size") {
+            let size = (throughput * CHUNK_TIME.as_secs_f64()) as u64 / MIB * MIB;
+            config.chunk_size = size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        }
+        if !config.is_set("Acquire::blob::Timeout") {
+            // Each of the chunks in flight gets its share of the throughput.
+            let share = throughput / config.chunk_parallelism.max(1) as f64;
+            let expected = self.latency + config.chunk_size as f64 / share;
+            let timeout = Duration::from_secs((expected * TIMEOUT_MARGIN).ceil() as u64);
+            config.timeout = Some(timeout.clamp(MIN_TIMEOUT, MAX_TIMEOUT));
+        }
+    }
+}
+
+fn smooth(old: f64, new: f64) -> f64 {
+    old + (new - old) * SMOOTHING
+}
+
+fn read_hosts(path: &Path) -> Result<BTreeMap<String, HostProfile>, atomic::UpdateError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse(&contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn parse(contents: &str) -> Result<BTreeMap<String, HostProfile>, atomic::UpdateError> {
+    let value: serde_json::Value = serde_json::from_str(contents)?;
+    Ok(value["hosts"]
+        .as_object()
+        .ok_or("No hosts")?
+        .iter()
+        .filter_map(|(host, profile)| {
+            let profile = HostProfile {
+                throughput: profile["throughput"].as_f64(),
+                latency: profile["latency"].as_f64()?,
+            };
+            Some((host.clone(), profile))
+        })
+        .collect())
+}
+
+fn to_json(hosts: &BTreeMap<String, HostProfile>) -> String {
+    let hosts: serde_json::Map<_, _> = hosts
+        .iter()
+        .map(|(host, profile)| {
+            let profile = json!({
+                "throughput": profile.throughput,
+                "latency": profile.latency,
+            });
+            (host.clone(), profile)
+        })
+        .collect();
+    json!({ "hosts": hosts }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageType};
+
+    fn configured(items: Vec<&str>) -> Config {
+        let message = Message::new(
+            MessageType::Configuration,
+            items
+                .into_iter()
+                .map(|item| ("Config-Item", item))
+                .collect(),
+        );
+        Config::from_message(&message).unwrap()
+    }
+
+    #[test]
+    fn test_update() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("profile").join("profile.json");
+
+        let profile = PerformanceProfile::update(&path, "a", 0.1, None)?;
+        assert_eq!(
+            profile,
+            HostProfile {
+                throughput: None,
+                latency: 0.1
+            }
+        );
+        let profile = PerformanceProfile::update(&path, "a", 0.2, Some(1000.0))?;
+        assert_eq!(profile.throughput, Some(1000.0));
+        assert!((profile.latency - 0.13).abs() < 1e-9);
+        let profile = PerformanceProfile::update(&path, "a", 0.13, Some(2000.0))?;
+        assert_eq!(profile.throughput, Some(1300.0));
+        PerformanceProfile::update(&path, "b", 0.5, None)?;
+
+        let hosts = read_hosts(&path)?;
+        assert_eq!(hosts["a"], profile);
+        assert_eq!(hosts["b"].latency, 0.5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("profile.json");
+
+        // Without a file nothing is kept.
+        PerformanceProfile::load(None)
+            .record("a", Duration::from_millis(100), 0, Duration::ZERO)
+            .await;
+
+        let profile = PerformanceProfile::load(path.to_str());
+        let second = Duration::from_secs(1);
+        profile
+            .record("a", Duration::from_millis(100), 1000, second)
+            .await;
+        assert_eq!(read_hosts(&path)?["a"].throughput, None);
+        profile
+            .record("a", Duration::from_millis(100), 4 * MIB, second)
+            .await;
+        assert_eq!(read_hosts(&path)?["a"].throughput, Some(4.0 * MIB as f64));
+
+        // The profile is used from the next run.
+        assert!(PerformanceProfile::load(path.to_str())
+            .hosts
+            .contains_key("a"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_invalid() -> Result<(), atomic::UpdateError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("profile.json");
+        std::fs::write(&path, "not json")?;
+        assert!(PerformanceProfile::load(path.to_str()).hosts.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tune() {
+        let mut profile = PerformanceProfile::default();
+        let config = Config::default();
+        assert!(matches!(profile.tune("a", &config), Cow::Borrowed(_)));
+
+        // 40 MiB/s, 200ms away.
+        profile.hosts.insert(
+            "a".to_string(),
+            HostProfile {
+                throughput: Some(40.0 * MIB as f64),
+                latency: 0.2,
+            },
+        );
+        let tuned = profile.tune("a", &config);
+        assert_eq!(tuned.chunk_parallelism, 4);
+        assert_eq!(tuned.chunk_size, 64 * MIB);
+        assert_eq!(tuned.timeout, Some(Duration::from_secs(66)));
+
+        // Slow and close by.
+        profile.hosts.insert(
+            "b".to_string(),
+            HostProfile {
+                throughput: Some(1.5 * MIB as f64),
+                latency: 0.01,
+            },
+        );
+        let tuned = profile.tune("b", &config);
+        assert_eq!(tuned.chunk_parallelism, MIN_CHUNK_PARALLELISM);
+        assert_eq!(tuned.chunk_size, 3 * MIB);
+        assert_eq!(tuned.timeout, Some(Duration::from_secs(41)));
+
+        // Only latency is known.
+        profile.hosts.insert(
+            "c".to_string(),
+            HostProfile {
+                throughput: None,
+                latency: 2.0,
+            },
+        );
+        let tuned = profile.tune("c", &config);
+        assert_eq!(tuned.chunk_parallelism, MAX_CHUNK_PARALLELISM);
+        assert_eq!(tuned.chunk_size, config.chunk_size);
+        assert_eq!(tuned.timeout, None);
+
+        // Configured options are left alone.
+        let config = configured(vec![
+            "Acquire::blob::Chunk-Size=1048576",
+            "Acquire::blob::Chunk-Parallelism=1",
+            "Acquire::blob::Timeout=5",
+        ]);
+        assert!(matches!(profile.tune("a", &config), Cow::Borrowed(_)));
+    }
+}
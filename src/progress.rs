@@ -0,0 +1,93 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::credentials::redact_sas;
+use crate::message::{Message, MessageType};
+use crate::processor;
+
+// The least time between reports of a download's progress.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reports how far a download has got to apt with `102 Status` messages, at
+/// most once a second, so that large downloads aren't silent and hung ones
+/// can be told apart from slow ones.
+pub struct Progress {
+    uri: String,
+    size: u64,
+    last_report: Instant,
+}
+
+impl Progress {
+    /// Track the download of the URI, of a blob of the given size.
+    pub fn new(uri: &str, size: u64) -> Self {
+        Progress {
+            uri: uri.to_string(),
+            size,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Note that the download has got to the given byte of the blob,
+    /// reporting it if it's been long enough since the last report.
+    pub fn update(&mut self, position: u64) {
+        if !self.due(Instant::now()) {
+            return;
+        }
+        let message = self.describe(position);
+        info!("{}: {}", redact_sas(&self.uri), message);
+        processor::send(&Message::new(
+            MessageType::Status,
+            vec![("URI", self.uri.as_str()), ("Message", message.as_str())],
+        ));
+    }
+
+    // Whether the progress is to be reported at the given time, in which
+    // case it's taken to have been.
+    fn due(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.last_report) < REPORT_INTERVAL {
+            return false;
+        }
+        self.last_report = now;
+        true
+    }
+
+    fn describe(&self, position: u64) -> String {
+        format!(
+            "Downloaded {} of {} bytes ({}%)",
+            position,
+            self.size,
+            position * 100 / self.size.max(1)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let mut progress = Progress::new("blob://a/c/b", 100);
+        let start = progress.last_report;
+        assert!(!progress.due(start + Duration::from_millis(500)));
+        assert!(progress.due(start + Duration::from_secs(1)));
+        assert!(!progress.due(start + Duration::from_millis(1500)));
+        assert!(progress.due(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_describe() {
+        let progress = Progress::new("blob://a/c/b", 3 * 1024 * 1024 * 1024);
+        assert_eq!(
+            progress.describe(1024 * 1024 * 1024),
+            "Downloaded 1073741824 of 3221225472 bytes (33%)"
+        );
+        assert_eq!(
+            Progress::new("blob://a/c/b", 0).describe(0),
+            "Downloaded 0 of 0 bytes (0%)"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::path::Path;
+use std::sync::RwLock;
+
+use log::debug;
+
+use crate::config::{Config, Quiesce};
+use crate::hooks;
+
+// Where the kernel lists power supplies, batteries among them.
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+// Why the method is quiesced for the run, if it is. It's decided when apt's
+// configuration arrives, and read by each acquisition, so it's kept here
+// rather than threaded through.
+static QUIESCED: RwLock<Option<String>> = RwLock::new(None);
+
+/// What to do with a blob while quiesced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Download it as usual.
+    Fetch,
+    /// Tell apt its copy is up to date, without downloading any changes.
+    Keep,
+    /// Fail transiently, so apt tries again once the method isn't quiesced.
+    Defer,
+}
+
+/// Decide whether to quiesce, as configured: in `auto` mode, when a battery
+/// is discharging at or below the configured charge, or the quiesce check
+/// succeeds. Returns why, if so.
+pub async fn detect(config: &Config) -> Option<String> {
+    match config.quiesce {
+        Quiesce::Off => None,
+        Quiesce::On => Some("it's configured to".to_string()),
+        Quiesce::Auto => {
+            if let Some(charge) = low_battery(Path::new(POWER_SUPPLY_DIR), config.quiesce_battery) {
+                return Some(format!("the battery is discharging at {}%", charge));
+            }
+            match hooks::quiesce_check(config).await {
+                Ok(()) => Some("the quiesce check succeeded".to_string()),
+                Err(err) => {
+                    debug!("Not quiescing: {}", err);
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Set why the method is quiesced for the run, or that it isn't.
+pub fn set(reason: Option<String>) {
+    *QUIESCED.write().unwrap_or_else(|err| err.into_inner()) = reason;
+}
+
+/// Why the method is quiesced, if it is.
+pub fn reason() -> Option<String> {
+    QUIESCED
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// What to do with a blob of the given size while quiesced. Indexes apt has
+/// a copy of are kept as they are, and those it hasn't are downloaded, so
+/// that it has a consistent set. Packages are downloaded if they're no
+/// larger than the configured size, and put off otherwise.
+pub fn action(config: &Config, is_index: bool, have_copy: bool, size: u64) -> Action {
+    match is_index {
+        true if have_copy => Action::Keep,
+        true => Action::Fetch,
+        false if size > config.quiesce_max_size => Action::Defer,
+        false => Action::Fetch,
+    }
+}
+
+// The charge of a battery in the directory of power supplies which is
+// discharging at or below the threshold, if there is one.
+fn low_battery(dir: &Path, threshold: u8) -> Option<u8> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    entries.filter_map(Result::ok).find_map(|entry| {
+        let read = |name| {
+            let value = std::fs::read_to_string(entry.path().join(name)).ok()?;
+            Some(value.trim().to_string())
+        };
+        if read("type")? != "Battery" || read("status")? != "Discharging" {
+            return None;
+        }
+        let charge: u8 = read("capacity")?.parse().ok()?;
+        (charge <= threshold).then_some(charge)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action() {
+        let mut config = Config::default();
+        config.quiesce_max_size = 100;
+        assert_eq!(action(&config, true, true, 1000), Action::Keep);
+        assert_eq!(action(&config, true, false, 1000), Action::Fetch);
+        assert_eq!(action(&config, false, false, 100), Action::Fetch);
+        assert_eq!(action(&config, false, true, 101), Action::Defer);
+    }
+
+    #[test]
+    fn test_low_battery() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let supply = |name: &str, kind: &str, status: &str, capacity: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir(&path)?;
+            std::fs::write(path.join("type"), format!("{}\n", kind))?;
+            std::fs::write(path.join("status"), format!("{}\n", status))?;
+            std::fs::write(path.join("capacity"), format!("{}\n", capacity))
+        };
+        supply("AC", "Mains", "Unknown", "0")?;
+        supply("BAT0", "Battery", "Charging", "5")?;
+        assert_eq!(low_battery(dir.path(), 20), None);
+
+        supply("BAT1", "Battery", "Discharging", "15")?;
+        assert_eq!(low_battery(dir.path(), 20), Some(15));
+        assert_eq!(low_battery(dir.path(), 10), None);
+        assert_eq!(low_battery(&dir.path().join("missing"), 20), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_detect() {
+        let mut config = Config::default();
+        assert_eq!(detect(&config).await, None);
+        config.quiesce = Quiesce::On;
+        assert!(detect(&config).await.is_some());
+        config.quiesce = Quiesce::Auto;
+        config.quiesce_battery = 0;
+        config.quiesce_check = Some("/bin/true".to_string());
+        assert_eq!(
+            detect(&config).await.as_deref(),
+            Some("the quiesce check succeeded")
+        );
+        config.quiesce_check = Some("/bin/false".to_string());
+        assert_eq!(detect(&config).await, None);
+    }
+}
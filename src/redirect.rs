@@ -0,0 +1,222 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::sync::Arc;
+use std::time::Duration;
+
+use azure_core::error::ErrorKind;
+use azure_core::headers;
+use azure_core::{Context, HttpClient, Policy, PolicyResult, Request, StatusCode};
+use url::{Position, Url};
+
+use crate::cloud::Cloud;
+use crate::config::{Config, TlsVersion};
+use crate::proxy;
+
+// How long idle connections are kept for reuse. The storage service closes
+// connections idle for longer, and reusing one it's closed fails the request.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Build the HTTP client requests to the storage service, and for tokens,
+/// are made with, with the configured timeouts, proxies and TLS settings. It
+/// doesn't follow redirects itself, so that apt can be told of them.
+pub fn new_http_client(config: &Config) -> Result<Arc<dyn HttpClient>, Box<dyn std::error::Error>> {
+    // Idle connections are kept, unlike with the SDK's own client, so that
+    // runs of many tiny files don't pay for a new connection and TLS
+    // handshake with each request. The SDK avoids it as hyper can hang
+    // reusing a connection from another runtime (hyperium/hyper#2312), but
+    // the method only has the one. A connection the service closed anyway
+    // fails the request with an IO error, which is retried.
+    let mut builder = reqwest::ClientBuilder::new()
+        .pool_idle_timeout(IDLE_TIMEOUT)
+        .connect_timeout(config.connect_timeout)
+        .read_timeout(config.request_timeout)
+        .proxy(proxy::for_client(config))
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(path) = &config.ca_bundle {
+        for certificate in read_ca_bundle(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(version) = config.min_tls_version {
+        builder = builder.min_tls_version(match version {
+            TlsVersion::Tls10 => reqwest::tls::Version::TLS_1_0,
+            TlsVersion::Tls11 => reqwest::tls::Version::TLS_1_1,
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+        });
+    }
+    Ok(Arc::new(builder.build()?))
+}
+
+// The certificates in a PEM bundle. One without any is refused, as it's
+// surely not the file meant, and trusting nothing more would only show up
+// as failures to connect.
+fn read_ca_bundle(path: &str) -> Result<Vec<reqwest::Certificate>, String> {
+    let pem = std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+        .map_err(|err| format!("Invalid CA bundle {}: {}", path, err))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates in CA bundle {}", path));
+    }
+    Ok(certificates)
+}
+
+/// Fails requests the storage service redirects, e.g. to another region or
+/// a CDN edge, with an error giving where to, so that the acquisition can be
+/// redirected rather than failed.
+#[derive(Debug)]
+pub struct RedirectPolicy {
+    // The endpoint suffixes of any other clouds in use, whose blob services
+    // redirects may be to.
+    endpoint_suffixes: Vec<String>,
+}
+
+impl RedirectPolicy {
+    pub fn new(endpoint_suffixes: &[&str]) -> Self {
+        RedirectPolicy {
+            endpoint_suffixes: endpoint_suffixes.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Policy for RedirectPolicy {
+    async fn send(
+        &self,
+        ctx: &Context,
+        request: &mut Request,
+        next: &[Arc<dyn Policy>],
+    ) -> PolicyResult {
+        let response = next[0].send(ctx, request, &next[1..]).await?;
+        let status = response.status();
+        if !matches!(
+            status,
+            StatusCode::MovedPermanently
+                | StatusCode::Found
+                | StatusCode::SeeOther
+                | StatusCode::TemporaryRedirect
+                | StatusCode::PermanentRedirect
+        ) {
+            return Ok(response);
+        }
+        // Locations may be relative to the URL requested.
+        let Some(location) = response
+            .headers()
+            .get_optional_str(&headers::LOCATION)
+            .and_then(|location| request.url().join(location).ok())
+        else {
+            return Ok(response);
+        };
+        let kind = ErrorKind::HttpResponse {
+            status,
+            error_code: None,
+        };
+        let endpoint_suffixes: Vec<_> = self.endpoint_suffixes.iter().map(String::as_str).collect();
+        let uri = apt_uri(&location, &endpoint_suffixes);
+        Err(azure_core::Error::new(
+            kind,
+            Redirected {
+                status,
+                location,
+                uri,
+            },
+        ))
+    }
+}
+
+// A response redirecting a request, and where to.
+#[derive(Debug, thiserror::Error)]
+#[error("Storage service responded {status}, redirecting to {location}")]
+struct Redirected {
+    status: StatusCode,
+    location: Url,
+    // The URI apt is to acquire instead.
+    uri: String,
+}
+
+/// The URI for apt to acquire in place of the one whose request failed with
+/// the error, if the request was redirected.
+pub fn new_uri(err: &azure_core::Error) -> Option<&str> {
+    Some(&err.get_ref()?.downcast_ref::<Redirected>()?.uri)
+}
+
+// The URI for apt to acquire in place of a redirected one. Redirects to
+// another blob service are fetched with this method, as `blob://` URIs;
+// any others are left for the method handling their scheme.
+fn apt_uri(location: &Url, endpoint_suffixes: &[&str]) -> String {
+    match location.host_str() {
+        Some(host) if Cloud::is_blob_host(host, endpoint_suffixes) => {
+            format!("blob://{}", &location[Position::BeforeHost..])
+        }
+        _ => location.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ca_bundle() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bundle.pem");
+        let path = path.to_str().unwrap();
+        let mut config = Config::default();
+        config.ca_bundle = Some(path.to_string());
+        let err = new_http_client(&config).err().unwrap();
+        assert!(err.to_string().starts_with("Failed to read"), "{}", err);
+
+        std::fs::write(path, "not a certificate\n")?;
+        let err = new_http_client(&config).err().unwrap();
+        assert!(err.to_string().starts_with("No certificates"), "{}", err);
+
+        config.ca_bundle = None;
+        config.min_tls_version = Some(TlsVersion::Tls12);
+        assert!(new_http_client(&config).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apt_uri() -> Result<(), Box<dyn std::error::Error>> {
+        let location =
+            Url::parse("https://other.blob.core.windows.net/repo/dists/stable/Release?sv=1")?;
+        assert_eq!(
+            apt_uri(&location, &[]),
+            "blob://other.blob.core.windows.net/repo/dists/stable/Release?sv=1"
+        );
+
+        let location = Url::parse("https://cdn.example.com/repo/dists/stable/Release")?;
+        assert_eq!(
+            apt_uri(&location, &[]),
+            "https://cdn.example.com/repo/dists/stable/Release"
+        );
+
+        let location = Url::parse("https://other.blob.local.azurestack.external/repo/pool/a.deb")?;
+        assert_eq!(
+            apt_uri(&location, &["local.azurestack.external"]),
+            "blob://other.blob.local.azurestack.external/repo/pool/a.deb"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_uri() -> Result<(), Box<dyn std::error::Error>> {
+        let kind = ErrorKind::HttpResponse {
+            status: StatusCode::TemporaryRedirect,
+            error_code: None,
+        };
+        let err = azure_core::Error::new(
+            kind.clone(),
+            Redirected {
+                status: StatusCode::TemporaryRedirect,
+                location: Url::parse("https://other.blob.core.windows.net/repo/a.deb")?,
+                uri: "blob://other.blob.core.windows.net/repo/a.deb".to_string(),
+            },
+        );
+        assert_eq!(
+            new_uri(&err),
+            Some("blob://other.blob.core.windows.net/repo/a.deb")
+        );
+        assert_eq!(new_uri(&azure_core::Error::message(kind, "error")), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use azure_core::error::ErrorKind;
+use azure_core::headers::{self, Headers};
+use azure_core::{Context, Policy, PolicyResult, Request, StatusCode};
+use log::warn;
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::metrics;
+
+// The longest the storage service may ask us to wait before retrying. Any
+// longer and the acquisition fails, for apt to retry later.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// How requests to the storage service are retried when they fail
+/// transiently: up to a number of times, waiting twice as long before each
+/// retry as the last, plus a random amount so that clients which failed
+/// together don't all retry together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    retries: u32,
+    delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        RetryPolicy { retries, delay }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        RetryPolicy::new(config.retries, config.retry_delay)
+    }
+
+    /// The time to wait before retrying after the given number of attempts
+    /// have failed, if the error is transient and there are retries left.
+    /// If the service said when to retry, that's how long is waited.
+    pub fn backoff(&self, failures: u32, err: &azure_core::Error) -> Option<Duration> {
+        if failures > self.retries || !is_transient(err) {
+            return None;
+        }
+        if let Some(throttled) = throttled(err) {
+            return (throttled.retry_after <= MAX_RETRY_AFTER).then_some(throttled.retry_after);
+        }
+        let delay = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)));
+        Some(delay + delay.mul_f64(fastrand::f64() / 2.0))
+    }
+
+    /// Run the operation, retrying it while it fails transiently. `what`
+    /// describes the operation in the log.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut operation: F) -> azure_core::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = azure_core::Result<T>>,
+    {
+        let mut failures = 0;
+        loop {
+            let err = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            failures += 1;
+            let Some(delay) = self.backoff(failures, &err) else {
+                return Err(err);
+            };
+            self.log_retry(what, failures, delay, &err);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Log and count that an operation is being retried after failing.
+    pub fn log_retry(&self, what: &str, failures: u32, delay: Duration, err: &azure_core::Error) {
+        metrics::record_retry();
+        warn!(
+            "{} failed, retrying in {:.1}s ({} of {}): {}",
+            what,
+            delay.as_secs_f64(),
+            failures,
+            self.retries,
+            err
+        );
+    }
+}
+
+/// Fails requests the storage service throttles, saying when to retry them,
+/// with an error giving that time, so that `RetryPolicy` can wait for it.
+#[derive(Debug)]
+pub struct RetryAfterPolicy;
+
+#[async_trait::async_trait]
+impl Policy for RetryAfterPolicy {
+    async fn send(
+        &self,
+        ctx: &Context,
+        request: &mut Request,
+        next: &[Arc<dyn Policy>],
+    ) -> PolicyResult {
+        let response = next[0].send(ctx, request, &next[1..]).await?;
+        let status = response.status();
+        if !matches!(
+            status,
+            StatusCode::TooManyRequests | StatusCode::ServiceUnavailable
+        ) {
+            return Ok(response);
+        }
+        let Some(retry_after) = retry_after(response.headers(), OffsetDateTime::now_utc()) else {
+            return Ok(response);
+        };
+        let error_code = response.headers().get_optional_string(&headers::ERROR_CODE);
+        let kind = ErrorKind::HttpResponse { status, error_code };
+        Err(azure_core::Error::new(
+            kind,
+            Throttled {
+                status,
+                retry_after,
+            },
+        ))
+    }
+}
+
+// A response throttling a request, and how long it asked to wait before the
+// request is retried.
+#[derive(Debug, thiserror::Error)]
+#[error("Storage service responded {status}, asking to retry after {}s", retry_after.as_secs())]
+struct Throttled {
+    status: StatusCode,
+    retry_after: Duration,
+}
+
+fn throttled(err: &azure_core::Error) -> Option<&Throttled> {
+    err.get_ref()?.downcast_ref::<Throttled>()
+}
+
+// How long a response asks to wait before retrying, given in milliseconds,
+// in seconds or as a time.
+fn retry_after(headers: &Headers, now: OffsetDateTime) -> Option<Duration> {
+    for name in [&headers::RETRY_AFTER_MS, &headers::X_MS_RETRY_AFTER_MS] {
+        if let Some(millis) = headers.get_optional_str(name) {
+            return millis.parse().ok().map(Duration::from_millis);
+        }
+    }
+    let value = headers.get_optional_str(&headers::RETRY_AFTER)?;
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = azure_core::date::parse_rfc1123(value).ok()?;
+    Some((at - now).try_into().unwrap_or(Duration::ZERO))
+}
+
+/// Whether the error may not happen again if the request is retried: a
+/// connection failing, or the service being overloaded or timing out.
+pub fn is_transient(err: &azure_core::Error) -> bool {
+    match err.kind() {
+        ErrorKind::Io => true,
+        ErrorKind::HttpResponse { status, .. } => matches!(
+            status,
+            StatusCode::RequestTimeout
+                | StatusCode::TooManyRequests
+                | StatusCode::InternalServerError
+                | StatusCode::BadGateway
+                | StatusCode::ServiceUnavailable
+                | StatusCode::GatewayTimeout
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn http_error(status: StatusCode) -> azure_core::Error {
+        let kind = ErrorKind::HttpResponse {
+            status,
+            error_code: None,
+        };
+        azure_core::Error::message(kind, "error")
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&azure_core::Error::message(
+            ErrorKind::Io,
+            "reset"
+        )));
+        assert!(is_transient(&http_error(StatusCode::ServiceUnavailable)));
+        assert!(is_transient(&http_error(StatusCode::TooManyRequests)));
+        assert!(!is_transient(&http_error(StatusCode::NotFound)));
+        assert!(!is_transient(&http_error(StatusCode::Forbidden)));
+        assert!(!is_transient(&azure_core::Error::message(
+            ErrorKind::Credential,
+            "error"
+        )));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1));
+        let err = http_error(StatusCode::ServiceUnavailable);
+        for (failures, base) in [(1, 1), (2, 2), (3, 4)] {
+            let delay = policy.backoff(failures, &err).unwrap();
+            let base = Duration::from_secs(base);
+            assert!(delay >= base && delay <= base.mul_f64(1.5));
+        }
+        assert_eq!(policy.backoff(4, &err), None);
+        assert_eq!(policy.backoff(1, &http_error(StatusCode::NotFound)), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = azure_core::date::parse_rfc1123("Wed, 29 May 2024 12:00:00 GMT").unwrap();
+        let headers = |name: &'static str, value: &str| {
+            let mut headers = Headers::new();
+            headers.insert(name, value.to_string());
+            headers
+        };
+        assert_eq!(
+            retry_after(&headers("retry-after", "5"), now),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry_after(&headers("x-ms-retry-after-ms", "250"), now),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            retry_after(
+                &headers("retry-after", "Wed, 29 May 2024 12:00:30 GMT"),
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(
+                &headers("retry-after", "Wed, 29 May 2024 11:00:00 GMT"),
+                now
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("retry-after", "soon"), now), None);
+        assert_eq!(retry_after(&Headers::new(), now), None);
+    }
+
+    #[test]
+    fn test_backoff_throttled() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1));
+        let throttled = |retry_after| {
+            let status = StatusCode::TooManyRequests;
+            let kind = ErrorKind::HttpResponse {
+                status,
+                error_code: None,
+            };
+            azure_core::Error::new(
+                kind,
+                Throttled {
+                    status,
+                    retry_after,
+                },
+            )
+        };
+        let err = throttled(Duration::from_secs(10));
+        assert!(err.to_string().contains("retry after 10s"));
+        assert_eq!(policy.backoff(1, &err), Some(Duration::from_secs(10)));
+        assert_eq!(policy.backoff(4, &err), None);
+        assert_eq!(
+            policy.backoff(1, &throttled(Duration::from_secs(600))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1));
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .run("Test", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(http_error(StatusCode::ServiceUnavailable)),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Retries run out.
+        attempts.store(0, Ordering::SeqCst);
+        let result: azure_core::Result<()> = policy
+            .run("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(http_error(StatusCode::ServiceUnavailable))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Errors which aren't transient aren't retried.
+        attempts.store(0, Ordering::SeqCst);
+        let result: azure_core::Result<()> = policy
+            .run("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(http_error(StatusCode::NotFound))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
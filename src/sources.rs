@@ -0,0 +1,109 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use log::debug;
+use url::Url;
+
+use crate::config::Config;
+
+/// The blob URIs in apt's source lists, in both the one-line and deb822
+/// formats, so that what they need can be got ready before apt asks for
+/// them. Lists which can't be read are skipped.
+pub fn blob_uris(config: &Config) -> BTreeSet<Url> {
+    let (list, parts) = config.source_lists();
+    let mut paths = vec![list];
+    if let Ok(entries) = std::fs::read_dir(&parts) {
+        let mut entries: Vec<_> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| is_source_list(path))
+            .collect();
+        entries.sort();
+        paths.extend(entries);
+    }
+    let mut uris = BTreeSet::new();
+    for path in paths {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => uris.extend(uris_in(&text)),
+            Err(err) => debug!("Not reading {}: {}", path.display(), err),
+        }
+    }
+    uris
+}
+
+// Whether the file is a source list apt reads from its directory of them.
+fn is_source_list(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "list" || extension == "sources")
+}
+
+// The blob URIs in a source list. Either format gives URIs as words of their
+// own: after the options of a one-line entry, or in the `URIs` field of a
+// deb822 one.
+fn uris_in(text: &str) -> impl Iterator<Item = Url> + '_ {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(str::split_whitespace)
+        .filter_map(|word| Url::parse(word).ok())
+        .filter(|url| matches!(url.scheme(), "blob" | "blob+https"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uris_in() {
+        let text = "\
+deb [signed-by=/usr/share/keyrings/repo.gpg] blob://one.blob.core.windows.net/repo stable main
+# deb blob://commented.blob.core.windows.net/repo stable main
+deb http://archive.ubuntu.com/ubuntu noble main
+
+Types: deb
+URIs: blob+https://two.blob.core.windows.net/repo blob://three/repo
+Suites: stable
+";
+        let uris: Vec<_> = uris_in(text).map(String::from).collect();
+        assert_eq!(
+            uris,
+            [
+                "blob://one.blob.core.windows.net/repo",
+                "blob+https://two.blob.core.windows.net/repo",
+                "blob://three/repo",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blob_uris() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let parts = dir.path().join("sources.list.d");
+        std::fs::create_dir(&parts)?;
+        std::fs::write(
+            parts.join("a.list"),
+            "deb blob://a.blob.core.windows.net/repo stable main\n",
+        )?;
+        std::fs::write(
+            parts.join("b.sources"),
+            "URIs: blob://b.blob.core.windows.net/repo\n",
+        )?;
+        std::fs::write(
+            parts.join("c.list.save"),
+            "deb blob://c.blob.core.windows.net/repo stable main\n",
+        )?;
+
+        let mut config = Config::default();
+        config.dir = dir.path().to_str().unwrap().to_string();
+        config.dir_etc = ".".to_string();
+        let uris: Vec<_> = blob_uris(&config).into_iter().map(String::from).collect();
+        assert_eq!(
+            uris,
+            [
+                "blob://a.blob.core.windows.net/repo",
+                "blob://b.blob.core.windows.net/repo",
+            ]
+        );
+        Ok(())
+    }
+}
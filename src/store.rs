@@ -0,0 +1,280 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::hashes::{Hasher, Hashes};
+
+// How long a claim on downloading content is honoured for. Older ones were
+// left by machines which crashed or were cut off part way through, and
+// waiting for the content no longer makes sense.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// How often to look for content another machine is downloading.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A directory of downloaded files named by their SHA256, which machines
+/// sharing it, such as over NFS, take files from rather than each
+/// downloading them. Entries are only ever renamed into place whole, so a
+/// machine never sees one part written, and whoever downloads content first
+/// claims it, so that the others wait for it rather than downloading it too.
+#[derive(Clone, Debug)]
+pub struct SharedStore {
+    dir: PathBuf,
+}
+
+/// What the store has for some content.
+#[derive(Debug)]
+pub enum Lookup {
+    /// The content, which has been copied to the file, with its hashes.
+    Hit(Hashes),
+    /// Nothing, but the content is claimed for this machine to download and
+    /// insert.
+    Claimed(Claim),
+    /// Nothing, and another machine which claimed the content didn't insert
+    /// it in time; download it without inserting it.
+    Miss,
+}
+
+/// A claim on downloading some content, which other machines wait on. It's
+/// given up when dropped, whether or not the content was inserted.
+#[derive(Debug)]
+pub struct Claim {
+    path: PathBuf,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+// A file an entry is copied to before it's renamed into place. It's removed
+// when dropped unless it was renamed, so one isn't left behind by a copy
+// which fails, or is cancelled as the method exits.
+struct TempFile {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl TempFile {
+    fn new(path: PathBuf) -> Self {
+        TempFile { path, kept: false }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl SharedStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SharedStore { dir: dir.into() }
+    }
+
+    /// Copy the content with the SHA256 to the file if the store has it. If
+    /// it doesn't, claim downloading it, or if another machine has, wait for
+    /// that machine to insert it.
+    pub async fn get(&self, sha256: &str, filename: &str) -> std::io::Result<Lookup> {
+        // Only hex digests name entries, so a header can't name a path
+        // outside the store.
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(Lookup::Miss);
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        let entry = self.entry(&sha256);
+        let deadline = Instant::now() + CLAIM_TIMEOUT;
+        loop {
+            if let Some(hashes) = copy_out(&entry, &sha256, filename).await? {
+                return Ok(Lookup::Hit(hashes));
+            }
+            if let Some(claim) = claim(&entry)? {
+                return Ok(Lookup::Claimed(claim));
+            }
+            if Instant::now() >= deadline {
+                return Ok(Lookup::Miss);
+            }
+            debug!("Waiting for another machine to store {}", sha256);
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Insert the downloaded file as the content the claim is for. It's
+    /// copied to a file of this process's own and renamed into place, which
+    /// is atomic on NFS as on local filesystems.
+    pub async fn insert(&self, claim: Claim, filename: &str) -> std::io::Result<()> {
+        let entry = claim.path.with_extension("");
+        let mut temp = TempFile::new(with_suffix(
+            &entry,
+            &format!(".{}.{}.tmp", std::process::id(), uuid::Uuid::new_v4()),
+        ));
+        tokio::fs::copy(filename, &temp.path).await?;
+        tokio::fs::File::open(&temp.path).await?.sync_all().await?;
+        tokio::fs::rename(&temp.path, &entry).await?;
+        temp.kept = true;
+        Ok(())
+    }
+
+    // Where the content with the SHA256 is kept. Entries are spread over
+    // directories by their first two digits, to keep directories small.
+    fn entry(&self, sha256: &str) -> PathBuf {
+        self.dir.join("sha256").join(&sha256[..2]).join(sha256)
+    }
+}
+
+// Copy the entry to the file if it exists, returning the file's hashes. An
+// entry which doesn't hash to its name was corrupted, and is removed.
+async fn copy_out(entry: &Path, sha256: &str, filename: &str) -> std::io::Result<Option<Hashes>> {
+    let mut source = match tokio::fs::File::open(entry).await {
+        Ok(source) => source,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut file = tokio::fs::File::create(filename).await?;
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = source.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read]).await?;
+    }
+    file.flush().await?;
+    let hashes = hasher.finish();
+    if hashes.sha256 != sha256 {
+        warn!(
+            "Removing corrupted {}: got SHA256 {}",
+            entry.display(),
+            hashes.sha256
+        );
+        let _ = tokio::fs::remove_file(entry).await;
+        let _ = tokio::fs::remove_file(filename).await;
+        return Ok(None);
+    }
+    Ok(Some(hashes))
+}
+
+// Claim downloading the entry's content by creating its lock file, which
+// fails if it exists, including over NFS. A lock older than the timeout is
+// taken over. Returns no claim if another machine holds one.
+fn claim(entry: &Path) -> std::io::Result<Option<Claim>> {
+    if let Some(dir) = entry.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let path = with_suffix(entry, ".lock");
+    for _ in 0..2 {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(Some(Claim { path })),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+        let modified = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.modified()?,
+            // Given up since; try again.
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age < CLAIM_TIMEOUT {
+            return Ok(None);
+        }
+        warn!("Taking over stale claim {}", path.display());
+        let _ = std::fs::remove_file(&path);
+    }
+    Ok(None)
+}
+
+// The path with the suffix appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn test_claim_insert_get() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let store = SharedStore::new(dir.path().join("store"));
+        let downloaded = dir.path().join("downloaded");
+        let downloaded = downloaded.to_str().unwrap();
+        std::fs::write(downloaded, "hello")?;
+
+        let Lookup::Claimed(claim) = store.get(HELLO_SHA256, downloaded).await? else {
+            panic!("expected a claim");
+        };
+        // Another machine can't claim it while this one holds the claim.
+        assert!(super::claim(&store.entry(HELLO_SHA256))?.is_none());
+        store.insert(claim, downloaded).await?;
+        let lock = with_suffix(&store.entry(HELLO_SHA256), ".lock");
+        assert!(!lock.exists());
+
+        let copied = dir.path().join("copied");
+        let copied = copied.to_str().unwrap();
+        let Lookup::Hit(hashes) = store.get(HELLO_SHA256, copied).await? else {
+            panic!("expected a hit");
+        };
+        assert_eq!(hashes.sha256, HELLO_SHA256);
+        assert_eq!(std::fs::read_to_string(copied)?, "hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_entry() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let store = SharedStore::new(dir.path());
+        let entry = store.entry(HELLO_SHA256);
+        std::fs::create_dir_all(entry.parent().unwrap())?;
+        std::fs::write(&entry, "goodbye")?;
+
+        let file = dir.path().join("file");
+        let file = file.to_str().unwrap();
+        assert!(matches!(
+            store.get(HELLO_SHA256, file).await?,
+            Lookup::Claimed(_)
+        ));
+        assert!(!entry.exists());
+        assert!(!Path::new(file).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("entry.tmp");
+        std::fs::write(&path, "part")?;
+        drop(TempFile::new(path.clone()));
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_sha256() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let store = SharedStore::new(dir.path());
+        let lookup = store.get("../../etc/passwd", "file").await?;
+        assert!(matches!(lookup, Lookup::Miss));
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::os::unix::net::UnixDatagram;
+
+use log::{Level, Log, Metadata, Record};
+
+// The socket the local syslog daemon takes messages on.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+// The tag the method's messages are logged under.
+const TAG: &str = "apt-transport-blob";
+
+// The facility messages are logged with, `LOG_USER`, as the method isn't a
+// daemon.
+const FACILITY: u8 = 1;
+
+/// Logs each record to the local syslog daemon, for systems without
+/// systemd-journald.
+#[derive(Debug)]
+pub struct SyslogAppender {
+    socket: UnixDatagram,
+}
+
+impl SyslogAppender {
+    pub fn new() -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(SyslogAppender { socket })
+    }
+}
+
+impl Log for SyslogAppender {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // There's nowhere to report a message the daemon wouldn't take.
+        let _ = self
+            .socket
+            .send(encode(record, std::process::id()).as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+// Encode a record as a syslog message, as the C library's `syslog` sends
+// them, on a single line. The daemon adds the time it's received.
+fn encode(record: &Record, pid: u32) -> String {
+    let priority = FACILITY * 8 + severity(record.level());
+    let message = record.args().to_string().replace('\n', " ");
+    format!("<{}>{}[{}]: {}", priority, TAG, pid, message)
+}
+
+// The syslog severity of a log level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let message = encode(
+            &Record::builder()
+                .args(format_args!("Downloaded\nblob"))
+                .level(Level::Warn)
+                .build(),
+            42,
+        );
+        assert_eq!(message, "<12>apt-transport-blob[42]: Downloaded blob");
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+use std::any::Any;
+use std::fmt::Display;
+
+use log::{error, info};
+
+use crate::azure;
+use crate::credentials::redact_sas;
+use crate::message::{Message, MessageType};
+use crate::redirect;
+use crate::retry;
+
+/// The steps of acquiring a URI, which failures say they happened in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Making sense of what apt asked for.
+    Request,
+    /// Getting the blob's properties, or the version of it to fetch.
+    Properties,
+    /// Asking for an archived blob to be rehydrated.
+    Rehydrate,
+    /// Downloading the blob to the file.
+    Download,
+    /// Checking the downloaded file.
+    Verify,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Request => "reading the request",
+            Phase::Properties => "getting the blob's properties",
+            Phase::Rehydrate => "rehydrating the blob",
+            Phase::Download => "downloading the blob",
+            Phase::Verify => "verifying the download",
+        }
+    }
+}
+
+/// Kinds of failure, which decide how apt is told of them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// The storage service redirected the request to the URI. apt re-queues
+    /// redirected acquisitions itself, with the method for the new URI.
+    Redirected(String),
+    /// The storage service was unavailable, or the blob changed while it
+    /// was being fetched, so trying again later may succeed.
+    Transient,
+    /// Anything else.
+    Permanent,
+}
+
+impl ErrorClass {
+    /// Classify an error a step of an acquisition failed with.
+    pub fn of(err: &dyn Any) -> Self {
+        let Some(err) = azure_error(err) else {
+            return ErrorClass::Permanent;
+        };
+        if let Some(new_uri) = redirect::new_uri(err) {
+            return ErrorClass::Redirected(new_uri.to_string());
+        }
+        if retry::is_transient(err) || azure::is_blob_changed(err) {
+            return ErrorClass::Transient;
+        }
+        ErrorClass::Permanent
+    }
+}
+
+/// The URI being acquired, to build the response for a step that fails
+/// with: a redirect, or a URI Failure saying why, with the `FailReason` and
+/// `Transient-Failure` the error calls for.
+#[derive(Debug)]
+pub struct UriContext<'a> {
+    uri: &'a str,
+    hints: bool,
+}
+
+impl<'a> UriContext<'a> {
+    pub fn new(uri: &'a str) -> Self {
+        UriContext { uri, hints: false }
+    }
+
+    /// Add a hint of what to do to failures, for errors there's one for.
+    pub fn with_hints(mut self, hints: bool) -> Self {
+        self.hints = hints;
+        self
+    }
+
+    pub fn uri(&self) -> &'a str {
+        self.uri
+    }
+
+    /// The value of a step in the phase, or if it failed, the response to
+    /// send apt, as the error so that it can be returned with `?`.
+    pub fn check<T, E: Display + 'static>(
+        &self,
+        phase: Phase,
+        result: Result<T, E>,
+    ) -> Result<T, Message> {
+        result.map_err(|err| self.failure(phase, &err))
+    }
+
+    // The response to send apt for a step in the phase failing.
+    fn failure<E: Display + 'static>(&self, phase: Phase, err: &E) -> Message {
+        let log_uri = redact_sas(self.uri);
+        let class = match ErrorClass::of(err) {
+            ErrorClass::Redirected(new_uri) => {
+                info!("Redirecting {} to {}", log_uri, redact_sas(&new_uri));
+                return Message::new(
+                    MessageType::Redirect,
+                    vec![("URI", self.uri), ("New-URI", &new_uri)],
+                );
+            }
+            class => class,
+        };
+        let mut message = redact_sas(&format!("Error: {}", err));
+        let hint = azure_error(err).and_then(azure::hint);
+        if let Some(hint) = hint.filter(|_| self.hints) {
+            message = format!("{}; hint: {}", message, hint);
+        }
+        error!(
+            "URI failure for {} {}: {}",
+            log_uri,
+            phase.as_str(),
+            message
+        );
+        let mut failure = Message::build_uri_failure(self.uri, &message);
+        if let Some(reason) = azure_error(err).and_then(azure::fail_reason) {
+            failure = failure.with_header("FailReason", &reason);
+        }
+        if class == ErrorClass::Transient {
+            failure = failure.with_header("Transient-Failure", "true");
+        }
+        failure
+    }
+}
+
+// The error from the storage service a step failed with, if it failed with
+// one, to say more about the failure to apt.
+fn azure_error(err: &dyn Any) -> Option<&azure_core::Error> {
+    err.downcast_ref::<azure_core::Error>().or_else(|| {
+        err.downcast_ref::<Box<dyn std::error::Error>>()
+            .and_then(|err| err.downcast_ref::<azure_core::Error>())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_core::error::ErrorKind;
+    use azure_core::StatusCode;
+
+    fn http_error(status: StatusCode, code: &str) -> azure_core::Error {
+        azure_core::Error::message(
+            ErrorKind::HttpResponse {
+                status,
+                error_code: Some(code.to_string()),
+            },
+            "error",
+        )
+    }
+
+    #[test]
+    fn test_error_class() {
+        let busy = http_error(StatusCode::ServiceUnavailable, "ServerBusy");
+        assert_eq!(ErrorClass::of(&busy), ErrorClass::Transient);
+        let boxed: Box<dyn std::error::Error> = Box::new(busy);
+        assert_eq!(ErrorClass::of(&boxed), ErrorClass::Transient);
+
+        let missing = http_error(StatusCode::NotFound, "BlobNotFound");
+        assert_eq!(ErrorClass::of(&missing), ErrorClass::Permanent);
+        let io = std::io::Error::other("disk full");
+        assert_eq!(ErrorClass::of(&io), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn test_check() {
+        let context = UriContext::new("blob://a/c/b?sig=secret");
+        assert_eq!(
+            context
+                .check(Phase::Request, Ok::<_, std::io::Error>(42))
+                .ok(),
+            Some(42)
+        );
+
+        let failure = context
+            .check::<(), _>(
+                Phase::Properties,
+                Err(http_error(StatusCode::ServiceUnavailable, "ServerBusy")),
+            )
+            .unwrap_err();
+        assert_eq!(failure.message_type, MessageType::URIFailure);
+        assert_eq!(failure.uri().unwrap(), "blob://a/c/b?sig=secret");
+        assert_eq!(failure.fail_reason(), Some("HttpError503"));
+        assert!(failure.to_string().contains("Transient-Failure: true\n"));
+
+        let failure = context
+            .check::<(), _>(Phase::Verify, Err(std::io::Error::other("disk full")))
+            .unwrap_err();
+        assert_eq!(failure.fail_reason(), None);
+        assert!(!failure.to_string().contains("Transient-Failure"));
+    }
+
+    #[test]
+    fn test_hints() {
+        let mismatch = || {
+            Err::<(), _>(http_error(
+                StatusCode::Forbidden,
+                "AuthorizationPermissionMismatch",
+            ))
+        };
+        let context = UriContext::new("blob://a/c/b");
+        let failure = context.check(Phase::Properties, mismatch()).unwrap_err();
+        assert!(!failure.to_string().contains("hint:"));
+
+        let context = context.with_hints(true);
+        let failure = context.check(Phase::Properties, mismatch()).unwrap_err();
+        assert!(failure
+            .to_string()
+            .contains("; hint: the credentials are accepted, but their identity needs"));
+    }
+}
@@ -0,0 +1,421 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Soak test simulating a nightly fleet update: thousands of acquisitions,
+//! pipelined as apt does, of blobs of random sizes, against a mock blob
+//! service which also throttles, refuses, redirects and is missing blobs,
+//! while other sessions are cancelled part way through.
+//!
+//! It checks that:
+//!
+//! - every acquisition is answered exactly once, with the outcome expected
+//!   of it, and every message is well formed;
+//! - the method's memory doesn't grow as it works through the acquisitions;
+//! - sessions finish promptly once apt closes its end of them, or stops
+//!   reading from them, so no task is left stuck.
+//!
+//! The test is ignored by default, as it runs for a while. Run it with
+//!
+//! ```text
+//! cargo test --release --test soak -- --ignored --nocapture
+//! ```
+//!
+//! `SOAK_SECONDS` sets how long it runs for, 60 seconds by default, and
+//! `SOAK_SEED` the seed to generate the blobs and acquisitions from, so that
+//! a failing run can be repeated.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+// Not every helper is used by every test crate.
+#[allow(dead_code)]
+mod support;
+
+use support::MockBlobService;
+
+// The number of blobs the mock service holds, and the most bytes in each.
+const BLOBS: usize = 64;
+const MAX_BLOB_SIZE: usize = 2 * 1024 * 1024;
+
+// The acquisitions sent at once, as apt pipelines them.
+const BATCH_SIZE: usize = 100;
+
+// How long a session may take to answer a batch, or to exit once apt is
+// done with it, before it's taken to be stuck.
+const STUCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How much the method's resident memory may grow past what it used once
+// warmed up, as a fraction of that plus a fixed allowance.
+const MEMORY_GROWTH: f64 = 0.5;
+const MEMORY_ALLOWANCE: u64 = 16 * 1024 * 1024;
+
+// What an acquisition is expected to end with.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Outcome {
+    Done,
+    Failure,
+    Redirect,
+}
+
+// An acquisition to send, and what it's expected to end with.
+struct Acquisition {
+    uri: String,
+    expected_sha256: String,
+    outcome: Outcome,
+}
+
+// A message from the method: its status line and headers.
+#[derive(Debug)]
+struct Reply {
+    status: String,
+    headers: HashMap<String, String>,
+}
+
+impl Reply {
+    fn code(&self) -> &str {
+        self.status.split(' ').next().unwrap_or_default()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+// The blobs the soak test downloads, by path, with the SHA256 hash of each.
+struct Repository {
+    blobs: Vec<(String, String)>,
+}
+
+impl Repository {
+    fn generate() -> (Self, HashMap<String, Vec<u8>>) {
+        let mut contents = HashMap::new();
+        let mut blobs = Vec::new();
+        for index in 0..BLOBS {
+            // Mostly small files, as indexes and packages are, with some
+            // large ones which are downloaded in chunks.
+            let size = match fastrand::u8(0..10) {
+                0 => fastrand::usize(0..=MAX_BLOB_SIZE),
+                _ => fastrand::usize(0..=64 * 1024),
+            };
+            let data: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+                .take(size)
+                .collect();
+            let path = format!("pool/main/s/soak/soak_{}.deb", index);
+            blobs.push((path.clone(), hex_sha256(&data)));
+            contents.insert(format!("/testaccount/repo/{}", path), data);
+        }
+        (Repository { blobs }, contents)
+    }
+
+    // A random acquisition: mostly of blobs which exist, but some for those
+    // which are missing, throttled, refused or moved, or which don't match
+    // the hash apt expects.
+    fn acquisition(&self) -> Acquisition {
+        let (path, sha256) = &self.blobs[fastrand::usize(..self.blobs.len())];
+        let uri = |container: &str| {
+            format!(
+                "blob://testaccount.blob.core.windows.net/{}/{}",
+                container, path
+            )
+        };
+        let (uri, expected_sha256, outcome) = match fastrand::u8(0..20) {
+            0 => (uri("repo"), "0".repeat(64), Outcome::Failure),
+            1 => (uri("missing"), sha256.clone(), Outcome::Failure),
+            2 => (uri("busy"), sha256.clone(), Outcome::Failure),
+            3 => (uri("private"), sha256.clone(), Outcome::Failure),
+            4 => (uri("moved"), sha256.clone(), Outcome::Redirect),
+            _ => (uri("repo"), sha256.clone(), Outcome::Done),
+        };
+        Acquisition {
+            uri,
+            expected_sha256,
+            outcome,
+        }
+    }
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// A session with the method binary, as apt runs it.
+struct Session {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    replies: Receiver<Reply>,
+    dir: tempfile::TempDir,
+    acquired: usize,
+}
+
+impl Session {
+    fn start(service: &MockBlobService) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_blob"))
+            .arg("--log-stderr")
+            .env_remove("AZURE_STORAGE_BEARER_TOKEN")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        // Parse messages as they arrive, so the method never blocks writing
+        // them.
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, replies) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut lines = stdout.lines();
+            while let Some(Ok(status)) = lines.next() {
+                let mut headers = HashMap::new();
+                for line in lines.by_ref() {
+                    let line = line.unwrap_or_default();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line
+                        .split_once(": ")
+                        .unwrap_or_else(|| panic!("Malformed header {:?}", line));
+                    headers.insert(name.to_string(), value.to_string());
+                }
+                if sender.send(Reply { status, headers }).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let sas_file = dir.path().join("blob-sas.conf");
+        std::fs::write(&sas_file, "testaccount sv=2022-11-02&sp=r&sig=test\n").unwrap();
+        let mut session = Session {
+            child,
+            stdin: None,
+            replies,
+            dir,
+            acquired: 0,
+        };
+        session.stdin = session.child.stdin.take();
+        session.send(&format!(
+            "601 Configuration\n\
+             Config-Item: Acquire::blob::Endpoint={}\n\
+             Config-Item: Acquire::blob::AllowInsecure=true\n\
+             Config-Item: Acquire::blob::SAS-File={}\n\
+             Config-Item: Acquire::blob::Chunk-Size=262144\n\n",
+            service.endpoint,
+            sas_file.display()
+        ));
+        session
+    }
+
+    fn send(&mut self, message: &str) {
+        // The method may have gone when a session is being cancelled.
+        if let Some(stdin) = &mut self.stdin {
+            let _ = stdin.write_all(message.as_bytes());
+        }
+    }
+
+    fn acquire(&mut self, acquisition: &Acquisition) {
+        self.acquired += 1;
+        let filename = self.dir.path().join(format!("partial_{}", self.acquired));
+        self.send(&format!(
+            "600 URI Acquire\n\
+             URI: {}\n\
+             Filename: {}\n\
+             Expected-SHA256: {}\n\n",
+            acquisition.uri,
+            filename.display(),
+            acquisition.expected_sha256
+        ));
+    }
+
+    // Wait for the method to answer each of the acquisitions, checking the
+    // answers follow the protocol and are as expected.
+    fn check(&self, acquisitions: &[Acquisition]) {
+        let mut outstanding: HashMap<&str, HashMap<Outcome, usize>> = HashMap::new();
+        let mut started: HashMap<&str, usize> = HashMap::new();
+        for acquisition in acquisitions {
+            *outstanding
+                .entry(&acquisition.uri)
+                .or_default()
+                .entry(acquisition.outcome)
+                .or_default() += 1;
+        }
+        let sha256s: HashMap<&str, &str> = acquisitions
+            .iter()
+            .filter(|acquisition| acquisition.outcome == Outcome::Done)
+            .map(|acquisition| {
+                (
+                    acquisition.uri.as_str(),
+                    acquisition.expected_sha256.as_str(),
+                )
+            })
+            .collect();
+
+        let mut remaining = acquisitions.len();
+        while remaining > 0 {
+            let reply = match self.replies.recv_timeout(STUCK_TIMEOUT) {
+                Ok(reply) => reply,
+                Err(RecvTimeoutError::Timeout) => {
+                    panic!("{} acquisitions unanswered; is a task stuck?", remaining)
+                }
+                Err(RecvTimeoutError::Disconnected) => panic!("The method exited"),
+            };
+            let outcome = match reply.code() {
+                "103" => Outcome::Redirect,
+                code if code.starts_with('1') => continue,
+                "200" => {
+                    let uri = reply.header("URI").expect("URI Start without a URI");
+                    let uri = outstanding.get_key_value(uri).map(|(uri, _)| *uri);
+                    *started
+                        .entry(uri.unwrap_or_else(|| panic!("Unexpected {:?}", reply)))
+                        .or_default() += 1;
+                    continue;
+                }
+                "201" => Outcome::Done,
+                "400" => Outcome::Failure,
+                _ => panic!("Unexpected {:?}", reply),
+            };
+            let uri = reply.header("URI").expect("Reply without a URI");
+            let count = outstanding
+                .get_mut(uri)
+                .and_then(|outcomes| outcomes.get_mut(&outcome))
+                .filter(|count| **count > 0)
+                .unwrap_or_else(|| panic!("Unexpected {:?}", reply));
+            *count -= 1;
+            if outcome == Outcome::Done {
+                assert_eq!(reply.header("SHA256-Hash"), sha256s.get(uri).copied());
+                let start = started.get_mut(uri);
+                let start = start.unwrap_or_else(|| panic!("Done before start: {:?}", reply));
+                *start = start.checked_sub(1).expect("Done before start");
+            }
+            remaining -= 1;
+        }
+    }
+
+    // The method's resident memory, in bytes.
+    fn resident_memory(&self) -> u64 {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.child.id()))
+            .expect("Can't read the method's memory use");
+        let kilobytes = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .expect("No VmRSS");
+        kilobytes * 1024
+    }
+
+    // Tell the method apt is done with it, and wait for it to exit.
+    fn finish(mut self) {
+        self.stdin.take();
+        let status = self.wait();
+        assert!(status.success(), "The method failed: {}", status);
+    }
+
+    // Wait for the method to exit, failing if it doesn't.
+    fn wait(&mut self) -> std::process::ExitStatus {
+        let deadline = Instant::now() + STUCK_TIMEOUT;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            if Instant::now() > deadline {
+                let _ = self.child.kill();
+                panic!("The method didn't exit; is a task stuck?");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// Start a session and cancel it part way through, as apt does when it's
+// interrupted: either closing its end before the acquisitions are answered,
+// after which the method must still answer them and exit, or no longer
+// reading what the method writes, after which it must exit.
+fn cancelled_session(service: &MockBlobService, repository: &Repository) {
+    let mut session = Session::start(service);
+    let acquisitions: Vec<_> = (0..fastrand::usize(1..BATCH_SIZE))
+        .map(|_| repository.acquisition())
+        .collect();
+    for acquisition in &acquisitions {
+        session.acquire(acquisition);
+    }
+    std::thread::sleep(Duration::from_millis(fastrand::u64(0..50)));
+    if fastrand::bool() {
+        session.stdin.take();
+        session.check(&acquisitions);
+        let status = session.wait();
+        assert!(status.success(), "The method failed: {}", status);
+    } else {
+        let (_, replies) = mpsc::channel();
+        drop(std::mem::replace(&mut session.replies, replies));
+        session.stdin.take();
+        session.wait();
+    }
+}
+
+#[test]
+#[ignore]
+fn test_soak() {
+    let seconds = std::env::var("SOAK_SECONDS").map_or(60, |seconds| seconds.parse().unwrap());
+    let duration = Duration::from_secs(seconds);
+    let seed =
+        std::env::var("SOAK_SEED").map_or_else(|_| fastrand::u64(..), |seed| seed.parse().unwrap());
+    println!("Soaking for {}s with SOAK_SEED={}", seconds, seed);
+    fastrand::seed(seed);
+
+    let (repository, contents) = Repository::generate();
+    let service = MockBlobService::start(contents);
+
+    // One long session works through the acquisitions, as apt does for an
+    // update, with cancelled sessions alongside it.
+    let mut session = Session::start(&service);
+    let started = Instant::now();
+    let mut batches = 0;
+    let mut baseline = None;
+    while started.elapsed() < duration {
+        let acquisitions: Vec<_> = (0..BATCH_SIZE).map(|_| repository.acquisition()).collect();
+        for acquisition in &acquisitions {
+            session.acquire(acquisition);
+        }
+        session.check(&acquisitions);
+        batches += 1;
+
+        // Measure the memory used once the method has warmed up, after a
+        // tenth of the run, and check it doesn't grow much past that.
+        let memory = session.resident_memory();
+        match baseline {
+            None if started.elapsed() > duration / 10 => baseline = Some(memory),
+            Some(baseline) => {
+                let limit = baseline + (baseline as f64 * MEMORY_GROWTH) as u64 + MEMORY_ALLOWANCE;
+                assert!(
+                    memory <= limit,
+                    "Memory grew from {} to {} bytes after {} acquisitions",
+                    baseline,
+                    memory,
+                    batches * BATCH_SIZE
+                );
+            }
+            None => (),
+        }
+
+        if fastrand::u8(0..4) == 0 {
+            cancelled_session(&service, &repository);
+        }
+    }
+    session.finish();
+    println!(
+        "Completed {} acquisitions in {:.0}s",
+        batches * BATCH_SIZE,
+        started.elapsed().as_secs_f64()
+    );
+}
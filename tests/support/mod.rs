@@ -0,0 +1,239 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers shared by the integration tests. The crate only builds a binary,
+//! so these live with the tests rather than behind a feature for other
+//! crates to use.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+// The ETag every blob the service serves has.
+const ETAG: &str = "\"0x8DC7FD2A1B2C3D4\"";
+
+/// A minimal stand-in for the blob service, answering Get Blob Properties
+/// (HEAD) and Get Blob (GET) requests for a fixed set of blobs. Requests for
+/// blobs in any account's `busy` container are always throttled, those in
+/// its `private` container are always refused, those in its `moved`
+/// container are redirected to another account, those in its `archive`
+/// container are archived, those in its `corrupt` container don't match
+/// their Content-MD5, and those in its `changing` container are replaced
+/// between their properties and content being got. Blobs are gzip
+/// compressed in transit when the client accepts it, as by a proxy or CDN in
+/// front of the service, which never does so itself, and only sent while
+/// they match any ETag the client asks for with `If-Match`.
+pub struct MockBlobService {
+    /// The `host:port` the service listens on.
+    pub address: String,
+    /// The service's endpoint, with `{account}` in place of the account.
+    pub endpoint: String,
+}
+
+impl MockBlobService {
+    /// Start serving the blobs, given by `/<account>/<container>/<blob>`
+    /// path, on a local port.
+    pub fn start(blobs: HashMap<String, Vec<u8>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let endpoint = format!("http://{}/{{account}}", address);
+        let blobs = Arc::new(blobs);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let blobs = blobs.clone();
+                std::thread::spawn(move || serve(stream, &blobs));
+            }
+        });
+        MockBlobService { address, endpoint }
+    }
+}
+
+// Answer requests on a connection until the client closes it.
+fn serve(stream: TcpStream, blobs: &HashMap<String, Vec<u8>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        let path = target.split('?').next().unwrap_or_default();
+        let response = match blobs.get(path) {
+            Some(_) if headers.get("if-match").is_some_and(|etag| etag != ETAG) => {
+                condition_not_met()
+            }
+            Some(data) => respond(
+                method,
+                data,
+                &[("x-ms-access-tier", "Hot".to_string())],
+                headers.get("x-ms-range"),
+                headers
+                    .get("accept-encoding")
+                    .is_some_and(|encodings| encodings.contains("gzip")),
+            ),
+            // Archived blobs can be asked to be rehydrated, but not read.
+            None if path.split('/').nth(2) == Some("archive") => match method {
+                "PUT" => http_response("202 Accepted", &[], b""),
+                _ => respond(
+                    "HEAD",
+                    b"archived",
+                    &[("x-ms-access-tier", "Archive".to_string())],
+                    None,
+                    false,
+                ),
+            },
+            // Blobs in the `corrupt` container are those in `repo`, with a
+            // Content-MD5 they don't match, that of no content.
+            None if path.split('/').nth(2) == Some("corrupt") => {
+                match blobs.get(&path.replacen("/corrupt/", "/repo/", 1)) {
+                    Some(data) => respond(
+                        method,
+                        data,
+                        &[("Content-MD5", "1B2M2Y8AsgTpgAmY7PhCfg==".to_string())],
+                        headers.get("x-ms-range"),
+                        false,
+                    ),
+                    None => http_response("404 The specified blob does not exist.", &[], b""),
+                }
+            }
+            // Blobs in the `changing` container are those in `repo`, which
+            // no longer have the ETag they're fetched with.
+            None if path.split('/').nth(2) == Some("changing") => {
+                match blobs.get(&path.replacen("/changing/", "/repo/", 1)) {
+                    Some(_) if headers.contains_key("if-match") => condition_not_met(),
+                    Some(data) => respond(method, data, &[], headers.get("x-ms-range"), false),
+                    None => http_response("404 The specified blob does not exist.", &[], b""),
+                }
+            }
+            None if path.split('/').nth(2) == Some("busy") => http_response(
+                "503 The server is busy.",
+                &[
+                    ("Retry-After", "0".to_string()),
+                    ("x-ms-error-code", "ServerBusy".to_string()),
+                ],
+                b"",
+            ),
+            None if path.split('/').nth(2) == Some("moved") => http_response(
+                "307 Temporary Redirect",
+                &[(
+                    "Location",
+                    format!(
+                        "https://otheraccount.blob.core.windows.net/repo/{}",
+                        path.splitn(4, '/').nth(3).unwrap_or_default()
+                    ),
+                )],
+                b"",
+            ),
+            None if path.split('/').nth(2) == Some("private") => http_response(
+                "403 This request is not authorized to perform this operation.",
+                &[("x-ms-error-code", "AuthorizationFailure".to_string())],
+                b"",
+            ),
+            None => http_response("404 The specified blob does not exist.", &[], b""),
+        };
+        if writer.write_all(&response).is_err() {
+            return;
+        }
+    }
+}
+
+// The response for a blob asked for with an ETag it doesn't have.
+fn condition_not_met() -> Vec<u8> {
+    http_response(
+        "412 The condition specified using HTTP conditional header(s) is not met.",
+        &[("x-ms-error-code", "ConditionNotMet".to_string())],
+        b"",
+    )
+}
+
+// Build the response for a blob which exists.
+fn respond(
+    method: &str,
+    data: &[u8],
+    properties: &[(&str, String)],
+    range: Option<&String>,
+    gzip: bool,
+) -> Vec<u8> {
+    let total = data.len();
+    let mut headers = vec![
+        ("Last-Modified", "Wed, 29 May 2024 12:00:00 GMT".to_string()),
+        ("ETag", ETAG.to_string()),
+        (
+            "x-ms-creation-time",
+            "Wed, 29 May 2024 12:00:00 GMT".to_string(),
+        ),
+        ("x-ms-blob-type", "BlockBlob".to_string()),
+        ("x-ms-server-encrypted", "true".to_string()),
+    ];
+    headers.extend_from_slice(properties);
+    if method == "HEAD" {
+        headers.push(("Content-Length", total.to_string()));
+        return http_response("200 OK", &headers, b"");
+    }
+
+    // Ranges are `bytes=<start>-<end>`, inclusive.
+    let (start, end) = range
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .map(|(start, end)| {
+            let start: usize = start.parse().unwrap();
+            let end = end
+                .parse()
+                .map_or(total - 1, |end: usize| end.min(total - 1));
+            (start, end)
+        })
+        .unwrap_or((0, total - 1));
+    let mut body = data[start..=end].to_vec();
+    if gzip {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        body = encoder.finish().unwrap();
+        headers.push(("Content-Encoding", "gzip".to_string()));
+    }
+    headers.push(("Content-Length", body.len().to_string()));
+    headers.push((
+        "Content-Range",
+        format!("bytes {}-{}/{}", start, end, total),
+    ));
+    http_response("206 Partial Content", &headers, &body)
+}
+
+fn http_response(status: &str, headers: &[(&str, String)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n\
+         x-ms-request-id: 00000000-0000-0000-0000-000000000000\r\n\
+         x-ms-version: 2022-11-02\r\n\
+         Date: Wed, 29 May 2024 12:00:00 GMT\r\n",
+        status
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        response.push_str("Content-Length: 0\r\n");
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
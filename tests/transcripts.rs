@@ -0,0 +1,222 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Golden transcript tests. Each `tests/transcripts/<name>.in` is a session
+//! of messages from apt, which is run through the method binary against a
+//! mock blob service. What the method writes to stdout must match
+//! `tests/transcripts/<name>.out` byte for byte.
+//!
+//! Transcripts may use these placeholders, which are replaced with the
+//! values for the test run in the input and restored in the output:
+//!
+//! - `@ENDPOINT@`: the mock blob service, to use as `Acquire::blob::Endpoint`
+//! - `@ADDRESS@`: the `host:port` of the mock blob service, for path-style
+//!   URLs as used with `Acquire::blob::Emulator`
+//! - `@SASFILE@`: a SAS token file with a token for every account
+//! - `@DIR@`: a temporary directory to download into
+//! - `@VERSION@`: the version of the method, with the features it was built
+//!   with
+//!
+//! Set `UPDATE_TRANSCRIPTS=1` to write the output of each session to its
+//! `.out` file instead of comparing against it.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+mod support;
+
+use support::MockBlobService;
+
+// Blobs served by the mock service, by `/<account>/<container>/<blob>` path.
+fn blobs() -> HashMap<String, Vec<u8>> {
+    HashMap::from([
+        (
+            "/testaccount/repo/dists/stable/Release".to_string(),
+            b"Origin: test\nLabel: test\nSuite: stable\n".to_vec(),
+        ),
+        (
+            "/testaccount/repo/pool/main/h/hello/hello_1.0_amd64.deb".to_string(),
+            (0..=255u8).cycle().take(4096).collect(),
+        ),
+    ])
+}
+
+// Run a session through the method and return what it wrote to stdout.
+fn run_session(input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_blob"))
+        .arg("--log-stderr")
+        .env_remove("AZURE_STORAGE_BEARER_TOKEN")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    // Drain stderr as the session runs so a chatty log can't block it.
+    let mut stderr = child.stderr.take().unwrap();
+    let log = std::thread::spawn(move || {
+        let mut log = String::new();
+        stderr.read_to_string(&mut log).unwrap();
+        log
+    });
+    let output = child.wait_with_output().unwrap();
+    let log = log.join().unwrap();
+    assert!(output.status.success(), "method failed:\n{}", log);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Replace the method's version, and the features it was built with, with
+// `@VERSION@`, so transcripts needn't change with them.
+fn replace_version(output: &str) -> String {
+    let version = format!("Version: {}", env!("CARGO_PKG_VERSION"));
+    output
+        .split_inclusive('\n')
+        .map(|line| match line.strip_prefix(&version) {
+            Some(rest) if rest.starts_with(['+', '\n']) => "Version: @VERSION@\n",
+            _ => line,
+        })
+        .collect()
+}
+
+fn transcripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+    let mut inputs: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "in"))
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+#[test]
+fn test_transcripts() {
+    let service = MockBlobService::start(blobs());
+    let update = std::env::var_os("UPDATE_TRANSCRIPTS").is_some();
+
+    let mut failures = vec![];
+    for input_path in transcripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap();
+        let sas_file = dir.path().join("blob-sas.conf");
+        std::fs::write(&sas_file, "testaccount sv=2022-11-02&sp=r&sig=test\n").unwrap();
+        let sas_file = sas_file.to_str().unwrap();
+
+        let input = std::fs::read_to_string(&input_path)
+            .unwrap()
+            .replace("@ENDPOINT@", &service.endpoint)
+            .replace("@ADDRESS@", &service.address)
+            .replace("@SASFILE@", sas_file)
+            .replace("@DIR@", dir_path);
+        let output = run_session(&input)
+            .replace(&service.endpoint, "@ENDPOINT@")
+            .replace(&service.address, "@ADDRESS@")
+            .replace(sas_file, "@SASFILE@")
+            .replace(dir_path, "@DIR@");
+        let output = replace_version(&output);
+
+        let output_path = input_path.with_extension("out");
+        if update {
+            std::fs::write(&output_path, &output).unwrap();
+        } else if std::fs::read_to_string(&output_path).ok().as_deref() != Some(&output) {
+            failures.push(format!("{}:\n{}", output_path.display(), output));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Output differs from transcripts:\n{}",
+        failures.join("\n")
+    );
+}
+
+// A partial file is resumed only if it's no older than the blob, so that part
+// of an earlier version isn't joined to the rest of this one.
+#[test]
+fn test_resume() {
+    let service = MockBlobService::start(blobs());
+    let deb = &blobs()["/testaccount/repo/pool/main/h/hello/hello_1.0_amd64.deb"];
+    let dir = tempfile::tempdir().unwrap();
+    let sas_file = dir.path().join("blob-sas.conf");
+    std::fs::write(&sas_file, "testaccount sv=2022-11-02&sp=r&sig=test\n").unwrap();
+
+    let acquire = |partial: &[u8], modified: SystemTime| {
+        let path = dir.path().join("hello_1.0_amd64.deb");
+        std::fs::write(&path, partial).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let output = run_session(&format!(
+            "601 Configuration\n\
+             Config-Item: Acquire::blob::Endpoint={}\n\
+             Config-Item: Acquire::blob::AllowInsecure=true\n\
+             Config-Item: Acquire::blob::SAS-File={}\n\
+             \n\
+             600 URI Acquire\n\
+             URI: blob://testaccount.blob.core.windows.net/repo/pool/main/h/hello/hello_1.0_amd64.deb\n\
+             Filename: {}\n\
+             \n",
+            service.endpoint,
+            sas_file.display(),
+            path.display()
+        ));
+        assert!(output.contains("201 URI Done"), "{}", output);
+        assert_eq!(&std::fs::read(&path).unwrap(), deb);
+        output
+    };
+
+    // The mock blob was last modified in May 2024.
+    let output = acquire(&deb[..100], SystemTime::now());
+    assert!(output.contains("Resume-Point: 100\n"), "{}", output);
+    let stale = SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+    let output = acquire(&[0xff; 100], stale);
+    assert!(!output.contains("Resume-Point"), "{}", output);
+}
+
+// The request timeout is for a request which stops receiving anything, so
+// the chunks of a download held back by a low rate limit aren't cut short,
+// however much longer than it they take in all.
+#[test]
+fn test_dl_limit_chunked() {
+    let service = MockBlobService::start(blobs());
+    let dir = tempfile::tempdir().unwrap();
+    let sas_file = dir.path().join("blob-sas.conf");
+    std::fs::write(&sas_file, "testaccount sv=2022-11-02&sp=r&sig=test\n").unwrap();
+    let path = dir.path().join("hello_1.0_amd64.deb");
+
+    // 4 chunks of 1 KiB at 1 KiB a second take about 4 seconds.
+    let output = run_session(&format!(
+        "601 Configuration\n\
+         Config-Item: Acquire::blob::Endpoint={}\n\
+         Config-Item: Acquire::blob::AllowInsecure=true\n\
+         Config-Item: Acquire::blob::SAS-File={}\n\
+         Config-Item: Acquire::blob::Chunk-Size=1024\n\
+         Config-Item: Acquire::blob::Dl-Limit=1\n\
+         Config-Item: Acquire::blob::Request-Timeout=1\n\
+         \n\
+         600 URI Acquire\n\
+         URI: blob://testaccount.blob.core.windows.net/repo/pool/main/h/hello/hello_1.0_amd64.deb\n\
+         Filename: {}\n\
+         \n",
+        service.endpoint,
+        sas_file.display(),
+        path.display()
+    ));
+    assert!(output.contains("201 URI Done"), "{}", output);
+    assert!(!output.contains("400 URI Failure"), "{}", output);
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        blobs()["/testaccount/repo/pool/main/h/hello/hello_1.0_amd64.deb"]
+    );
+}